@@ -0,0 +1,213 @@
+use anyhow::{bail, Result};
+use celestia_types::{nmt::Namespace, Blob, Commitment};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tokio::time::{interval, Duration, Instant};
+use tracing::{debug, error, info, instrument, warn, Span};
+
+use crate::{
+    da::DataAvailabilityLayer,
+    fullnode::{attachment_namespace, Batch},
+    metrics::Metrics,
+    tx::{SendMessage, Transaction},
+};
+
+const BATCH_INTERVAL: Duration = Duration::from_secs(3);
+
+/// The last batch that was posted successfully.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PostedBatch {
+    pub height: u64,
+    pub txs: usize,
+    /// Seconds since the Unix epoch.
+    pub posted_at: u64,
+}
+
+/// Collects accepted transactions and posts them to the DA layer as
+/// batches. Full nodes and gateways both post through one.
+pub struct Batcher {
+    da: Arc<dyn DataAvailabilityLayer>,
+    namespace: Namespace,
+    metrics: Arc<Metrics>,
+    /// Transactions by the namespace they go to, which is the app namespace
+    /// unless they are messages to a channel moved to its own.
+    pending: Mutex<Vec<(Namespace, Vec<Transaction>)>>,
+    last_batch: Mutex<Option<PostedBatch>>,
+    /// Set once shutdown starts, after which nothing more is queued.
+    closed: AtomicBool,
+}
+
+impl Batcher {
+    pub fn new(
+        da: Arc<dyn DataAvailabilityLayer>,
+        namespace: Namespace,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Batcher {
+            da,
+            namespace,
+            metrics,
+            pending: Mutex::new(Vec::new()),
+            last_batch: Mutex::new(None),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Queues `tx` for the app namespace.
+    pub async fn queue(&self, tx: Transaction) -> Result<()> {
+        self.queue_to(self.namespace, tx).await
+    }
+
+    pub async fn queue_to(&self, namespace: Namespace, tx: Transaction) -> Result<()> {
+        let mut pending = self.pending.lock().await;
+        // Checked under the lock so nothing is queued after the final batch.
+        if self.closed.load(Ordering::SeqCst) {
+            bail!("node is shutting down");
+        }
+        if let Ok(hash) = tx.hash() {
+            debug!(tx = hex::encode(hash), "Queued transaction");
+        }
+        match pending.iter_mut().find(|(ns, _)| *ns == namespace) {
+            Some((_, txs)) => txs.push(tx),
+            None => pending.push((namespace, vec![tx])),
+        }
+        self.metrics.txs_queued.inc();
+        Ok(())
+    }
+
+    /// Stops accepting transactions. Those already queued are still posted
+    /// by `post_pending`.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    pub async fn pending_len(&self) -> usize {
+        self.pending
+            .lock()
+            .await
+            .iter()
+            .map(|(_, txs)| txs.len())
+            .sum()
+    }
+
+    /// The messages waiting to be posted, to any namespace.
+    pub async fn pending_messages(&self) -> Vec<SendMessage> {
+        self.pending
+            .lock()
+            .await
+            .iter()
+            .flat_map(|(_, txs)| txs)
+            .filter_map(|tx| match tx {
+                Transaction::SendMessage(msg) => Some(msg.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub async fn last_batch(&self) -> Option<PostedBatch> {
+        self.last_batch.lock().await.clone()
+    }
+
+    /// Posts everything pending, one batch per namespace, all at the same
+    /// height. If the submission fails, everything stays pending.
+    #[instrument(skip_all, fields(txs, bytes, commitments, height))]
+    pub async fn post_pending(&self) -> Result<()> {
+        let mut pending = self.pending.lock().await;
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut txs = 0;
+        let mut blobs = Vec::new();
+        for (namespace, batch) in pending.iter() {
+            txs += batch.len();
+            blobs.push(Batch::new(batch.clone()).to_blob(*namespace)?);
+        }
+        let size: usize = blobs.iter().map(|blob| blob.data.len()).sum();
+        let span = Span::current();
+        span.record("txs", txs);
+        span.record("bytes", size);
+        span.record(
+            "commitments",
+            blobs
+                .iter()
+                .map(|blob| hex::encode(blob.commitment.0))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+        // The lock is held until the submission is done, so on failure the
+        // transactions are still pending and go out with the next batch.
+        let height = self.submit("batch", &blobs).await?;
+        span.record("height", height);
+        pending.clear();
+
+        self.metrics.txs_posted.add(txs as u64);
+        self.metrics.batch_txs.observe(&[], txs as f64);
+        self.metrics.batch_bytes.observe(&[], size as f64);
+        *self.last_batch.lock().await = Some(PostedBatch {
+            height,
+            txs,
+            posted_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+        });
+        info!("Batch posted with {} transactions", txs);
+        Ok(())
+    }
+
+    /// Posts an attachment as its own blob in the attachment namespace,
+    /// returning the height and commitment a `SendMessage` can reference it
+    /// by.
+    pub async fn post_attachment(&self, data: Vec<u8>) -> Result<(u64, Commitment)> {
+        let blob = Blob::new(attachment_namespace(self.namespace), data)?;
+        let commitment = blob.commitment;
+        let height = self.submit("attachment", &[blob]).await?;
+        Ok((height, commitment))
+    }
+
+    /// Submits blobs in one transaction, recording how long it took and
+    /// what it cost under `kind`. Returns the height they were included at.
+    async fn submit(&self, kind: &str, blobs: &[Blob]) -> Result<u64> {
+        let started = Instant::now();
+        let submission = self.da.submit(blobs).await;
+        self.metrics
+            .submit_seconds
+            .observe_duration(&[kind], started.elapsed());
+        match submission {
+            Ok(submission) => {
+                if let Some(fee) = submission.fee {
+                    self.metrics.submit_fees.add(&[kind], fee);
+                }
+                Ok(submission.height)
+            }
+            Err(e) => {
+                warn!(kind, error = %e, "Blob submission failed");
+                self.metrics.submit_failures.inc(&[kind]);
+                Err(e)
+            }
+        }
+    }
+
+    /// Posts pending transactions every `BATCH_INTERVAL` until `shutdown`
+    /// resolves. The final batch is left to the caller.
+    pub async fn run(&self, shutdown: impl Future<Output = ()>) {
+        let mut interval = interval(BATCH_INTERVAL);
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = &mut shutdown => return,
+            }
+            if let Err(e) = self.post_pending().await {
+                error!(error = %e, "Error posting batch");
+            }
+        }
+    }
+}
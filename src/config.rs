@@ -0,0 +1,366 @@
+use crate::da::DEFAULT_GAS_PRICE;
+use crate::ratelimit::MAX_POW_DIFFICULTY;
+use crate::tx::PublicKey;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::time::Duration;
+
+/// Reads `name` from the environment, falling back to `default` if unset.
+fn env_or<T: FromStr>(name: &str, default: T) -> Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(name) {
+        Ok(value) => value.parse().context(format!("Failed to parse {name}")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Comma-separated list from the environment, empty if unset.
+fn env_list<T: FromStr>(name: &str) -> Result<Vec<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            item.trim()
+                .parse()
+                .context(format!("Failed to parse {name}"))
+        })
+        .collect()
+}
+
+/// Operator settings for a full node. Apart from `chain`, these only affect
+/// what a node accepts over HTTP and how it runs, never how it applies
+/// blocks, so nodes may differ freely.
+#[derive(Clone)]
+pub struct NodeConfig {
+    pub chain: ChainConfig,
+    pub rate_limits: RateLimitConfig,
+    /// What blobs are submitted at, in utia per unit of gas.
+    pub da_gas_price: f64,
+    /// How long shutdown may take to finish in-flight requests, post the
+    /// last batch and save a snapshot.
+    pub shutdown_timeout: Duration,
+    /// Where to save a snapshot on shutdown, if anywhere. A node started
+    /// with one already there resumes from it.
+    pub snapshot_path: Option<PathBuf>,
+    /// How long the DA subscription may go without delivering a height
+    /// before sync treats it as dead and resubscribes.
+    pub subscription_timeout: Duration,
+    /// A read-only node syncs and serves reads but never posts to the DA
+    /// layer, so it needs no funded account. Its writes are forwarded to
+    /// `upstream_url`, or refused if there is none.
+    pub read_only: bool,
+    pub upstream_url: Option<String>,
+    /// A full node a gateway looks up accounts and channels on, to check
+    /// that attachment uploads come from registered users and to post
+    /// messages to their channel's namespace. Without one, a gateway refuses
+    /// attachments and posts every message to the app namespace.
+    pub state_url: Option<String>,
+    /// Which channels that were moved to their own namespace to download,
+    /// besides everything in the app namespace.
+    pub follow_channels: FollowChannels,
+    pub index_filter: IndexFilter,
+}
+
+/// Consensus parameters. Unlike the rest of `NodeConfig`, these decide
+/// which transactions are valid, so every node on a network must use the
+/// same values.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct ChainConfig {
+    pub activations: Activations,
+    /// The accounts allowed to move channels to their own namespace, set as
+    /// comma-separated hex keys with `GRUGCHAT_DIRECTORY_ADMINS`. Without
+    /// any, channels stay in the app namespace.
+    pub directory_admins: Vec<PublicKey>,
+}
+
+impl ChainConfig {
+    pub fn from_env() -> Result<Self> {
+        Ok(ChainConfig {
+            activations: Activations::from_env()?,
+            directory_admins: parse_keys(env_list("GRUGCHAT_DIRECTORY_ADMINS")?)?,
+        })
+    }
+}
+
+/// The DA heights from which rules added after a network started apply, so
+/// that earlier blocks are still applied by the rules they were posted
+/// under. Networks started with every rule in place leave these at zero.
+/// Each is set with `GRUGCHAT_ACTIVATE_<RULE>`, e.g.
+/// `GRUGCHAT_ACTIVATE_MESSAGE_QUOTA`.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Activations {
+    /// `state::MESSAGE_QUOTA`.
+    pub message_quota: u64,
+    /// The limits in `validation` on the messages and registrations that
+    /// networks started with. Other transactions are checked from the start.
+    pub content_limits: u64,
+    /// Blobs without the version prefix `Batch::to_blob` writes are decoded
+    /// as the `legacy` transactions networks started with, and applied only
+    /// below this height.
+    pub versioned_batches: u64,
+}
+
+impl Activations {
+    pub fn from_env() -> Result<Self> {
+        Ok(Activations {
+            message_quota: env_or("GRUGCHAT_ACTIVATE_MESSAGE_QUOTA", 0)?,
+            content_limits: env_or("GRUGCHAT_ACTIVATE_CONTENT_LIMITS", 0)?,
+            versioned_batches: env_or("GRUGCHAT_ACTIVATE_VERSIONED_BATCHES", 0)?,
+        })
+    }
+}
+
+/// Channels moved to their own namespace are only downloaded by nodes that
+/// follow them. Set with `GRUGCHAT_FOLLOW_CHANNELS`: unset or `*` for all,
+/// otherwise a comma-separated list, which may be empty. Those channels are
+/// committed to like any other, so state roots only match between nodes
+/// that follow the same ones.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub enum FollowChannels {
+    #[default]
+    All,
+    Only(Vec<String>),
+}
+
+impl FollowChannels {
+    pub fn follows(&self, channel: &str) -> bool {
+        match self {
+            FollowChannels::All => true,
+            FollowChannels::Only(channels) => channels.iter().any(|c| c == channel),
+        }
+    }
+
+    fn from_env() -> Result<Self> {
+        match env::var("GRUGCHAT_FOLLOW_CHANNELS").as_deref() {
+            Err(_) | Ok("*") => Ok(FollowChannels::All),
+            Ok(_) => Ok(FollowChannels::Only(env_list("GRUGCHAT_FOLLOW_CHANNELS")?)),
+        }
+    }
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            chain: ChainConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+            da_gas_price: DEFAULT_GAS_PRICE,
+            shutdown_timeout: Duration::from_secs(30),
+            snapshot_path: None,
+            subscription_timeout: Duration::from_secs(120),
+            read_only: false,
+            upstream_url: None,
+            state_url: None,
+            follow_channels: FollowChannels::All,
+            index_filter: IndexFilter::default(),
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn from_env() -> Result<Self> {
+        let default = Self::default();
+        Ok(NodeConfig {
+            chain: ChainConfig::from_env()?,
+            rate_limits: RateLimitConfig::from_env()?,
+            da_gas_price: env_or("GRUGCHAT_DA_GAS_PRICE", default.da_gas_price)?,
+            shutdown_timeout: Duration::from_secs(env_or(
+                "GRUGCHAT_SHUTDOWN_TIMEOUT_SECS",
+                default.shutdown_timeout.as_secs(),
+            )?),
+            snapshot_path: env::var("GRUGCHAT_SNAPSHOT_PATH").ok().map(PathBuf::from),
+            subscription_timeout: Duration::from_secs(env_or(
+                "GRUGCHAT_SUBSCRIPTION_TIMEOUT_SECS",
+                default.subscription_timeout.as_secs(),
+            )?),
+            read_only: env_or("GRUGCHAT_READ_ONLY", default.read_only)?,
+            upstream_url: env::var("GRUGCHAT_UPSTREAM_URL").ok(),
+            state_url: env::var("GRUGCHAT_STATE_URL").ok(),
+            follow_channels: FollowChannels::from_env()?,
+            index_filter: IndexFilter::from_env()?,
+        })
+    }
+}
+
+/// Which messages a node stores and serves. The rest are still checked and
+/// counted against their sender's quota, and account-level transactions
+/// like `Register` are always applied, so the node accepts and rejects
+/// exactly what every other node does. Users are matched by the key of the
+/// account that sent a message, as of when it was sent.
+///
+/// Set with `GRUGCHAT_INDEX_CHANNELS` and `GRUGCHAT_INDEX_USERS`, which if
+/// set are the only channels and users indexed, and `GRUGCHAT_SKIP_CHANNELS`
+/// and `GRUGCHAT_SKIP_USERS`, which are never indexed. All are
+/// comma-separated; users are hex public keys.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct IndexFilter {
+    pub channels: Option<Vec<String>>,
+    pub skip_channels: Vec<String>,
+    pub users: Option<Vec<PublicKey>>,
+    pub skip_users: Vec<PublicKey>,
+}
+
+/// Like `env_list`, but `None` if unset.
+fn env_allow_list<T: FromStr>(name: &str) -> Result<Option<Vec<T>>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(name) {
+        Ok(_) => Ok(Some(env_list(name)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn parse_keys(keys: Vec<String>) -> Result<Vec<PublicKey>> {
+    keys.iter()
+        .map(|key| {
+            hex::decode(key)
+                .map(PublicKey::new)
+                .context(format!("Failed to decode public key {key:?}"))
+        })
+        .collect()
+}
+
+impl IndexFilter {
+    pub fn from_env() -> Result<Self> {
+        Ok(IndexFilter {
+            channels: env_allow_list("GRUGCHAT_INDEX_CHANNELS")?,
+            skip_channels: env_list("GRUGCHAT_SKIP_CHANNELS")?,
+            users: env_allow_list("GRUGCHAT_INDEX_USERS")?
+                .map(parse_keys)
+                .transpose()?,
+            skip_users: parse_keys(env_list("GRUGCHAT_SKIP_USERS")?)?,
+        })
+    }
+
+    /// Whether every message is indexed.
+    pub fn is_everything(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn indexes_channel(&self, channel: &str) -> bool {
+        self.channels
+            .as_ref()
+            .is_none_or(|channels| channels.iter().any(|c| c == channel))
+            && !self.skip_channels.iter().any(|c| c == channel)
+    }
+
+    pub fn indexes_user(&self, user: &PublicKey) -> bool {
+        self.users.as_ref().is_none_or(|users| users.contains(user))
+            && !self.skip_users.contains(user)
+    }
+
+    pub fn indexes(&self, channel: &str, author: &PublicKey) -> bool {
+        self.indexes_channel(channel) && self.indexes_user(author)
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitConfig {
+    pub window: Duration,
+    /// Transactions accepted per public key within `window`.
+    pub per_key: u32,
+    /// Transactions accepted per client IP within `window`.
+    pub per_ip: u32,
+    /// Leading zero bits required of the proof-of-work stamp on messages
+    /// from IPs outside `trusted_ips`. Zero disables stamps.
+    pub pow_difficulty: u32,
+    /// The same for attachment uploads, which cost far more to post.
+    pub attachment_pow_difficulty: u32,
+    pub trusted_ips: Vec<IpAddr>,
+    /// Read-only replicas whose forwarded client IPs are believed, so the
+    /// writes they pass on are limited by the client that sent them rather
+    /// than by the replica.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            window: Duration::from_secs(60),
+            per_key: 30,
+            per_ip: 120,
+            pow_difficulty: 0,
+            attachment_pow_difficulty: 16,
+            trusted_ips: Vec::new(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> Result<Self> {
+        let default = Self::default();
+        let pow_difficulty = env_or("GRUGCHAT_POW_DIFFICULTY", default.pow_difficulty)?;
+        if pow_difficulty > MAX_POW_DIFFICULTY {
+            bail!("GRUGCHAT_POW_DIFFICULTY may be at most {MAX_POW_DIFFICULTY}");
+        }
+        let attachment_pow_difficulty = env_or(
+            "GRUGCHAT_ATTACHMENT_POW_DIFFICULTY",
+            default.attachment_pow_difficulty,
+        )?;
+        if attachment_pow_difficulty > MAX_POW_DIFFICULTY {
+            bail!("GRUGCHAT_ATTACHMENT_POW_DIFFICULTY may be at most {MAX_POW_DIFFICULTY}");
+        }
+        Ok(RateLimitConfig {
+            window: Duration::from_secs(env_or(
+                "GRUGCHAT_RATE_LIMIT_WINDOW_SECS",
+                default.window.as_secs(),
+            )?),
+            per_key: env_or("GRUGCHAT_RATE_LIMIT_PER_KEY", default.per_key)?,
+            per_ip: env_or("GRUGCHAT_RATE_LIMIT_PER_IP", default.per_ip)?,
+            pow_difficulty,
+            attachment_pow_difficulty,
+            trusted_ips: env_list("GRUGCHAT_TRUSTED_IPS")?,
+            trusted_proxies: env_list("GRUGCHAT_TRUSTED_PROXIES")?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// How the process logs. `filter` takes `tracing` directives, so levels can
+/// be set per module, e.g. `info,grugchat::fullnode=debug`.
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+    pub filter: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            filter: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn from_env() -> Result<Self> {
+        let default = Self::default();
+        let format = match env::var("GRUGCHAT_LOG_FORMAT").as_deref() {
+            Ok("json") => LogFormat::Json,
+            Ok("text") | Err(_) => LogFormat::Text,
+            Ok(other) => bail!("Unknown GRUGCHAT_LOG_FORMAT {other:?}, expected text or json"),
+        };
+        Ok(LogConfig {
+            filter: env_or("GRUGCHAT_LOG", default.filter)?,
+            format,
+        })
+    }
+}
@@ -0,0 +1,482 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use celestia_rpc::{blob::BlobsAtHeight, BlobClient, HeaderClient, ShareClient, StateClient};
+use celestia_types::{nmt::Namespace, Blob, Commitment, ExtendedHeader, TxConfig};
+use std::sync::Arc;
+use tokio::spawn;
+use tokio::sync::{mpsc, RwLock};
+use tracing::warn;
+
+/// The blobs in a namespace at one height, along with the header they were
+/// checked against. Layers that have no headers to verify leave it empty.
+pub struct FetchedHeight {
+    pub height: u64,
+    pub header: Option<ExtendedHeader>,
+    pub blobs: Vec<Blob>,
+}
+
+/// Where submitted blobs were included, and the fee paid for them in utia
+/// if the layer reports it.
+pub struct Submission {
+    pub height: u64,
+    pub fee: Option<u64>,
+}
+
+#[async_trait]
+pub trait DataAvailabilityLayer: Send + Sync {
+    async fn network_height(&self) -> Result<u64>;
+
+    /// Fetches the blobs in `namespace` at `height` and checks against that
+    /// height's header that they are all of the namespace. If `blobs` is
+    /// `Some` they are checked instead of being fetched.
+    async fn fetch_height(
+        &self,
+        height: u64,
+        namespace: Namespace,
+        blobs: Option<Vec<Blob>>,
+    ) -> Result<FetchedHeight>;
+
+    /// Fetches the blob in `namespace` with `commitment` at `height`,
+    /// checked against that height's header.
+    async fn get_blob(
+        &self,
+        height: u64,
+        namespace: Namespace,
+        commitment: Commitment,
+    ) -> Result<Blob>;
+
+    /// Streams the blobs in `namespace` at every new height.
+    async fn subscribe(&self, namespace: Namespace) -> Result<mpsc::Receiver<BlobsAtHeight>>;
+
+    /// Submits `blobs` and returns the height they were included at.
+    async fn submit(&self, blobs: &[Blob]) -> Result<Submission>;
+
+    /// Replaces the connection to the DA node after it has failed. Layers
+    /// without a connection have nothing to do.
+    async fn reconnect(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The gas price blobs are submitted at unless configured otherwise, in
+/// utia. Setting it, rather than leaving it to the DA node, is what makes
+/// the fee paid known.
+pub const DEFAULT_GAS_PRICE: f64 = 0.002;
+
+pub struct CelestiaDa {
+    url: String,
+    auth_token: Option<String>,
+    client: RwLock<Arc<celestia_rpc::Client>>,
+    /// What blobs are submitted at, in utia per unit of gas.
+    gas_price: f64,
+}
+
+impl CelestiaDa {
+    pub async fn connect(url: &str, auth_token: Option<&str>) -> Result<Self> {
+        let client = Self::new_client(url, auth_token).await?;
+        Ok(CelestiaDa {
+            url: url.to_string(),
+            auth_token: auth_token.map(str::to_string),
+            client: RwLock::new(Arc::new(client)),
+            gas_price: DEFAULT_GAS_PRICE,
+        })
+    }
+
+    /// Submits at `gas_price` instead of `DEFAULT_GAS_PRICE`.
+    pub fn with_gas_price(mut self, gas_price: f64) -> Self {
+        self.gas_price = gas_price;
+        self
+    }
+
+    async fn new_client(url: &str, auth_token: Option<&str>) -> Result<celestia_rpc::Client> {
+        celestia_rpc::Client::new(url, auth_token)
+            .await
+            .context("Couldn't start Celestia client")
+    }
+
+    /// The current connection. Requests already using it carry on with it
+    /// if it is replaced by `reconnect`.
+    async fn client(&self) -> Arc<celestia_rpc::Client> {
+        self.client.read().await.clone()
+    }
+
+    /// Checks that `blob` is committed to by the data root of `header`, using
+    /// the namespace proofs of the rows the blob spans.
+    async fn verify_blob_inclusion(
+        &self,
+        header: &ExtendedHeader,
+        namespace: Namespace,
+        blob: &Blob,
+    ) -> Result<()> {
+        let height = header.height().value();
+        if blob.namespace != namespace {
+            bail!(
+                "blob at height {height} is not in namespace {}",
+                hex::encode(namespace.as_bytes())
+            );
+        }
+        let index = blob
+            .index
+            .context(format!("blob at height {height} has no share index"))?;
+
+        let proofs =
+            BlobClient::blob_get_proof(&*self.client().await, height, namespace, blob.commitment)
+                .await
+                .context(format!("Failed to fetch blob proof at height {height}"))?;
+
+        let shares = blob.to_shares()?;
+        let square_width = u64::from(header.dah.square_width());
+        let mut start = index % square_width;
+        let mut offset = 0;
+        for (row, proof) in (index / square_width..).zip(proofs) {
+            if u64::from(proof.start_idx()) != start {
+                bail!("blob proof at height {height} does not start at the blob's share");
+            }
+            let root = u16::try_from(row)
+                .ok()
+                .and_then(|row| header.dah.row_root(row))
+                .context(format!(
+                    "blob at height {height} spans past the data square"
+                ))?;
+            let end = offset + (proof.end_idx() - proof.start_idx()) as usize;
+            if end > shares.len() {
+                bail!("blob proof at height {height} covers more shares than the blob");
+            }
+            proof
+                .verify_range(&root, &shares[offset..end], *namespace)
+                .map_err(|e| anyhow!("blob proof at height {height} is invalid: {e:?}"))?;
+            offset = end;
+            start = 0;
+        }
+        if offset != shares.len() {
+            bail!("blob proofs at height {height} do not cover the whole blob");
+        }
+        Ok(())
+    }
+
+    /// Checks that `blobs` are everything in `namespace` at the height of
+    /// `header`: the namespace's shares are fetched with a proof for every
+    /// row whose root covers the namespace, and must be exactly the blobs'
+    /// shares apart from padding. This also proves the blobs are included.
+    async fn verify_namespace_complete(
+        &self,
+        header: &ExtendedHeader,
+        namespace: Namespace,
+        blobs: &[Blob],
+    ) -> Result<()> {
+        let height = header.height().value();
+        let namespace_hex = hex::encode(namespace.as_bytes());
+        let namespaced =
+            ShareClient::share_get_shares_by_namespace(&*self.client().await, header, namespace)
+                .await
+                .context(format!(
+                    "Failed to fetch shares of namespace {namespace_hex} at height {height}"
+                ))?;
+
+        let rows: Vec<_> = header
+            .dah
+            .row_roots()
+            .iter()
+            .filter(|root| root.min_namespace() <= *namespace && *namespace <= root.max_namespace())
+            .collect();
+        if rows.len() != namespaced.rows.len() {
+            bail!(
+                "expected shares of namespace {namespace_hex} from {} rows at height {height}, got {}",
+                rows.len(),
+                namespaced.rows.len()
+            );
+        }
+        let mut shares = Vec::new();
+        for (root, row) in rows.into_iter().zip(namespaced.rows) {
+            row.proof
+                .verify_complete_namespace(root, &row.shares, *namespace)
+                .map_err(|e| {
+                    anyhow!("namespace {namespace_hex} proof at height {height} is invalid: {e:?}")
+                })?;
+            shares.extend(row.shares);
+        }
+
+        // Blobs in a namespace can be separated by padding shares, which
+        // start a sequence of length zero.
+        shares.retain(|share| share.sequence_length() != Some(0));
+        let mut blob_shares = Vec::new();
+        for blob in blobs {
+            if blob.namespace != namespace {
+                bail!("blob at height {height} is not in namespace {namespace_hex}");
+            }
+            blob_shares.extend(blob.to_shares()?);
+        }
+        if shares != blob_shares {
+            bail!("blobs at height {height} are not all of namespace {namespace_hex}");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DataAvailabilityLayer for CelestiaDa {
+    async fn network_height(&self) -> Result<u64> {
+        let network_head = HeaderClient::header_network_head(&*self.client().await).await?;
+        Ok(network_head.height().value())
+    }
+
+    async fn fetch_height(
+        &self,
+        height: u64,
+        namespace: Namespace,
+        blobs: Option<Vec<Blob>>,
+    ) -> Result<FetchedHeight> {
+        let client = self.client().await;
+        let header = HeaderClient::header_get_by_height(&*client, height)
+            .await
+            .context(format!("Failed to fetch header at height {height}"))?;
+        header
+            .validate()
+            .context(format!("Invalid header at height {height}"))?;
+
+        let blobs = match blobs {
+            Some(blobs) => blobs,
+            None => BlobClient::blob_get_all(&*client, height, &[namespace])
+                .await
+                .context(format!("Failed to fetch blobs at height {height}"))?
+                .unwrap_or_default(),
+        };
+        self.verify_namespace_complete(&header, namespace, &blobs)
+            .await?;
+
+        Ok(FetchedHeight {
+            height,
+            header: Some(header),
+            blobs,
+        })
+    }
+
+    async fn get_blob(
+        &self,
+        height: u64,
+        namespace: Namespace,
+        commitment: Commitment,
+    ) -> Result<Blob> {
+        let client = self.client().await;
+        let header = HeaderClient::header_get_by_height(&*client, height)
+            .await
+            .context(format!("Failed to fetch header at height {height}"))?;
+        header
+            .validate()
+            .context(format!("Invalid header at height {height}"))?;
+
+        let blob = BlobClient::blob_get(&*client, height, namespace, commitment)
+            .await
+            .context(format!("Failed to fetch blob at height {height}"))?;
+        self.verify_blob_inclusion(&header, namespace, &blob)
+            .await?;
+        Ok(blob)
+    }
+
+    async fn subscribe(&self, namespace: Namespace) -> Result<mpsc::Receiver<BlobsAtHeight>> {
+        let (tx, rx) = mpsc::channel(100); // Adjust buffer size as needed
+
+        let mut blobsub = BlobClient::blob_subscribe(&*self.client().await, namespace)
+            .await
+            .context(format!(
+                "Failed to subscribe to namespace {}",
+                hex::encode(namespace.as_bytes())
+            ))?;
+        spawn(async move {
+            while let Some(result) = blobsub.next().await {
+                match result {
+                    Ok(blobs_at_height) => {
+                        if tx.send(blobs_at_height).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        warn!(error = %e, "Error retrieving blobs from DA layer");
+                    }
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+
+    async fn submit(&self, blobs: &[Blob]) -> Result<Submission> {
+        let response = StateClient::state_submit_pay_for_blob(
+            &*self.client().await,
+            blobs,
+            TxConfig {
+                gas_price: Some(self.gas_price),
+                ..TxConfig::default()
+            },
+        )
+        .await?;
+        if response.code != 0 {
+            bail!(
+                "blob submission failed with code {}: {}",
+                response.code,
+                response.raw_log
+            );
+        }
+        Ok(Submission {
+            height: response.height as u64,
+            // Fees are charged on the gas limit, not on what was used.
+            fee: Some((response.gas_wanted as f64 * self.gas_price).ceil() as u64),
+        })
+    }
+
+    async fn reconnect(&self) -> Result<()> {
+        let client = Self::new_client(&self.url, self.auth_token.as_deref()).await?;
+        *self.client.write().await = Arc::new(client);
+        Ok(())
+    }
+}
+
+/// An in-memory DA layer for tests. Submitted blobs are held until
+/// `produce_block` includes all of them at the next height, in submission
+/// order, so blobs from several nodes can land in the same block.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Default)]
+pub struct MockDa {
+    inner: tokio::sync::Mutex<MockDaInner>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Default)]
+struct MockDaInner {
+    height: u64,
+    blocks: std::collections::HashMap<u64, Vec<Blob>>,
+    pending: Vec<Blob>,
+    subscribers: Vec<(Namespace, mpsc::Sender<BlobsAtHeight>)>,
+    failing_submissions: bool,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MockDa {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `submit` fail until it is called again with `false`.
+    pub async fn fail_submissions(&self, failing: bool) {
+        self.inner.lock().await.failing_submissions = failing;
+    }
+
+    /// Ends every subscription, as a DA node dropping its connections would.
+    pub async fn close_subscriptions(&self) {
+        self.inner.lock().await.subscribers.clear();
+    }
+
+    /// Includes every pending blob at a new height and notifies subscribers.
+    /// Returns the new height.
+    pub async fn produce_block(&self) -> u64 {
+        let (height, blobs, subscribers) = {
+            let mut inner = self.inner.lock().await;
+            inner.height += 1;
+            let height = inner.height;
+
+            let mut next_index = 0;
+            let blobs: Vec<Blob> = inner
+                .pending
+                .drain(..)
+                .map(|mut blob| {
+                    blob.index = Some(next_index);
+                    next_index += blob.to_shares().map_or(1, |shares| shares.len() as u64);
+                    blob
+                })
+                .collect();
+            inner.blocks.insert(height, blobs.clone());
+            (height, blobs, inner.subscribers.clone())
+        };
+
+        for (namespace, tx) in subscribers {
+            let blobs: Vec<Blob> = blobs
+                .iter()
+                .filter(|blob| blob.namespace == namespace)
+                .cloned()
+                .collect();
+            let _ = tx
+                .send(BlobsAtHeight {
+                    blobs: (!blobs.is_empty()).then_some(blobs),
+                    height,
+                })
+                .await;
+        }
+        self.inner
+            .lock()
+            .await
+            .subscribers
+            .retain(|(_, tx)| !tx.is_closed());
+
+        height
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+#[async_trait]
+impl DataAvailabilityLayer for MockDa {
+    async fn network_height(&self) -> Result<u64> {
+        Ok(self.inner.lock().await.height)
+    }
+
+    async fn fetch_height(
+        &self,
+        height: u64,
+        namespace: Namespace,
+        blobs: Option<Vec<Blob>>,
+    ) -> Result<FetchedHeight> {
+        let inner = self.inner.lock().await;
+        let Some(block) = inner.blocks.get(&height) else {
+            bail!("height {height} has not been produced yet");
+        };
+
+        let blobs = blobs.unwrap_or_else(|| {
+            block
+                .iter()
+                .filter(|blob| blob.namespace == namespace)
+                .cloned()
+                .collect()
+        });
+        Ok(FetchedHeight {
+            height,
+            header: None,
+            blobs,
+        })
+    }
+
+    async fn get_blob(
+        &self,
+        height: u64,
+        namespace: Namespace,
+        commitment: Commitment,
+    ) -> Result<Blob> {
+        let inner = self.inner.lock().await;
+        inner
+            .blocks
+            .get(&height)
+            .and_then(|block| {
+                block
+                    .iter()
+                    .find(|blob| blob.namespace == namespace && blob.commitment == commitment)
+            })
+            .cloned()
+            .context(format!("no such blob at height {height}"))
+    }
+
+    async fn subscribe(&self, namespace: Namespace) -> Result<mpsc::Receiver<BlobsAtHeight>> {
+        let (tx, rx) = mpsc::channel(100);
+        self.inner.lock().await.subscribers.push((namespace, tx));
+        Ok(rx)
+    }
+
+    async fn submit(&self, blobs: &[Blob]) -> Result<Submission> {
+        let mut inner = self.inner.lock().await;
+        if inner.failing_submissions {
+            bail!("blob submission failed");
+        }
+        inner.pending.extend_from_slice(blobs);
+        Ok(Submission {
+            height: inner.height + 1,
+            fee: None,
+        })
+    }
+}
@@ -1,213 +1,798 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use axum::{
+    middleware,
     routing::{get, post},
     Router,
 };
-use celestia_rpc::{BlobClient, HeaderClient};
-use celestia_types::{nmt::Namespace, Blob, TxConfig};
+use celestia_rpc::blob::BlobsAtHeight;
+use celestia_types::{nmt::Namespace, Blob, Commitment, ExtendedHeader};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
 };
 use tokio::spawn;
-use tokio::sync::{mpsc, Mutex, Notify};
-use tokio::time::{interval, Duration};
-
-use crate::{state::State, tx::Transaction, webserver::*};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{
+    batcher::{Batcher, PostedBatch},
+    config::{FollowChannels, IndexFilter, NodeConfig},
+    da::{CelestiaDa, DataAvailabilityLayer, FetchedHeight},
+    legacy,
+    merkle::Hash,
+    metrics::Metrics,
+    ratelimit::RateLimiter,
+    snapshot::Snapshot,
+    state::{Rejected, State},
+    tx::{NamespaceId, PublicKey, Transaction},
+    upstream::Upstream,
+    validation,
+    webserver::*,
+};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Versioned blobs start with this, then the version of the encoding that
+/// follows. Unversioned ones hold `legacy` transactions, which never start
+/// with it.
+const BATCH_MAGIC: &[u8; 4] = b"grug";
+const BATCH_VERSION: u8 = 1;
 
 #[derive(Serialize, Deserialize)]
 pub struct Batch(Vec<Transaction>);
 
-const BATCH_INTERVAL: Duration = Duration::from_secs(3);
+impl Batch {
+    pub fn new(txs: Vec<Transaction>) -> Self {
+        Batch(txs)
+    }
+
+    pub fn to_blob(&self, namespace: Namespace) -> Result<Blob> {
+        let mut data = BATCH_MAGIC.to_vec();
+        data.push(BATCH_VERSION);
+        bincode::serialize_into(&mut data, self)?;
+        Ok(Blob::new(namespace, data)?)
+    }
+}
+
+/// The transactions in a blob, in the encoding they were posted in.
+enum BlobTransactions {
+    Versioned(Vec<Transaction>),
+    Legacy(Vec<legacy::Transaction>),
+}
+
+/// A transaction from either kind of blob, applied by the rules of its own.
+enum PostedTransaction {
+    Versioned(Transaction),
+    Legacy(legacy::Transaction),
+}
+
+/// How many heights ahead of the sync cursor are fetched concurrently.
+const FETCH_WINDOW: usize = 16;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// Bounds on how long to wait before restarting failed sync. The wait
+/// doubles on each failure that made no progress.
+const MIN_SYNC_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_SYNC_BACKOFF: Duration = Duration::from_secs(60);
 
 pub struct FullNode {
-    da_client: celestia_rpc::Client,
+    da: Arc<dyn DataAvailabilityLayer>,
     namespace: Namespace,
     start_height: u64,
 
     pub(crate) state: Arc<Mutex<State>>,
-    pending_transactions: Arc<Mutex<Vec<Transaction>>>,
+    batcher: Batcher,
 
     genesis_sync_complete: Arc<AtomicBool>,
-    sync_notify: Arc<Notify>,
+    /// Why sync is down, while it is being restarted.
+    sync_error: std::sync::Mutex<Option<String>>,
+    subscription_timeout: Duration,
+
+    pub(crate) rate_limiter: RateLimiter,
+    pub(crate) metrics: Arc<Metrics>,
+
+    /// Flips to true once shutdown starts, after which no more transactions
+    /// are queued.
+    shutdown: watch::Sender<bool>,
+    shutdown_timeout: Duration,
+    snapshot_path: Option<PathBuf>,
+
+    read_only: bool,
+    /// Where a read-only node forwards writes.
+    pub(crate) upstream: Option<Upstream>,
+
+    /// Which channels in their own namespace to download.
+    follow_channels: FollowChannels,
+    /// Which messages are stored. Unless it is everything, the state root
+    /// can't be recomputed from what the node has, so it serves no roots or
+    /// proofs.
+    index_filter: IndexFilter,
+}
+
+/// What `GET /status` reports about a node.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NodeStatus {
+    pub version: String,
+    /// Hex, in the form `start-fullnode` takes it.
+    pub namespace: String,
+    pub start_height: u64,
+    pub read_only: bool,
+    /// The node a read-only node forwards writes to.
+    pub upstream: Option<String>,
+    pub genesis_sync_complete: bool,
+    /// Set while sync is down and being restarted, in which case the state
+    /// may be falling behind the DA layer.
+    pub sync_error: Option<String>,
+    pub last_height: Option<u64>,
+    /// The latest height seen from the DA layer, once one has been.
+    pub network_height: Option<u64>,
+    /// The channel namespaces followed besides the app namespace, in hex.
+    pub channel_namespaces: Vec<String>,
+    /// Which messages the node stores and serves.
+    pub index_filter: IndexFilter,
+    pub pending_transactions: usize,
+    pub last_batch: Option<PostedBatch>,
+}
+
+/// Tracks the next DA height to apply and the last header that was verified,
+/// so that every height is applied exactly once and in order.
+struct SyncCursor {
+    next_height: u64,
+    trusted: Option<ExtendedHeader>,
+}
+
+impl SyncCursor {
+    fn new(start_height: u64) -> Self {
+        SyncCursor {
+            next_height: start_height,
+            trusted: None,
+        }
+    }
+
+    fn advance(&mut self, height: u64, header: Option<ExtendedHeader>) {
+        self.next_height = height + 1;
+        if header.is_some() {
+            self.trusted = header;
+        }
+    }
+}
+
+/// Periodically reports how far a catch-up has progressed.
+struct SyncProgress {
+    start_height: u64,
+    target: u64,
+    started: Instant,
+    last_report: Instant,
 }
 
-impl TryFrom<&Blob> for Batch {
+impl SyncProgress {
+    fn new(start_height: u64, target: u64) -> Self {
+        let now = Instant::now();
+        SyncProgress {
+            start_height,
+            target,
+            started: now,
+            last_report: now,
+        }
+    }
+
+    fn report(&mut self, height: u64) {
+        if height < self.target && self.last_report.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        self.last_report = Instant::now();
+
+        let applied = height + 1 - self.start_height;
+        let rate = applied as f64 / self.started.elapsed().as_secs_f64().max(f64::EPSILON);
+        let eta = (self.target - height) as f64 / rate;
+        info!(
+            height,
+            target = self.target,
+            "Synced height {}/{} ({:.1} heights/s, ETA {:.0}s)",
+            height,
+            self.target,
+            rate,
+            eta
+        );
+    }
+}
+
+impl TryFrom<&Blob> for BlobTransactions {
     type Error = anyhow::Error;
 
     fn try_from(value: &Blob) -> Result<Self, Self::Error> {
-        match bincode::deserialize(&value.data) {
-            Ok(batch) => Ok(batch),
-            Err(_) => {
-                let transaction: Transaction = bincode::deserialize(&value.data)
-                    .context(format!("Failed to decode blob into Transaction: {value:?}"))?;
-
-                Ok(Batch(vec![transaction]))
+        let Some(versioned) = value.data.strip_prefix(BATCH_MAGIC) else {
+            return Ok(BlobTransactions::Legacy(legacy::Transaction::decode_batch(
+                &value.data,
+            )?));
+        };
+        match versioned.split_first() {
+            Some((&BATCH_VERSION, data)) => {
+                let batch: Batch = bincode::deserialize(data)
+                    .context(format!("Failed to decode blob into Batch: {value:?}"))?;
+                Ok(BlobTransactions::Versioned(batch.0))
             }
+            Some((version, _)) => bail!("unknown batch version {version}"),
+            None => bail!("blob has no batch version"),
         }
     }
 }
 
 impl FullNode {
     pub async fn new(namespace: Namespace, start_height: u64) -> Result<Self> {
-        let da_client = celestia_rpc::Client::new("ws://localhost:26658", None)
-            .await
-            .context("Couldn't start Celestia client")?;
+        let config = NodeConfig::from_env()?;
+        let da = CelestiaDa::connect("ws://localhost:26658", None)
+            .await?
+            .with_gas_price(config.da_gas_price);
+        Self::resume(Arc::new(da), namespace, start_height, config)
+    }
+
+    /// Boots a node from a verified snapshot, continuing to sync from the
+    /// height after the one it was taken at.
+    pub async fn from_snapshot(
+        namespace: Namespace,
+        snapshot: Snapshot,
+        trusted_root: Hash,
+    ) -> Result<Self> {
+        let config = NodeConfig::from_env()?;
+        let da = CelestiaDa::connect("ws://localhost:26658", None)
+            .await?
+            .with_gas_price(config.da_gas_price);
+        let start_height = snapshot.height + 1;
+        let state = State::from_snapshot(snapshot, trusted_root)?;
+        Ok(Self::with_state(
+            Arc::new(da),
+            namespace,
+            start_height,
+            state,
+            config,
+        ))
+    }
+
+    pub fn with_da(
+        da: Arc<dyn DataAvailabilityLayer>,
+        namespace: Namespace,
+        start_height: u64,
+        config: NodeConfig,
+    ) -> Self {
+        Self::with_state(da, namespace, start_height, State::new(), config)
+    }
+
+    /// Like `with_da`, but if a previous run saved a snapshot to
+    /// `snapshot_path`, restores it and continues from the height after the
+    /// one it was taken at instead of `start_height`. The node wrote the
+    /// snapshot itself, so its own state root is trusted.
+    pub fn resume(
+        da: Arc<dyn DataAvailabilityLayer>,
+        namespace: Namespace,
+        start_height: u64,
+        config: NodeConfig,
+    ) -> Result<Self> {
+        let Some(path) = config.snapshot_path.as_ref().filter(|path| path.exists()) else {
+            return Ok(Self::with_da(da, namespace, start_height, config));
+        };
+        let snapshot = Snapshot::read(path)?;
+        info!(
+            height = snapshot.height,
+            path = %path.display(),
+            "Restoring from saved snapshot"
+        );
+        let start_height = snapshot.height + 1;
+        let trusted_root = snapshot.state_root;
+        let state = State::from_snapshot(snapshot, trusted_root)?;
+        Ok(Self::with_state(da, namespace, start_height, state, config))
+    }
 
-        Ok(FullNode {
-            da_client,
+    fn with_state(
+        da: Arc<dyn DataAvailabilityLayer>,
+        namespace: Namespace,
+        start_height: u64,
+        mut state: State,
+        config: NodeConfig,
+    ) -> Self {
+        state.set_chain_config(config.chain);
+        state.set_app_namespaces(
+            [namespace, attachment_namespace(namespace)]
+                .iter()
+                .filter_map(|namespace| namespace.id_v0()?.try_into().ok())
+                .collect(),
+        );
+        state.set_index_filter(config.index_filter.clone());
+        let metrics = Arc::new(Metrics::new());
+        FullNode {
+            batcher: Batcher::new(da.clone(), namespace, metrics.clone()),
+            da,
             namespace,
             start_height,
-            pending_transactions: Arc::new(Mutex::new(Vec::new())),
-            state: Arc::new(Mutex::new(State::new())),
+            state: Arc::new(Mutex::new(state)),
             genesis_sync_complete: Arc::new(AtomicBool::new(false)),
-            sync_notify: Arc::new(Notify::new()),
-        })
+            sync_error: std::sync::Mutex::new(None),
+            subscription_timeout: config.subscription_timeout,
+            rate_limiter: RateLimiter::new(config.rate_limits),
+            metrics,
+            shutdown: watch::channel(false).0,
+            shutdown_timeout: config.shutdown_timeout,
+            snapshot_path: config.snapshot_path,
+            read_only: config.read_only,
+            upstream: config
+                .upstream_url
+                .filter(|_| config.read_only)
+                .map(|url| Upstream::new(&url, namespace)),
+            follow_channels: config.follow_channels,
+            index_filter: config.index_filter,
+        }
     }
 
-    pub async fn start_server(self: Arc<Self>) -> Result<()> {
-        let app = Router::new()
+    pub fn namespace(&self) -> Namespace {
+        self.namespace
+    }
+
+    pub fn state(&self) -> &Arc<Mutex<State>> {
+        &self.state
+    }
+
+    /// Whether the node has caught up with the DA layer since it started.
+    pub fn is_synced(&self) -> bool {
+        self.genesis_sync_complete.load(Ordering::SeqCst)
+    }
+
+    /// Why sync is down, if it is.
+    pub fn sync_error(&self) -> Option<String> {
+        self.sync_error.lock().unwrap().clone()
+    }
+
+    fn set_sync_error(&self, error: Option<String>) -> Option<String> {
+        std::mem::replace(&mut self.sync_error.lock().unwrap(), error)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Starts a graceful shutdown of `start`.
+    pub fn shutdown(&self) {
+        self.batcher.close();
+        self.shutdown.send_replace(true);
+    }
+
+    /// Resolves once shutdown has started.
+    fn shutdown_requested(&self) -> impl std::future::Future<Output = ()> {
+        let mut shutdown = self.shutdown.subscribe();
+        async move {
+            let _ = shutdown.wait_for(|shutting_down| *shutting_down).await;
+        }
+    }
+
+    pub async fn status(&self) -> NodeStatus {
+        let network_height = self.metrics.network_height.get();
+        // Read before `channel_namespaces`, which takes the state lock too.
+        let last_height = self.state.lock().await.last_height();
+        NodeStatus {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            namespace: namespace_hex(&self.namespace),
+            start_height: self.start_height,
+            read_only: self.read_only,
+            upstream: self
+                .upstream
+                .as_ref()
+                .map(|upstream| upstream.url().to_string()),
+            genesis_sync_complete: self.is_synced(),
+            sync_error: self.sync_error(),
+            last_height,
+            network_height: (network_height > 0).then_some(network_height),
+            channel_namespaces: self
+                .channel_namespaces()
+                .await
+                .iter()
+                .map(namespace_hex)
+                .collect(),
+            index_filter: self.index_filter.clone(),
+            pending_transactions: self.batcher.pending_len().await,
+            last_batch: self.batcher.last_batch().await,
+        }
+    }
+
+    /// The node's API, unprefixed. `serve` mounts it under the node's
+    /// namespace. Roots and proofs are only served by nodes indexing
+    /// everything.
+    pub(crate) fn router(self: Arc<Self>) -> Router {
+        let mut router = Router::new()
             .route("/channels", get(list_channels))
             .route("/channels/:channel", get(read_channel))
-            .route("/register", post(register_user))
-            .route("/send", post(send_message))
-            .with_state(self.clone());
-
-        let addr = "0.0.0.0:3000";
-        println!("Server listening on {}", addr);
-        axum::Server::bind(&addr.parse().unwrap())
-            .serve(app.into_make_service())
+            .route("/directory", get(directory))
+            .route("/register", post(register_user::<Self>))
+            .route("/send", post(send_message::<Self>))
+            .route("/tx", post(submit_transaction::<Self>))
+            .route("/snapshot", get(snapshot));
+        if self.index_filter.is_everything() {
+            router = router
+                .route("/root/:height", get(state_root))
+                .route("/root/:height/:namespace", get(channel_root))
+                .route("/proof/user/:key", get(prove_user))
+                .route("/proof/channel/:channel", get(prove_channel))
+                .route("/proof/message/:id", get(prove_message));
+        }
+        router
+            .route("/search", get(search))
+            .route("/users/:id", get(get_user))
+            .route("/users/:id/messages", get(get_user_messages))
+            .route("/attachments", post(upload_attachment::<Self>))
+            .route("/attachments/:height/:commitment", get(download_attachment))
+            .route("/metrics", get(metrics))
+            .route("/health", get(health))
+            .route("/ready", get(ready))
+            .route("/status", get(status))
+            .route_layer(middleware::from_fn_with_state(
+                self.clone(),
+                observe_request::<Self, _>,
+            ))
+            .with_state(self)
+    }
+
+    pub async fn start_server(self: Arc<Self>) -> Result<()> {
+        let shutdown = self.shutdown_requested();
+        serve(&[self], shutdown).await
+    }
+
+    /// Queues `tx` for the next batch. Messages to a channel in its own
+    /// namespace are posted there, and messages over their sender's quota
+    /// are turned away rather than posted to be rejected.
+    pub async fn queue_transaction(&self, tx: Transaction) -> Result<()> {
+        validation::validate_tx(&tx)?;
+        if self.read_only {
+            bail!("node is read-only");
+        }
+        let namespace = match &tx {
+            Transaction::SendMessage(msg) => {
+                let pending = self.batcher.pending_messages().await;
+                let state = self.state.lock().await;
+                state.check_pending_quota(msg, &pending)?;
+                state
+                    .channel_namespace(&msg.channel)
+                    .map(Namespace::const_v0)
+            }
+            _ => None,
+        };
+        self.batcher
+            .queue_to(namespace.unwrap_or(self.namespace), tx)
             .await
-            .context("Failed to start server")?;
+    }
 
-        Ok(())
+    /// The namespaces of the followed channels that were moved out of the
+    /// app namespace, which are downloaded along with it. Channels that
+    /// wouldn't be indexed are left out.
+    pub async fn channel_namespaces(&self) -> Vec<Namespace> {
+        let state = self.state.lock().await;
+        let mut namespaces: Vec<_> = state
+            .directory()
+            .into_iter()
+            .filter(|(channel, _)| {
+                self.follow_channels.follows(channel)
+                    && state.index_filter().indexes_channel(channel)
+            })
+            .map(|(_, assignment)| Namespace::const_v0(assignment.namespace))
+            .filter(|namespace| *namespace != self.namespace)
+            .collect();
+        namespaces.sort();
+        namespaces.dedup();
+        namespaces
     }
 
-    pub async fn queue_transaction(self: Arc<Self>, tx: Transaction) -> Result<()> {
-        let mut pending_txs = self.pending_transactions.lock().await;
-        pending_txs.push(tx);
-        Ok(())
+    pub async fn post_pending_batch(self: Arc<Self>) -> Result<()> {
+        self.batcher.post_pending().await
     }
 
-    async fn post_pending_batch(self: Arc<Self>) -> Result<()> {
-        let mut pending_txs = self.pending_transactions.lock().await;
-        if pending_txs.is_empty() {
-            return Ok(());
+    /// Posts an attachment as its own blob in the attachment namespace,
+    /// returning the height and commitment a `SendMessage` can reference it
+    /// by.
+    pub async fn post_attachment(&self, data: Vec<u8>) -> Result<(u64, Commitment)> {
+        if self.read_only {
+            bail!("node is read-only");
         }
+        self.batcher.post_attachment(data).await
+    }
 
-        let batch = Batch(pending_txs.drain(..).collect());
-        let encoded_batch = bincode::serialize(&batch)?;
-
-        let blob = Blob::new(self.namespace, encoded_batch)?;
-        BlobClient::blob_submit(&self.da_client, &[blob], TxConfig::default()).await?;
+    /// Looks in the attachment namespace, then in the app namespace where
+    /// attachments used to be posted.
+    pub async fn fetch_attachment(
+        self: Arc<Self>,
+        height: u64,
+        commitment: Commitment,
+    ) -> Result<Vec<u8>> {
+        let namespace = attachment_namespace(self.namespace);
+        let blob = match self.da.get_blob(height, namespace, commitment).await {
+            Ok(blob) => blob,
+            Err(_) => self.da.get_blob(height, self.namespace, commitment).await?,
+        };
+        Ok(blob.data)
+    }
 
-        println!("Batch posted with {} transactions", batch.0.len());
-        Ok(())
+    pub async fn is_registered(&self, user: &PublicKey) -> bool {
+        self.state.lock().await.user_info(user).is_some()
     }
 
-    async fn process_l1_block(self: Arc<Self>, blobs: Vec<Blob>) {
-        let txs: Vec<Transaction> = blobs
+    /// Applies the transactions at `height`, from the app namespace and
+    /// any channel namespaces followed. Blobs are ordered by their position
+    /// in the block and transactions by their position in the batch, so
+    /// every node applies a height identically no matter how many nodes
+    /// posted batches to it or in which order the DA node returned them.
+    #[instrument(skip(self, blobs), fields(blobs = blobs.len()))]
+    async fn process_l1_block(self: Arc<Self>, height: u64, mut blobs: Vec<Blob>) {
+        blobs.sort_by_key(|blob| blob.index.unwrap_or(u64::MAX));
+        let txs: Vec<(Option<NamespaceId>, PostedTransaction)> = blobs
             .into_iter()
-            .flat_map(|blob| match Batch::try_from(&blob) {
-                Ok(batch) => batch.0,
-                Err(_) => Vec::new(),
+            .flat_map(|blob| {
+                // Blobs outside the app namespace are identified by the v0
+                // id of the channel namespace they are in.
+                let namespace = if blob.namespace == self.namespace {
+                    Some(None)
+                } else {
+                    blob.namespace
+                        .id_v0()
+                        .and_then(|id| id.try_into().ok())
+                        .map(Some)
+                };
+                match (namespace, BlobTransactions::try_from(&blob)) {
+                    (Some(namespace), Ok(BlobTransactions::Versioned(txs))) => txs
+                        .into_iter()
+                        .map(|tx| (namespace, PostedTransaction::Versioned(tx)))
+                        .collect(),
+                    (Some(namespace), Ok(BlobTransactions::Legacy(txs))) => txs
+                        .into_iter()
+                        .map(|tx| (namespace, PostedTransaction::Legacy(tx)))
+                        .collect(),
+                    _ => {
+                        debug!(
+                            commitment = hex::encode(blob.commitment.0),
+                            "Skipping blob that is not a batch"
+                        );
+                        Vec::new()
+                    }
+                }
             })
             .collect();
 
         let mut state = self.state.lock().await;
-        txs.into_iter().for_each(|tx| match state.process_tx(tx) {
-            Ok(_) => println!("Processed transaction"),
-            Err(e) => eprintln!("Error processing tx: {}", e),
-        });
-    }
-
-    async fn sync_from_genesis(self: Arc<Self>) -> Result<()> {
-        let network_head = HeaderClient::header_network_head(&self.da_client).await?;
-        let network_height = network_head.height();
-        for height in self.start_height..network_height.value() {
-            let response =
-                BlobClient::blob_get_all(&self.da_client, height, &[self.namespace]).await?;
-            if let Some(blobs) = response {
-                self.clone().process_l1_block(blobs).await;
+        state.begin_block(height);
+        for (namespace, tx) in txs {
+            let hash = match &tx {
+                PostedTransaction::Versioned(tx) => tx.hash(),
+                PostedTransaction::Legacy(tx) => tx.hash(),
+            };
+            let hash = hash.map(hex::encode).unwrap_or_default();
+            let _span = tracing::debug_span!("tx", hash).entered();
+            let result = match tx {
+                PostedTransaction::Versioned(tx) => state.process_tx_in(namespace, tx),
+                PostedTransaction::Legacy(tx) => state.process_legacy_tx_in(namespace, tx),
+            };
+            match result {
+                Ok(_) => {
+                    self.metrics.txs_applied.inc();
+                    debug!("Processed transaction");
+                }
+                Err(e) => {
+                    let reason = e
+                        .downcast_ref::<Rejected>()
+                        .map_or("other", |rejected| rejected.reason.as_str());
+                    self.metrics.txs_rejected.inc(&["apply", reason]);
+                    info!(reason, error = %e, "Rejected transaction");
+                }
             }
         }
-        self.genesis_sync_complete.store(true, Ordering::SeqCst);
-        self.sync_notify.notify_waiters();
-        Ok(())
+        state.set_last_height(height);
+        self.metrics.synced_height.set(height);
     }
 
-    pub async fn start_batch_posting(self: Arc<Self>) {
-        let mut interval = interval(BATCH_INTERVAL);
+    async fn fetch_height(
+        self: Arc<Self>,
+        height: u64,
+        blobs: Option<Vec<Blob>>,
+    ) -> Result<FetchedHeight> {
+        self.da.fetch_height(height, self.namespace, blobs).await
+    }
 
-        loop {
-            interval.tick().await;
-            if let Err(e) = self.clone().post_pending_batch().await {
-                eprintln!("Error posting batch: {}", e);
+    /// Verifies a fetched height against the last trusted header and applies
+    /// it to the state. Must be called with heights in order.
+    async fn apply_height(
+        self: Arc<Self>,
+        cursor: &mut SyncCursor,
+        fetched: FetchedHeight,
+    ) -> Result<()> {
+        let height = fetched.height;
+        if height != cursor.next_height {
+            bail!(
+                "expected height {} to be applied next, got {height}",
+                cursor.next_height
+            );
+        }
+        match (&cursor.trusted, &fetched.header) {
+            (Some(trusted), Some(header)) => trusted
+                .verify(header)
+                .context(format!("Failed to verify header at height {height}"))?,
+            (Some(_), None) => bail!("height {height} has no header to verify"),
+            (None, _) => {}
+        }
+
+        // Which channel namespaces to fetch depends on the state, since
+        // channels are moved as heights are applied, so they are fetched
+        // here rather than ahead with the app namespace.
+        let mut blobs = fetched.blobs;
+        for namespace in self.channel_namespaces().await {
+            let channel_height = self.da.fetch_height(height, namespace, None).await?;
+            let same_header = match (&fetched.header, &channel_height.header) {
+                (Some(header), Some(channel_header)) => header.hash() == channel_header.hash(),
+                _ => true,
+            };
+            if !same_header {
+                bail!(
+                    "header for namespace {} at height {height} does not match",
+                    namespace_hex(&namespace)
+                );
             }
+            blobs.extend(channel_height.blobs);
         }
+
+        self.process_l1_block(height, blobs).await;
+        cursor.advance(height, fetched.header);
+        Ok(())
     }
 
-    async fn sync_incoming_blocks(self: Arc<Self>) -> Result<(), tokio::task::JoinError> {
-        let (tx, mut rx) = mpsc::channel(100); // Adjust buffer size as needed
+    /// Applies every height from the cursor up to and including `target`,
+    /// fetching up to `FETCH_WINDOW` heights ahead concurrently.
+    async fn catch_up(self: Arc<Self>, cursor: &mut SyncCursor, target: u64) -> Result<()> {
+        let mut progress = SyncProgress::new(cursor.next_height, target);
+        let mut pending = VecDeque::new();
+        let mut next_fetch = cursor.next_height;
+
+        while cursor.next_height <= target {
+            while next_fetch <= target && pending.len() < FETCH_WINDOW {
+                pending.push_back(spawn(self.clone().fetch_height(next_fetch, None)));
+                next_fetch += 1;
+            }
 
-        // Start the subscription immediately
-        let subscription_handle = spawn({
-            let node = self.clone();
-            async move {
-                let mut blobsub = BlobClient::blob_subscribe(&node.da_client, node.namespace)
-                    .await
-                    .context("Failed to subscribe to app namespace")
-                    .unwrap();
-
-                while let Some(result) = blobsub.next().await {
-                    match result {
-                        Ok(blob_response) => {
-                            if let Some(blobs) = blob_response.blobs {
-                                if tx.send(blobs).await.is_err() {
-                                    break;
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            println!("Error retrieving blobs from DA layer: {}", e);
-                        }
-                    }
-                }
+            let handle = pending
+                .pop_front()
+                .expect("a fetch is pending for every height up to the target");
+            let applied = async {
+                let fetched = handle.await??;
+                self.clone().apply_height(cursor, fetched).await
+            }
+            .await;
+            if let Err(e) = applied {
+                pending.iter().for_each(JoinHandle::abort);
+                return Err(e);
             }
-        });
 
-        // Wait for genesis sync to complete before processing incoming blocks
-        self.sync_notify.notified().await;
+            progress.report(cursor.next_height - 1);
+        }
+        Ok(())
+    }
 
-        // Process incoming blocks
-        while let Some(blobs) = rx.recv().await {
-            self.clone().process_l1_block(blobs).await;
+    /// Applies every height from the cursor up to and including `height`.
+    /// Heights the cursor has already passed are skipped, and if `blobs` are
+    /// given they are used for `height` itself instead of being refetched.
+    async fn sync_to(
+        self: Arc<Self>,
+        cursor: &mut SyncCursor,
+        height: u64,
+        blobs: Option<Vec<Blob>>,
+    ) -> Result<()> {
+        if height < cursor.next_height {
+            debug!(height, "Skipping already applied height");
+            return Ok(());
         }
 
-        subscription_handle.await
+        if cursor.next_height < height {
+            self.clone().catch_up(cursor, height - 1).await?;
+        }
+        let fetched = self.clone().fetch_height(height, blobs).await?;
+        self.apply_height(cursor, fetched).await
     }
 
+    /// Keeps the state in sync with the DA layer. Whenever sync fails, for
+    /// instance because the DA node went away or the subscription died, the
+    /// node is marked degraded, reconnects after a backoff and resumes from
+    /// the last applied height.
     pub async fn start_sync(self: Arc<Self>) -> Result<()> {
-        let genesis_sync = spawn({
-            let node = self.clone();
-            async move { node.sync_from_genesis().await }
-        });
+        let next_height = match self.state.lock().await.last_height() {
+            Some(height) => height + 1,
+            None => self.start_height,
+        };
+        let mut cursor = SyncCursor::new(next_height);
+        let mut backoff = MIN_SYNC_BACKOFF;
 
-        let incoming_sync = spawn({
-            let node = self.clone();
-            async move { node.sync_incoming_blocks().await }
-        });
+        loop {
+            let resumed_from = cursor.next_height;
+            let error = match self.clone().sync(&mut cursor).await {
+                Ok(()) => anyhow!("sync stopped"),
+                Err(e) => e,
+            };
+            if cursor.next_height > resumed_from {
+                backoff = MIN_SYNC_BACKOFF;
+            }
 
-        let _ = tokio::try_join!(genesis_sync, incoming_sync)?;
+            self.metrics.sync_restarts.inc();
+            self.set_sync_error(Some(format!("{error:#}")));
+            warn!(
+                error = format!("{error:#}"),
+                retry_in_secs = backoff.as_secs(),
+                "Sync failed, restarting"
+            );
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_SYNC_BACKOFF);
+
+            if let Err(e) = self.da.reconnect().await {
+                warn!(error = format!("{e:#}"), "Failed to reconnect to DA node");
+            }
+        }
+    }
 
-        Ok(())
+    /// Subscribes, catches up from the cursor to the network head and then
+    /// applies heights as they arrive, until something fails.
+    async fn sync(self: Arc<Self>, cursor: &mut SyncCursor) -> Result<()> {
+        // Subscribe before catching up so that heights included while we
+        // sync from genesis are buffered instead of missed.
+        let mut rx = self.da.subscribe(self.namespace).await?;
+
+        let network_height = self.da.network_height().await?;
+        self.metrics.network_height.set(network_height);
+        self.clone().catch_up(cursor, network_height).await?;
+        if !self.genesis_sync_complete.swap(true, Ordering::SeqCst) {
+            info!(height = network_height, "Genesis sync complete");
+        }
+        if self.set_sync_error(None).is_some() {
+            info!(height = network_height, "Sync recovered");
+        }
+
+        loop {
+            // Every height is delivered, even without blobs in our
+            // namespace, so silence means the subscription has died.
+            let Ok(received) = timeout(self.subscription_timeout, rx.recv()).await else {
+                bail!(
+                    "no heights received from the subscription in {:?}",
+                    self.subscription_timeout
+                );
+            };
+            let Some(BlobsAtHeight { blobs, height }) = received else {
+                bail!("blob subscription closed");
+            };
+            self.metrics.network_height.set(height);
+            self.clone()
+                .sync_to(cursor, height, Some(blobs.unwrap_or_default()))
+                .await?;
+        }
     }
 
-    pub async fn start(self: Arc<Self>) -> Result<()> {
+    /// Posts pending transactions until shutdown starts. The final batch is
+    /// left to `start`. Read-only nodes have nothing to post.
+    pub async fn start_batch_posting(self: Arc<Self>) {
+        if self.read_only {
+            return;
+        }
+        self.batcher.run(self.shutdown_requested()).await
+    }
+
+    /// Runs sync and batch posting until shutdown starts. The final batch
+    /// and snapshot are left to `finish`.
+    pub(crate) async fn run(self: Arc<Self>) -> Result<()> {
+        // Dropping sync on shutdown only ever stops it between heights, as
+        // each height is applied under a single lock of the state.
         let sync_handle = spawn({
             let node = self.clone();
-            async move { node.start_sync().await }
+            async move {
+                tokio::select! {
+                    synced = node.clone().start_sync() => synced,
+                    _ = node.shutdown_requested() => Ok(()),
+                }
+            }
         });
 
         let batch_posting_handle = spawn({
@@ -215,13 +800,169 @@ impl FullNode {
             async move { node.start_batch_posting().await }
         });
 
+        let (synced, _) = tokio::try_join!(sync_handle, batch_posting_handle)?;
+        synced
+    }
+
+    /// Posts the pending transactions as a final batch and saves a snapshot
+    /// if `snapshot_path` is set. Called once shutdown has started.
+    pub async fn finish(self: Arc<Self>) -> Result<()> {
+        self.clone().post_pending_batch().await?;
+        self.save_snapshot().await
+    }
+
+    /// Runs the node until SIGINT, SIGTERM or `shutdown`, then shuts down
+    /// gracefully: the server stops taking requests and finishes the ones in
+    /// flight, the pending transactions are posted as a final batch, and the
+    /// state is saved as a snapshot if `snapshot_path` is set.
+    pub async fn start(self: Arc<Self>) -> Result<()> {
+        let run_handle = spawn(self.clone().run());
+
         let server_handle = spawn({
             let node = self.clone();
             async move { node.start_server().await }
         });
 
-        let _ = tokio::try_join!(sync_handle, batch_posting_handle, server_handle)?;
+        let tasks =
+            async { tokio::try_join!(async { run_handle.await? }, async { server_handle.await? }) };
+        run_until_shutdown(
+            tasks,
+            self.shutdown_requested(),
+            || self.shutdown(),
+            self.shutdown_timeout,
+            self.clone().finish(),
+        )
+        .await
+    }
 
+    /// Writes the state to `snapshot_path` so the node can be restored from
+    /// where it stopped instead of resyncing.
+    async fn save_snapshot(&self) -> Result<()> {
+        let Some(path) = &self.snapshot_path else {
+            return Ok(());
+        };
+        let state = self.state.lock().await;
+        if state.last_height().is_none() {
+            info!("No height applied yet, not saving a snapshot");
+            return Ok(());
+        }
+        if !state.index_filter().is_everything() {
+            info!("Not every message is indexed, not saving a snapshot");
+            return Ok(());
+        }
+        let snapshot = state.to_snapshot()?;
+        snapshot.write(path)?;
+        info!(
+            height = snapshot.height,
+            state_root = hex::encode(snapshot.state_root),
+            path = %path.display(),
+            "Saved snapshot"
+        );
         Ok(())
     }
 }
+
+/// The form `start-fullnode` takes a namespace in.
+pub(crate) fn namespace_hex(namespace: &Namespace) -> String {
+    hex::encode(namespace.id_v0().unwrap_or_else(|| namespace.as_bytes()))
+}
+
+/// Where the attachments of the network in `app` are posted, apart from its
+/// transactions so that syncing nodes don't download them: the v0 namespace
+/// whose id is the first 10 bytes of a hash of the app namespace.
+pub fn attachment_namespace(app: Namespace) -> Namespace {
+    let mut hasher = Sha256::new();
+    hasher.update(b"grugchat-attachments");
+    hasher.update(app.as_bytes());
+    let hash: [u8; 32] = hasher.finalize().into();
+    Namespace::const_v0(hash[..10].try_into().unwrap())
+}
+
+/// Serves the API of each node under `/ns/<namespace_hex>` on port 3000
+/// until `shutdown` resolves. Requests without the prefix go to the
+/// namespace named by the `x-grugchat-namespace` header, or to the first
+/// node if there is none.
+pub(crate) async fn serve(
+    nodes: &[Arc<FullNode>],
+    shutdown: impl std::future::Future<Output = ()>,
+) -> Result<()> {
+    let mut namespaces = Vec::new();
+    let mut routes = Router::new();
+    for node in nodes {
+        let namespace = namespace_hex(&node.namespace);
+        routes = routes.nest(&format!("/ns/{namespace}"), node.clone().router());
+        namespaces.push(namespace);
+    }
+    // Rewriting the path has to happen before `routes` routes the request,
+    // so it runs around it as a fallback rather than as one of its layers.
+    let app = Router::new()
+        .fallback_service(routes)
+        .layer(middleware::from_fn_with_state(
+            Arc::new(namespaces),
+            route_namespace,
+        ));
+
+    let addr = "0.0.0.0:3000";
+    info!(addr, namespaces = nodes.len(), "Server listening");
+    axum::Server::bind(&addr.parse().unwrap())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown)
+        .await
+        .context("Failed to start server")?;
+
+    Ok(())
+}
+
+/// Waits for `tasks`, returning early with whatever they return, until
+/// SIGINT, SIGTERM or `shutdown_requested`. Then calls `shutdown` and gives
+/// `tasks` and then `finish` `shutdown_timeout` to wind down; an error
+/// `tasks` stops with by then is logged rather than returned, so `finish`
+/// still runs.
+pub(crate) async fn run_until_shutdown<T>(
+    tasks: impl std::future::Future<Output = Result<T>>,
+    shutdown_requested: impl std::future::Future<Output = ()>,
+    shutdown: impl FnOnce(),
+    shutdown_timeout: Duration,
+    finish: impl std::future::Future<Output = Result<()>>,
+) -> Result<()> {
+    tokio::pin!(tasks);
+    tokio::select! {
+        signal = shutdown_signal() => signal?,
+        _ = shutdown_requested => {}
+        joined = &mut tasks => return joined.map(|_| ()),
+    }
+
+    info!("Shutting down");
+    shutdown();
+    let drained = timeout(shutdown_timeout, async {
+        if let Err(e) = (&mut tasks).await {
+            error!(error = %e, "Stopped with an error while shutting down");
+        }
+        finish.await
+    })
+    .await;
+
+    match drained {
+        Ok(result) => result,
+        Err(_) => bail!(
+            "shutdown did not finish within {}s",
+            shutdown_timeout.as_secs()
+        ),
+    }
+}
+
+/// Resolves on the first SIGINT or SIGTERM.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            interrupted = tokio::signal::ctrl_c() => interrupted?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
@@ -0,0 +1,277 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use axum::{
+    http::StatusCode,
+    middleware,
+    routing::{get, post},
+    Router,
+};
+use celestia_types::nmt::Namespace;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::spawn;
+use tokio::sync::{watch, Mutex};
+use tokio::time::{Duration, Instant};
+use tracing::info;
+
+use crate::{
+    batcher::{Batcher, PostedBatch},
+    config::NodeConfig,
+    da::{CelestiaDa, DataAvailabilityLayer},
+    fullnode::{namespace_hex, run_until_shutdown},
+    metrics::Metrics,
+    ratelimit::RateLimiter,
+    tx::{NamespaceId, Transaction},
+    upstream::Upstream,
+    webserver::*,
+};
+
+/// How long the directory fetched from the state node is used for, which
+/// bounds how long after a channel moves its messages still go to the app
+/// namespace, to be skipped there.
+const DIRECTORY_TTL: Duration = Duration::from_secs(3);
+
+/// Accepts writes and posts them to the DA layer in batches, without
+/// syncing or keeping any state, so posting can be run and scaled apart
+/// from the nodes that index and serve reads.
+///
+/// Transactions are only checked statelessly, for a valid signature and
+/// contents. Anything the state would reject, like a message from an
+/// unregistered key, is still posted and then skipped by full nodes when
+/// they apply it.
+///
+/// Messages are posted to the namespace their channel is in, as listed in
+/// the directory of the full node at `state_url`, and attachments only for
+/// users registered there. Without that node, every message goes to the
+/// app namespace and attachments are refused.
+pub struct Gateway {
+    namespace: Namespace,
+    batcher: Batcher,
+    state_node: Option<Upstream>,
+    /// The state node's directory, by channel, and when it was fetched.
+    directory: Mutex<Option<(Instant, HashMap<String, NamespaceId>)>>,
+    rate_limiter: RateLimiter,
+    metrics: Arc<Metrics>,
+
+    shutdown: watch::Sender<bool>,
+    shutdown_timeout: Duration,
+}
+
+/// What `GET /status` reports about a gateway.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GatewayStatus {
+    pub version: String,
+    pub namespace: String,
+    pub pending_transactions: usize,
+    pub last_batch: Option<PostedBatch>,
+}
+
+impl Gateway {
+    pub async fn new(namespace: Namespace) -> Result<Self> {
+        let config = NodeConfig::from_env()?;
+        let da = CelestiaDa::connect("ws://localhost:26658", None)
+            .await?
+            .with_gas_price(config.da_gas_price);
+        Ok(Self::with_da(Arc::new(da), namespace, config))
+    }
+
+    pub fn with_da(
+        da: Arc<dyn DataAvailabilityLayer>,
+        namespace: Namespace,
+        config: NodeConfig,
+    ) -> Self {
+        let metrics = Arc::new(Metrics::new());
+        Gateway {
+            namespace,
+            batcher: Batcher::new(da, namespace, metrics.clone()),
+            state_node: config.state_url.map(|url| Upstream::new(&url, namespace)),
+            directory: Mutex::new(None),
+            rate_limiter: RateLimiter::new(config.rate_limits),
+            metrics,
+            shutdown: watch::channel(false).0,
+            shutdown_timeout: config.shutdown_timeout,
+        }
+    }
+
+    pub async fn post_pending_batch(&self) -> Result<()> {
+        self.batcher.post_pending().await
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Starts a graceful shutdown of `start`.
+    pub fn shutdown(&self) {
+        self.batcher.close();
+        self.shutdown.send_replace(true);
+    }
+
+    /// Resolves once shutdown has started.
+    fn shutdown_requested(&self) -> impl std::future::Future<Output = ()> {
+        let mut shutdown = self.shutdown.subscribe();
+        async move {
+            let _ = shutdown.wait_for(|shutting_down| *shutting_down).await;
+        }
+    }
+
+    /// The namespace a message to `channel` goes to, looked up in the state
+    /// node's directory, which is fetched again once `DIRECTORY_TTL` old.
+    /// Without a state node, that is always the app namespace.
+    async fn message_namespace(&self, channel: &str) -> Result<Namespace, (StatusCode, String)> {
+        let Some(state_node) = &self.state_node else {
+            return Ok(self.namespace);
+        };
+        let cached = match &*self.directory.lock().await {
+            Some((fetched, channels)) if fetched.elapsed() < DIRECTORY_TTL => {
+                Some(channels.get(channel).copied())
+            }
+            _ => None,
+        };
+        let id = match cached {
+            Some(id) => id,
+            None => {
+                // The lock isn't held while fetching, so a slow state node
+                // doesn't hold up messages that could use the cache.
+                let channels: HashMap<_, _> = state_node
+                    .directory()
+                    .await?
+                    .into_iter()
+                    .map(|(channel, assignment)| (channel, assignment.namespace))
+                    .collect();
+                let id = channels.get(channel).copied();
+                *self.directory.lock().await = Some((Instant::now(), channels));
+                id
+            }
+        };
+        Ok(id.map_or(self.namespace, Namespace::const_v0))
+    }
+
+    pub async fn status(&self) -> GatewayStatus {
+        GatewayStatus {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            namespace: namespace_hex(&self.namespace),
+            pending_transactions: self.batcher.pending_len().await,
+            last_batch: self.batcher.last_batch().await,
+        }
+    }
+
+    pub async fn start_server(self: Arc<Self>) -> Result<()> {
+        let app = Router::new()
+            .route("/register", post(register_user::<Self>))
+            .route("/send", post(send_message::<Self>))
+            .route("/tx", post(submit_transaction::<Self>))
+            .route("/attachments", post(upload_attachment::<Self>))
+            .route("/metrics", get(gateway_metrics))
+            .route("/health", get(health))
+            // With nothing to sync, a gateway is ready as soon as it is up.
+            .route("/ready", get(health))
+            .route("/status", get(gateway_status))
+            .route_layer(middleware::from_fn_with_state(
+                self.clone(),
+                observe_request::<Self, _>,
+            ))
+            .with_state(self.clone());
+
+        let addr = "0.0.0.0:3000";
+        info!(addr, "Gateway listening");
+        axum::Server::bind(&addr.parse().unwrap())
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(self.shutdown_requested())
+            .await
+            .context("Failed to start server")?;
+
+        Ok(())
+    }
+
+    /// Runs the gateway until SIGINT, SIGTERM or `shutdown`, then stops
+    /// taking requests and posts whatever is pending as a final batch.
+    pub async fn start(self: Arc<Self>) -> Result<()> {
+        let batch_posting_handle = spawn({
+            let gateway = self.clone();
+            async move { gateway.batcher.run(gateway.shutdown_requested()).await }
+        });
+
+        let server_handle = spawn({
+            let gateway = self.clone();
+            async move { gateway.start_server().await }
+        });
+
+        let tasks = async {
+            tokio::try_join!(async { Ok(batch_posting_handle.await?) }, async {
+                server_handle.await?
+            })
+        };
+        run_until_shutdown(
+            tasks,
+            self.shutdown_requested(),
+            || self.shutdown(),
+            self.shutdown_timeout,
+            self.post_pending_batch(),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl Ingress for Gateway {
+    fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        Gateway::is_shutting_down(self)
+    }
+
+    fn forwards_writes(&self) -> bool {
+        false
+    }
+
+    async fn accept_transaction(
+        &self,
+        tx: Transaction,
+        _pow_nonce: Option<u64>,
+        _client_ip: IpAddr,
+    ) -> Result<(), (StatusCode, String)> {
+        let namespace = match &tx {
+            Transaction::SendMessage(msg) => self.message_namespace(&msg.channel).await?,
+            _ => self.namespace,
+        };
+        self.batcher
+            .queue_to(namespace, tx)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+    }
+
+    async fn accept_attachment(
+        &self,
+        uploader: &AttachmentUploader,
+        _client_ip: IpAddr,
+        data: Vec<u8>,
+    ) -> Result<AttachmentUpload, (StatusCode, String)> {
+        let Some(state_node) = &self.state_node else {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "this gateway has no full node to check uploaders on".to_string(),
+            ));
+        };
+        if !state_node.is_registered(&uploader.user).await? {
+            return Err(unregistered_uploader());
+        }
+        let (height, commitment) = self
+            .batcher
+            .post_attachment(data)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        Ok(AttachmentUpload {
+            height,
+            commitment: commitment.0,
+        })
+    }
+}
@@ -0,0 +1,96 @@
+use crate::tx::{self, Content, PublicKey, Signature};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Transactions as networks started out encoding them, before message
+/// contents were structured. Blobs in this encoding are unversioned: either
+/// a batch of these or a single one. They are only applied below the
+/// `versioned_batches` activation height.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Transaction {
+    SendMessage(SendMessage),
+    Register(Register),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SendMessage {
+    pub user: PublicKey,
+    pub contents: String,
+    pub channel: String,
+    pub signature: Signature,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Register {
+    pub user: PublicKey,
+    pub id: String,
+    pub signature: Signature,
+}
+
+impl Transaction {
+    /// Decodes an unversioned blob.
+    pub fn decode_batch(data: &[u8]) -> Result<Vec<Transaction>> {
+        match bincode::deserialize(data) {
+            Ok(batch) => Ok(batch),
+            Err(_) => {
+                let transaction =
+                    bincode::deserialize(data).context("Failed to decode unversioned blob")?;
+                Ok(vec![transaction])
+            }
+        }
+    }
+
+    pub fn hash(&self) -> Result<[u8; 32], bincode::Error> {
+        Ok(Sha256::digest(bincode::serialize(self)?).into())
+    }
+
+    fn signature(&self) -> &Signature {
+        match self {
+            Transaction::SendMessage(SendMessage { signature, .. }) => signature,
+            Transaction::Register(Register { signature, .. }) => signature,
+        }
+    }
+
+    fn pubkey(&self) -> &PublicKey {
+        match self {
+            Transaction::SendMessage(SendMessage { user, .. }) => user,
+            Transaction::Register(Register { user, .. }) => user,
+        }
+    }
+
+    /// The signature is over the encoding with an empty signature, as it is
+    /// for current transactions.
+    pub fn verify_signature(&self) -> bool {
+        let mut unsigned = self.clone();
+        match &mut unsigned {
+            Transaction::SendMessage(msg) => msg.signature = Signature::new(Vec::new()),
+            Transaction::Register(register) => register.signature = Signature::new(Vec::new()),
+        }
+        match bincode::serialize(&unsigned) {
+            Ok(payload) => self.signature().verify(self.pubkey(), &payload),
+            Err(_) => false,
+        }
+    }
+
+    /// The current form of the transaction, to be applied once its own
+    /// signature has been checked, since the current form's won't verify.
+    /// Messages get a nonce of zero, which isn't checked, since they were
+    /// sent without one.
+    pub fn upgrade(self) -> tx::Transaction {
+        match self {
+            Transaction::SendMessage(msg) => tx::Transaction::SendMessage(tx::SendMessage {
+                user: msg.user,
+                contents: Content::Text(msg.contents),
+                channel: msg.channel,
+                nonce: 0,
+                signature: msg.signature,
+            }),
+            Transaction::Register(register) => tx::Transaction::Register(tx::Register {
+                user: register.user,
+                id: register.id,
+                signature: register.signature,
+            }),
+        }
+    }
+}
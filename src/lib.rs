@@ -1,6 +1,18 @@
-#![allow(dead_code)]
-
-mod fullnode;
-mod state;
-mod tx;
-mod webserver;
+pub mod batcher;
+pub mod config;
+pub mod da;
+pub mod fullnode;
+pub mod gateway;
+pub mod legacy;
+pub mod logging;
+pub mod merkle;
+mod metrics;
+pub mod multinode;
+pub mod ratelimit;
+pub mod search;
+pub mod snapshot;
+pub mod state;
+pub mod tx;
+mod upstream;
+pub mod validation;
+pub mod webserver;
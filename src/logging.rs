@@ -0,0 +1,17 @@
+use crate::config::{LogConfig, LogFormat};
+use anyhow::{anyhow, Context, Result};
+use tracing_subscriber::EnvFilter;
+
+/// Installs the global subscriber. Logs go to stderr so they never mix with
+/// command output on stdout.
+pub fn init(config: &LogConfig) -> Result<()> {
+    let filter = EnvFilter::try_new(&config.filter).context("Invalid GRUGCHAT_LOG filter")?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).try_init(),
+    }
+    .map_err(|e| anyhow!(e))
+}
@@ -1,31 +1,57 @@
-use anyhow::{Context, Result};
-use celestia_types::nmt::Namespace;
-use ed25519_dalek::{ed25519::signature::Signer, SigningKey};
+use anyhow::{anyhow, bail, Context, Result};
+use celestia_types::{nmt::Namespace, Blob};
+use ed25519_dalek::{Signer, SigningKey};
+use grugchat::config::{self, RateLimitConfig};
+use grugchat::da::{CelestiaDa, DataAvailabilityLayer};
+use grugchat::fullnode::{attachment_namespace, Batch, FullNode, NodeStatus};
+use grugchat::gateway::Gateway;
+use grugchat::merkle::{self, Hash};
+use grugchat::multinode::MultiNode;
+use grugchat::ratelimit::mint_stamp;
+use grugchat::search::SearchQuery;
+use grugchat::snapshot::Snapshot;
+use grugchat::state::{
+    self, ChannelAssignment, ChannelProof, IndexedMessage, Message, MessageProof, UserInfo,
+    UserProof,
+};
+use grugchat::tx::{
+    attachment_payload, AssignChannel, Attachment, AuthorizeSessionKey, CancelRecovery, Content,
+    LinkPreview, NamespaceId, PublicKey, RecoverAccount, Register, RevokeSessionKey, RotateKey,
+    SendMessage, SessionScope, SetRecoveryKeys, Signature, Transaction, UpdateProfile,
+};
+use grugchat::webserver::{AttachmentUpload, AttachmentUploader};
+use grugchat::{logging, validation};
 use keystore_rs::{KeyChain, KeyStore};
 use reqwest::Client;
 use serde_json::json;
-use state::Message;
-use std::{env, sync::Arc};
-use tx::{Register, SendMessage, Signature, Transaction};
-
-mod fullnode;
-mod state;
-mod tx;
-mod webserver;
-
-use crate::fullnode::FullNode;
+use std::{
+    env,
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+    logging::init(&config::LogConfig::from_env()?)?;
+
+    let mut args: Vec<String> = env::args().collect();
+    let namespace_flags = take_namespace_flags(&mut args)?;
     if args.len() < 2 {
         print_usage();
         return Ok(());
     }
 
     let client = Client::new();
-    let server_url =
+    let mut server_url =
         env::var("GRUGCHAT_SERVER_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    // Nodes serving several namespaces serve each under its own prefix.
+    if let Some(namespace) = namespace_flags.first() {
+        server_url = format!("{}/ns/{}", server_url.trim_end_matches('/'), namespace);
+    }
+    // Only built by the commands that submit, so that a bad submitter
+    // setting doesn't stop the others.
+    let submitter = || Submitter::from_env(&client, &server_url);
 
     match args[1].as_str() {
         "generate-key" => {
@@ -44,7 +70,19 @@ async fn main() -> Result<()> {
                 println!("Error: Channel name required");
                 return Ok(());
             }
-            read_channel(&client, &server_url, &args[2]).await?
+            if args.get(3).map(String::as_str) == Some("--verify") {
+                read_channel_verified(&client, &server_url, &args[2]).await?
+            } else {
+                read_channel(&client, &server_url, &args[2]).await?
+            }
+        }
+        "search" => {
+            if args.len() < 3 {
+                println!("Error: search query required");
+                return Ok(());
+            }
+            let query = parse_search_args(&args[2..])?;
+            search(&client, &server_url, &query).await?
         }
         "register-user" => {
             if args.len() < 3 {
@@ -58,7 +96,7 @@ async fn main() -> Result<()> {
                 println!("Error: {}", res.clone().err().unwrap());
             }
 
-            register_user(&client, &server_url, &res.unwrap(), &args[2]).await?
+            register_user(&submitter()?, &res.unwrap(), &args[2]).await?
         }
         "send-message" => {
             if args.len() < 3 {
@@ -66,17 +104,201 @@ async fn main() -> Result<()> {
                 return Ok(());
             }
 
-            let keychain = KeyChain;
-            let res = keychain.get_signing_key();
-            if res.is_err() {
-                println!("Error: {}", res.clone().err().unwrap());
+            let key = message_signing_key()?;
+            let contents = if args.get(4).map(String::as_str) == Some("--markdown") {
+                Content::Markdown(args[3].clone())
+            } else {
+                Content::Text(args[3].clone())
+            };
+            send_message(&submitter()?, &key, &args[2], contents).await?
+        }
+        "send-link" => {
+            if args.len() < 4 {
+                println!("Error: Channel and URL required");
+                return Ok(());
             }
-            // let key_hex = hex::encode(res.unwrap().verifying_key().to_bytes());
 
-            send_message(&client, &server_url, &res.unwrap(), &args[2], &args[3]).await?
+            let key = message_signing_key()?;
+            let contents = Content::Link(LinkPreview {
+                url: args[3].clone(),
+                title: args.get(4).cloned(),
+                description: args.get(5).cloned(),
+            });
+            send_message(&submitter()?, &key, &args[2], contents).await?
         }
-        "start-fullnode" => {
+        "send-file" => {
+            if args.len() < 4 {
+                println!("Error: Channel and file path required");
+                return Ok(());
+            }
+
+            let key = message_signing_key()?;
+            // Uploads are signed by the account, even if messages aren't.
+            let account_key = KeyChain.get_signing_key().map_err(|e| anyhow!(e))?;
+            let submitter = submitter()?;
+            let attachment =
+                upload_file(&submitter, &account_key, &args[3], args.get(4).cloned()).await?;
+            send_message(&submitter, &key, &args[2], Content::Attachment(attachment)).await?
+        }
+        "download-attachment" => {
+            if args.len() < 5 {
+                println!("Error: channel, message index and output path required");
+                return Ok(());
+            }
+            let index = args[3]
+                .parse::<u64>()
+                .context("Failed to parse message index")?;
+            download_attachment(&client, &server_url, &args[2], index, &args[4]).await?
+        }
+        "profile" => match args.get(2).map(String::as_str) {
+            Some("show") => {
+                let key_hex = match args.get(3) {
+                    Some(key_hex) => key_hex.clone(),
+                    None => {
+                        let key = KeyChain.get_signing_key().map_err(|e| anyhow!(e))?;
+                        hex::encode(key.verifying_key().to_bytes())
+                    }
+                };
+                show_profile(&client, &server_url, &key_hex).await?
+            }
+            Some("set") => {
+                let key = KeyChain.get_signing_key().map_err(|e| anyhow!(e))?;
+                set_profile(&client, &server_url, &submitter()?, &key, &args[3..]).await?
+            }
+            _ => print_usage(),
+        },
+        "rotate-key" => {
+            let key = KeyChain.get_signing_key().map_err(|e| anyhow!(e))?;
+            rotate_key(&submitter()?, &key).await?
+        }
+        "set-recovery-keys" => {
+            let key = KeyChain.get_signing_key().map_err(|e| anyhow!(e))?;
+            let recovery_keys = args[2..]
+                .iter()
+                .map(|key_hex| parse_public_key(key_hex))
+                .collect::<Result<Vec<_>>>()?;
+            let tx = Transaction::SetRecoveryKeys(SetRecoveryKeys {
+                user: key.verifying_key().into(),
+                recovery_keys,
+                nonce: next_nonce(),
+                signature: Signature::new(Vec::new()),
+            });
+            match submitter()?.submit(&key, tx).await {
+                Ok(()) => println!("Recovery keys update sent successfully."),
+                Err(e) => println!("Failed to set recovery keys. {}", e),
+            }
+        }
+        "recover-account" => {
             if args.len() < 4 {
+                println!("Error: account public key and new public key required");
+                return Ok(());
+            }
+            let key = KeyChain.get_signing_key().map_err(|e| anyhow!(e))?;
+            let tx = Transaction::RecoverAccount(RecoverAccount {
+                recovery_key: key.verifying_key().into(),
+                account: parse_public_key(&args[2])?,
+                new_key: parse_public_key(&args[3])?,
+                nonce: next_nonce(),
+                signature: Signature::new(Vec::new()),
+            });
+            match submitter()?.submit(&key, tx).await {
+                Ok(()) => println!(
+                    "Recovery started. The account moves after {} DA heights unless its owner cancels.",
+                    state::RECOVERY_DELAY
+                ),
+                Err(e) => println!("Failed to start recovery. {}", e),
+            }
+        }
+        "cancel-recovery" => {
+            let key = KeyChain.get_signing_key().map_err(|e| anyhow!(e))?;
+            let tx = Transaction::CancelRecovery(CancelRecovery {
+                user: key.verifying_key().into(),
+                nonce: next_nonce(),
+                signature: Signature::new(Vec::new()),
+            });
+            match submitter()?.submit(&key, tx).await {
+                Ok(()) => println!("Recovery cancellation sent successfully."),
+                Err(e) => println!("Failed to cancel recovery. {}", e),
+            }
+        }
+        "authorize-session" => {
+            if args.len() < 3 {
+                println!("Error: expiry height required");
+                return Ok(());
+            }
+            let expires = args[2]
+                .parse::<u64>()
+                .context("Failed to parse expiry height")?;
+            let scope = if args.len() > 3 {
+                SessionScope::Channels(args[3..].to_vec())
+            } else {
+                SessionScope::AnyChannel
+            };
+            let key = KeyChain.get_signing_key().map_err(|e| anyhow!(e))?;
+            authorize_session(&submitter()?, &key, scope, expires).await?
+        }
+        "revoke-session" => {
+            if args.len() < 3 {
+                println!("Error: session public key required");
+                return Ok(());
+            }
+            let key = KeyChain.get_signing_key().map_err(|e| anyhow!(e))?;
+            let tx = Transaction::RevokeSessionKey(RevokeSessionKey {
+                user: key.verifying_key().into(),
+                session_key: parse_public_key(&args[2])?,
+                nonce: next_nonce(),
+                signature: Signature::new(Vec::new()),
+            });
+            match submitter()?.submit(&key, tx).await {
+                Ok(()) => println!("Session key revocation sent successfully."),
+                Err(e) => println!("Failed to revoke session key. {}", e),
+            }
+        }
+        "assign-channel" => {
+            if args.len() < 4 {
+                println!("Error: channel and namespace required");
+                return Ok(());
+            }
+            let namespace = parse_namespace(&args[3])?;
+            let namespace_id = namespace
+                .id_v0()
+                .and_then(|id| id.try_into().ok())
+                .context("Namespace must be a version 0 namespace")?;
+            let key = KeyChain.get_signing_key().map_err(|e| anyhow!(e))?;
+            let tx = Transaction::AssignChannel(AssignChannel {
+                user: key.verifying_key().into(),
+                channel: args[2].clone(),
+                namespace: namespace_id,
+                nonce: next_nonce(),
+                signature: Signature::new(Vec::new()),
+            });
+            match submitter()?.submit(&key, tx).await {
+                Ok(()) => println!(
+                    "Channel assignment sent. Messages to {} go to namespace {} from the height after it is included.",
+                    args[2], args[3]
+                ),
+                Err(e) => println!("Failed to assign channel. {}", e),
+            }
+        }
+        "verify-user" => {
+            if args.len() < 3 {
+                println!("Error: public key required");
+                return Ok(());
+            }
+            verify_user(&client, &server_url, &args[2]).await?
+        }
+        "verify-message" => {
+            if args.len() < 4 {
+                println!("Error: channel and message index required");
+                return Ok(());
+            }
+            let index = args[3]
+                .parse::<u64>()
+                .context("Failed to parse message index")?;
+            verify_message(&client, &server_url, &args[2], index).await?
+        }
+        "start-fullnode" => {
+            if args.len() < 4 && (args.len() < 3 || namespace_flags.is_empty()) {
                 println!("Error: start height and namespace required");
                 return Ok(());
             }
@@ -84,15 +306,59 @@ async fn main() -> Result<()> {
                 .parse::<u64>()
                 .context("Failed to parse start height")?;
 
-            let namespace_bytes =
-                hex::decode(&args[3]).context("Failed to decode namespace hex")?;
-            let namespace = Namespace::new_v0(namespace_bytes.as_slice())
-                .context("Failed to create namespace")?;
+            let namespaces = args[3..]
+                .iter()
+                .chain(&namespace_flags)
+                .map(|namespace_hex| parse_namespace(namespace_hex))
+                .collect::<Result<Vec<_>>>()?;
+
+            if let [namespace] = namespaces[..] {
+                let fullnode = Arc::new(FullNode::new(namespace, start_height).await?);
+                fullnode.start().await?;
+            } else {
+                let multinode = Arc::new(MultiNode::new(&namespaces, start_height).await?);
+                multinode.start().await?;
+            }
+            return Ok(());
+        }
+        "start-gateway" => {
+            let Some(namespace_hex) = args.get(2).or(namespace_flags.first()) else {
+                println!("Error: namespace required");
+                return Ok(());
+            };
+            let namespace = parse_namespace(namespace_hex)?;
 
-            let fullnode = Arc::new(FullNode::new(namespace, start_height).await?);
-            fullnode.start().await?;
+            let gateway = Arc::new(Gateway::new(namespace).await?);
+            gateway.start().await?;
             return Ok(());
         }
+        "snapshot" => match args.get(2).map(String::as_str) {
+            Some("create") => {
+                if args.len() < 4 {
+                    println!("Error: snapshot file required");
+                    return Ok(());
+                }
+                create_snapshot(&client, &server_url, &args[3]).await?
+            }
+            Some("restore") => {
+                if args.len() < 6 {
+                    println!("Error: snapshot file, namespace and state root required");
+                    return Ok(());
+                }
+                let snapshot = Snapshot::read(&args[3])?;
+                let namespace = parse_namespace(&args[4])?;
+                let trusted_root: [u8; 32] = hex::decode(&args[5])
+                    .context("Failed to decode state root hex")?
+                    .try_into()
+                    .map_err(|_| anyhow!("State root must be 32 bytes"))?;
+
+                let fullnode =
+                    Arc::new(FullNode::from_snapshot(namespace, snapshot, trusted_root).await?);
+                fullnode.start().await?;
+                return Ok(());
+            }
+            _ => print_usage(),
+        },
         _ => print_usage(),
     }
 
@@ -101,17 +367,84 @@ async fn main() -> Result<()> {
 
 fn print_usage() {
     println!("Usage:");
+    println!("  grugchat [--namespace <namespace_hex>] <command>");
     println!("  grugchat generate-key");
     println!("  grugchat list-channels");
-    println!("  grugchat read-channel <channel_name>");
+    println!("  grugchat read-channel <channel_name> [--verify]");
+    println!(
+        "  grugchat search <query> [--channel <channel>] [--user <user_id>] [--from <height>] [--to <height>]"
+    );
     println!("  grugchat register-user <user_id>");
-    println!("  grugchat send-message <channel> <message>");
-    println!("  grugchat start-fullnode <start_height> <namespace_hex>");
+    println!("  grugchat send-message <channel> <message> [--markdown]");
+    println!("  grugchat send-link <channel> <url> [title] [description]");
+    println!("  grugchat send-file <channel> <path> [mime_type]");
+    println!("  grugchat download-attachment <channel> <index> <output_path>");
+    println!("  grugchat profile show [public_key_hex]");
+    println!("  grugchat profile set [--name <name>] [--bio <bio>] [--avatar <image_path>]");
+    println!("  grugchat rotate-key");
+    println!("  grugchat set-recovery-keys [public_key_hex...]");
+    println!("  grugchat recover-account <account_public_key_hex> <new_public_key_hex>");
+    println!("  grugchat cancel-recovery");
+    println!("  grugchat authorize-session <expiry_height> [channel...]");
+    println!("  grugchat revoke-session <session_public_key_hex>");
+    println!("  grugchat assign-channel <channel> <namespace_hex>");
+    println!("  grugchat verify-user <public_key_hex>");
+    println!("  grugchat verify-message <channel> <index>");
+    println!(
+        "  grugchat start-fullnode <start_height> <namespace_hex> [--namespace <namespace_hex>...]"
+    );
+    println!("  grugchat start-gateway <namespace_hex>");
+    println!("  grugchat snapshot create <file>");
+    println!("  grugchat snapshot restore <file> <namespace_hex> <state_root_hex>");
+}
+
+/// Removes every `--namespace <namespace_hex>` from `args`, returning the
+/// namespaces. Clients talk to the first; `start-fullnode` serves them all.
+fn take_namespace_flags(args: &mut Vec<String>) -> Result<Vec<String>> {
+    let mut namespaces = Vec::new();
+    while let Some(position) = args.iter().position(|arg| arg == "--namespace") {
+        if position + 1 >= args.len() {
+            bail!("Missing value for --namespace");
+        }
+        namespaces.push(args.remove(position + 1));
+        args.remove(position);
+    }
+    Ok(namespaces)
+}
+
+fn parse_namespace(namespace_hex: &str) -> Result<Namespace> {
+    let namespace_bytes = hex::decode(namespace_hex).context("Failed to decode namespace hex")?;
+    Namespace::new_v0(namespace_bytes.as_slice()).context("Failed to create namespace")
+}
+
+async fn create_snapshot(client: &Client, server_url: &str, path: &str) -> Result<()> {
+    let response = client
+        .get(format!("{}/snapshot", server_url))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        println!(
+            "Failed to create snapshot. Server responded with: {}",
+            response.text().await?
+        );
+        return Ok(());
+    }
+
+    let snapshot: Snapshot = bincode::deserialize(&response.bytes().await?)
+        .context("Failed to decode snapshot from server")?;
+    snapshot.write(path)?;
+    println!(
+        "Snapshot at height {} written to {} (state root {})",
+        snapshot.height,
+        path,
+        hex::encode(snapshot.state_root)
+    );
+    Ok(())
 }
 
 async fn list_channels(client: &Client, server_url: &str) -> Result<()> {
     let channels: Vec<String> = client
-        .get(&format!("{}/channels", server_url))
+        .get(format!("{}/channels", server_url))
         .send()
         .await?
         .json()
@@ -126,7 +459,7 @@ async fn list_channels(client: &Client, server_url: &str) -> Result<()> {
 
 async fn read_channel(client: &Client, server_url: &str, channel: &str) -> Result<()> {
     let messages: Option<Vec<Message>> = client
-        .get(&format!("{}/channels/{}", server_url, channel))
+        .get(format!("{}/channels/{}", server_url, channel))
         .send()
         .await?
         .json()
@@ -143,77 +476,800 @@ async fn read_channel(client: &Client, server_url: &str, channel: &str) -> Resul
     }
     Ok(())
 }
-async fn register_user(
+
+/// Parses `<query> [--channel c] [--user u] [--from h] [--to h]`.
+fn parse_search_args(args: &[String]) -> Result<SearchQuery> {
+    let mut query = SearchQuery {
+        q: args[0].clone(),
+        ..SearchQuery::default()
+    };
+    let mut flags = args[1..].iter();
+    while let Some(flag) = flags.next() {
+        let value = flags
+            .next()
+            .context(format!("Missing value for {}", flag))?;
+        match flag.as_str() {
+            "--channel" => query.channel = Some(value.clone()),
+            "--user" => query.user = Some(value.clone()),
+            "--from" => query.from = Some(value.parse().context("Failed to parse --from")?),
+            "--to" => query.to = Some(value.parse().context("Failed to parse --to")?),
+            _ => bail!("Unknown search option {}", flag),
+        }
+    }
+    Ok(query)
+}
+
+async fn search(client: &Client, server_url: &str, query: &SearchQuery) -> Result<()> {
+    let response = client
+        .get(format!("{}/search", server_url))
+        .query(query)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        println!("Search failed: {}", response.text().await?);
+        return Ok(());
+    }
+
+    let results: Vec<IndexedMessage> = response.json().await?;
+    if results.is_empty() {
+        println!("No messages found");
+        return Ok(());
+    }
+    for result in results {
+        println!(
+            "#{}:{} (height {}) {}: {}",
+            result.channel,
+            result.index,
+            result.message.height,
+            result.message.user_id,
+            result.message.contents
+        );
+    }
+    Ok(())
+}
+
+async fn fetch_user(client: &Client, server_url: &str, key_hex: &str) -> Result<Option<UserInfo>> {
+    let response = client
+        .get(format!("{}/users/{}", server_url, key_hex))
+        .send()
+        .await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        bail!("Server responded with: {}", response.text().await?);
+    }
+    Ok(Some(response.json().await?))
+}
+
+/// Prints a user's profile and their most recent messages.
+async fn show_profile(client: &Client, server_url: &str, key_hex: &str) -> Result<()> {
+    let Some(info) = fetch_user(client, server_url, key_hex).await? else {
+        println!("User {} is not registered", key_hex);
+        return Ok(());
+    };
+
+    println!("User id: {}", info.user_id);
+    if let Some(name) = &info.profile.display_name {
+        println!("Display name: {}", name);
+    }
+    if let Some(bio) = &info.profile.bio {
+        println!("Bio: {}", bio);
+    }
+    if let Some(avatar) = &info.profile.avatar {
+        println!("Avatar: {}", avatar);
+    }
+    println!("Messages: {}", info.message_count);
+
+    let messages: Vec<IndexedMessage> = client
+        .get(format!("{}/users/{}/messages", server_url, key_hex))
+        .query(&[("limit", 10)])
+        .send()
+        .await?
+        .json()
+        .await?;
+    for msg in messages {
+        println!(
+            "#{}:{} (height {}) {}",
+            msg.channel, msg.index, msg.message.height, msg.message.contents
+        );
+    }
+    Ok(())
+}
+
+/// Updates the given profile fields, keeping the others as they are. An
+/// empty value clears a field.
+async fn set_profile(
     client: &Client,
     server_url: &str,
+    submitter: &Submitter<'_>,
     key: &SigningKey,
-    id: &str,
+    args: &[String],
 ) -> Result<()> {
-    let public_key_bytes = key.clone().verifying_key().to_bytes().to_vec();
-    let tx = Transaction::Register(Register {
+    let key_hex = hex::encode(key.verifying_key().to_bytes());
+    let mut profile = fetch_user(client, server_url, &key_hex)
+        .await?
+        .map(|info| info.profile)
+        .unwrap_or_default();
+
+    let mut flags = args.iter();
+    while let Some(flag) = flags.next() {
+        let value = flags
+            .next()
+            .context(format!("Missing value for {}", flag))?;
+        let value = (!value.is_empty()).then(|| value.clone());
+        match flag.as_str() {
+            "--name" => profile.display_name = value,
+            "--bio" => profile.bio = value,
+            "--avatar" => {
+                profile.avatar = match value {
+                    Some(path) => Some(upload_file(submitter, key, &path, None).await?),
+                    None => None,
+                }
+            }
+            _ => bail!("Unknown profile option {}", flag),
+        }
+    }
+    validation::validate_profile(&profile)?;
+
+    let tx = Transaction::UpdateProfile(UpdateProfile {
         user: key.verifying_key().into(),
-        id: id.to_string(),
+        profile,
+        nonce: next_nonce(),
         signature: Signature::new(Vec::new()),
     });
+    match submitter.submit(key, tx).await {
+        Ok(()) => println!("Profile update sent successfully."),
+        Err(e) => println!("Failed to update profile. {}", e),
+    }
+    Ok(())
+}
 
-    let sig = key.sign(&bincode::serialize(&tx)?);
-    let response = client
-        .post(&format!("{}/register", server_url))
-        .json(&json!({
-            "public_key": public_key_bytes,
-            "id": id,
-            "signature": sig.to_bytes().to_vec(),
-        }))
+/// Reads a channel without trusting the node. The messages are taken from
+/// the channel proofs themselves, fetched a page at a time, and every page
+/// must be against the same root, so the messages, the proven message count
+/// and the root the witnesses are asked about all belong to the same height.
+/// Each message is also checked against its original signature. The state
+/// root is cross-checked with the nodes listed in `GRUGCHAT_WITNESS_URLS`,
+/// if any, and the channel isn't shown if one disagrees.
+async fn read_channel_verified(client: &Client, server_url: &str, channel: &str) -> Result<()> {
+    let proof = fetch_channel_proof(client, server_url, channel, 0).await?;
+    if !proof.verify() {
+        println!("Node returned an invalid proof for channel '{}'", channel);
+        return Ok(());
+    }
+    print_proof_root(proof.height, &proof.root, proof.namespace);
+    if let Some(height) = proof.height {
+        check_witnesses(client, height, &proof.root, proof.namespace).await?;
+    }
+
+    let Some(count) = proof.count else {
+        println!("Channel '{}' not found", channel);
+        return Ok(());
+    };
+    let mut messages = proof.messages;
+    while (messages.len() as u64) < count {
+        let page = fetch_channel_proof(client, server_url, channel, messages.len() as u64).await?;
+        if !page.verify() {
+            println!("Node returned an invalid proof for channel '{}'", channel);
+            return Ok(());
+        }
+        if page.root != proof.root || page.count != proof.count {
+            bail!("channel '{channel}' changed while it was being read, try again");
+        }
+        messages.extend(page.messages);
+    }
+
+    let mut problems = 0;
+    println!("Messages in channel '{}':", channel);
+    for message_proof in &messages {
+        let proven = message_proof
+            .message
+            .as_ref()
+            .expect("verified channel proofs contain every message");
+        let flag = if proven.verify_signature(channel) {
+            ""
+        } else {
+            problems += 1;
+            "[forged] "
+        };
+        println!("{}{}: {}", flag, proven.user_id, proven.contents);
+    }
+
+    if problems == 0 {
+        println!("All {} messages verified", messages.len());
+    } else {
+        println!("{} messages failed verification", problems);
+    }
+    Ok(())
+}
+
+/// Fetches the page of `channel`'s proof starting at message `from`.
+async fn fetch_channel_proof(
+    client: &Client,
+    server_url: &str,
+    channel: &str,
+    from: u64,
+) -> Result<ChannelProof> {
+    Ok(client
+        .get(format!("{}/proof/channel/{}", server_url, channel))
+        .query(&[("from", from)])
+        .send()
+        .await?
+        .json()
+        .await?)
+}
+
+/// Asks every witness node for its root at `height`, the app namespace's or
+/// that of the channel namespace `namespace`, and reports any that disagree
+/// with `root`, failing if there are any.
+async fn check_witnesses(
+    client: &Client,
+    height: u64,
+    root: &Hash,
+    namespace: Option<NamespaceId>,
+) -> Result<()> {
+    let witnesses = env::var("GRUGCHAT_WITNESS_URLS").unwrap_or_default();
+    let witnesses: Vec<&str> = witnesses.split(',').filter(|url| !url.is_empty()).collect();
+    if witnesses.is_empty() {
+        println!("Warning: no witnesses configured, the state root is trusted as served");
+        return Ok(());
+    }
+
+    let mut disagreeing = 0;
+    for witness in witnesses {
+        let url = match namespace {
+            Some(namespace) => format!("{}/root/{}/{}", witness, height, hex::encode(namespace)),
+            None => format!("{}/root/{}", witness, height),
+        };
+        let response = client.get(url).send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            println!("Witness {} does not serve this state root", witness);
+            continue;
+        }
+        let witness_root: Option<Hash> = response.json().await?;
+        match witness_root {
+            Some(witness_root) if witness_root == *root => {
+                println!("Witness {} agrees on the state root", witness)
+            }
+            Some(witness_root) => {
+                disagreeing += 1;
+                println!(
+                    "Warning: witness {} has state root {} at height {}",
+                    witness,
+                    hex::encode(witness_root),
+                    height
+                )
+            }
+            None => println!("Witness {} has not reached height {}", witness, height),
+        }
+    }
+    if disagreeing > 0 {
+        bail!("{disagreeing} witnesses disagree on the state root");
+    }
+    Ok(())
+}
+
+async fn verify_user(client: &Client, server_url: &str, public_key_hex: &str) -> Result<()> {
+    let proof: UserProof = client
+        .get(format!("{}/proof/user/{}", server_url, public_key_hex))
         .send()
+        .await?
+        .json()
         .await?;
-    if response.status().is_success() {
-        println!("User registration request sent successfully.");
-    } else {
-        let error_body = &response.text().await?;
-        println!(
-            "Failed to register user. Server responded with: {}",
-            error_body
-        );
+
+    if !proof.verify() {
+        println!("Invalid proof for user {}", public_key_hex);
+        return Ok(());
+    }
+    match proof.user_id {
+        Some(id) => println!("User {} is registered as '{}'", public_key_hex, id),
+        None => println!("User {} is not registered", public_key_hex),
     }
+    print_proof_root(proof.height, &proof.root, None);
     Ok(())
 }
 
-async fn send_message(
+async fn verify_message(
     client: &Client,
     server_url: &str,
-    key: &SigningKey,
     channel: &str,
-    message: &str,
+    index: u64,
 ) -> Result<()> {
-    let public_key_bytes = key.clone().verifying_key().to_bytes().to_vec();
+    let proof: MessageProof = client
+        .get(format!(
+            "{}/proof/message/{}:{}",
+            server_url, channel, index
+        ))
+        .send()
+        .await?
+        .json()
+        .await?;
 
-    let tx = Transaction::SendMessage(SendMessage {
-        user: key.clone().verifying_key().into(),
-        channel: channel.to_string(),
-        contents: message.to_string(),
-        signature: Signature::new(Vec::new()),
-    });
+    if !proof.verify() {
+        println!("Invalid proof for message {} in '{}'", index, channel);
+        return Ok(());
+    }
+    match proof.message {
+        Some(msg) => println!("{}: {}", msg.user_id, msg.contents),
+        None => println!("Message {} does not exist in '{}'", index, channel),
+    }
+    print_proof_root(proof.height, &proof.root, proof.namespace);
+    Ok(())
+}
 
-    let sig = key.clone().sign(&bincode::serialize(&tx)?);
+/// Fetches the attachment of a proven message and checks it against the
+/// hash and size the sender signed before writing it to `path`.
+async fn download_attachment(
+    client: &Client,
+    server_url: &str,
+    channel: &str,
+    index: u64,
+    path: &str,
+) -> Result<()> {
+    let proof: MessageProof = client
+        .get(format!(
+            "{}/proof/message/{}:{}",
+            server_url, channel, index
+        ))
+        .send()
+        .await?
+        .json()
+        .await?;
+    if !proof.verify() {
+        bail!("Invalid proof for message {} in '{}'", index, channel);
+    }
+    let attachment = match proof.message.map(|msg| msg.contents) {
+        Some(Content::Attachment(attachment)) => attachment,
+        Some(_) => bail!("Message {} in '{}' has no attachment", index, channel),
+        None => bail!("Message {} does not exist in '{}'", index, channel),
+    };
 
     let response = client
-        .post(&format!("{}/send", server_url))
-        .json(&json!({
-            "user": public_key_bytes,
-            "channel": channel,
-            "contents": message,
-            "signature": sig.to_bytes().to_vec(),
-        }))
+        .get(format!(
+            "{}/attachments/{}/{}",
+            server_url,
+            attachment.height,
+            hex::encode(attachment.commitment)
+        ))
         .send()
         .await?;
+    if !response.status().is_success() {
+        bail!("Server responded with: {}", response.text().await?);
+    }
+    let data = response.bytes().await?;
+    if data.len() as u64 != attachment.size || merkle::hash(&data) != attachment.hash {
+        bail!(
+            "Attachment served for message {} does not match its hash",
+            index
+        );
+    }
 
-    if response.status().is_success() {
-        println!("Message sent successfully.");
-    } else {
-        println!(
-            "Failed to send message. Server responded with: {}",
-            response.status()
+    std::fs::write(path, &data).context(format!("Failed to write {}", path))?;
+    println!(
+        "Saved {} ({}, {} bytes) to {}",
+        attachment.name, attachment.mime_type, attachment.size, path
+    );
+    Ok(())
+}
+
+/// Guesses a MIME type from a file extension for the common cases.
+fn guess_mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("txt") => "text/plain",
+        Some("md") => "text/markdown",
+        Some("html" | "htm") => "text/html",
+        Some("json") => "application/json",
+        Some("pdf") => "application/pdf",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("mp3") => "audio/mpeg",
+        Some("mp4") => "video/mp4",
+        Some("zip") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Posts the file at `path` as a blob, with an upload signed by `key`, and
+/// describes it as an attachment.
+async fn upload_file(
+    submitter: &Submitter<'_>,
+    key: &SigningKey,
+    path: &str,
+    mime_type: Option<String>,
+) -> Result<Attachment> {
+    let path = Path::new(path);
+    let data = std::fs::read(path).context(format!("Failed to read {}", path.display()))?;
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("File name must be valid UTF-8")?
+        .to_string();
+    let mime_type = mime_type.unwrap_or_else(|| guess_mime_type(path).to_string());
+    let size = data.len() as u64;
+    let hash = merkle::hash(&data);
+
+    let (height, commitment) = submitter.upload(key, data).await?;
+    let attachment = Attachment {
+        name,
+        mime_type,
+        size,
+        hash,
+        height,
+        commitment,
+    };
+    validation::validate_attachment(&attachment)?;
+    println!("Uploaded {} at DA height {}", attachment.name, height);
+    Ok(attachment)
+}
+
+fn print_proof_root(height: Option<u64>, root: &Hash, namespace: Option<NamespaceId>) {
+    if let Some(namespace) = namespace {
+        println!("Channel is in namespace {}", hex::encode(namespace));
+    }
+    match height {
+        Some(height) => println!(
+            "Proven against root {} at height {}",
+            hex::encode(root),
+            height
+        ),
+        None => println!(
+            "Proven against root {} of the empty state",
+            hex::encode(root)
+        ),
+    }
+}
+
+/// Where the CLI sends signed transactions. By default they go to a full
+/// node, but if `GRUGCHAT_DIRECT_NAMESPACE` is set they are posted straight
+/// to the DA layer so that no full node can censor them.
+enum Submitter<'a> {
+    FullNode {
+        client: &'a Client,
+        server_url: &'a str,
+    },
+    /// Posts to the DA layer itself, still asking the full node where
+    /// channels are and whether messages were applied.
+    DirectToDa {
+        client: &'a Client,
+        server_url: &'a str,
+        da_url: String,
+        auth_token: Option<String>,
+        namespace: Namespace,
+    },
+}
+
+impl<'a> Submitter<'a> {
+    fn from_env(client: &'a Client, server_url: &'a str) -> Result<Self> {
+        let Ok(namespace_hex) = env::var("GRUGCHAT_DIRECT_NAMESPACE") else {
+            return Ok(Submitter::FullNode { client, server_url });
+        };
+
+        Ok(Submitter::DirectToDa {
+            client,
+            server_url,
+            da_url: env::var("GRUGCHAT_DA_URL")
+                .unwrap_or_else(|_| "ws://localhost:26658".to_string()),
+            auth_token: env::var("GRUGCHAT_DA_AUTH_TOKEN").ok(),
+            namespace: parse_namespace(&namespace_hex)?,
+        })
+    }
+
+    /// Signs `tx` and submits it, either to the full node's generic `/tx`
+    /// route or as a single-transaction batch blob. Posted directly, a
+    /// message goes to its channel's namespace in the full node's directory,
+    /// and is checked to have been applied there once the node reaches the
+    /// height it was included at; anything else goes to the app namespace.
+    async fn submit(&self, key: &SigningKey, tx: Transaction) -> Result<()> {
+        let signed = tx.sign(key)?;
+        match self {
+            Submitter::FullNode { client, server_url } => {
+                let tx_bytes = bincode::serialize(&signed)?;
+                // Nodes may require a proof-of-work stamp on messages from
+                // IPs they don't trust.
+                let pow_nonce = match (&signed, env::var("GRUGCHAT_POW_DIFFICULTY")) {
+                    (Transaction::SendMessage(_), Ok(difficulty)) => Some(mint_stamp(
+                        &tx_bytes,
+                        difficulty
+                            .parse()
+                            .context("Failed to parse GRUGCHAT_POW_DIFFICULTY")?,
+                    )?),
+                    _ => None,
+                };
+                let response = client
+                    .post(format!("{}/tx", server_url))
+                    .json(&json!({ "tx": hex::encode(&tx_bytes), "pow_nonce": pow_nonce }))
+                    .send()
+                    .await?;
+                if !response.status().is_success() {
+                    return Err(anyhow!("Server responded with: {}", response.text().await?));
+                }
+            }
+            Submitter::DirectToDa {
+                client,
+                server_url,
+                da_url,
+                auth_token,
+                namespace,
+            } => {
+                let message = match &signed {
+                    Transaction::SendMessage(msg) => Some(msg.clone()),
+                    _ => None,
+                };
+                let namespace = match &message {
+                    Some(msg) => channel_namespace(client, server_url, &msg.channel)
+                        .await?
+                        .map_or(*namespace, Namespace::const_v0),
+                    None => *namespace,
+                };
+                let da = CelestiaDa::connect(da_url, auth_token.as_deref()).await?;
+                let blob = Batch::new(vec![signed]).to_blob(namespace)?;
+                let height = da.submit(&[blob]).await?.height;
+                println!("Transaction included at DA height {}", height);
+                if let Some(msg) = message {
+                    check_message_applied(client, server_url, &msg, height).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Posts `data` as a blob in the attachment namespace, either through
+    /// the full node, which needs the upload signed by a registered `key`
+    /// and stamped, or directly. Returns the height and commitment it got.
+    async fn upload(&self, key: &SigningKey, data: Vec<u8>) -> Result<(u64, Hash)> {
+        match self {
+            Submitter::FullNode { client, server_url } => {
+                let payload = attachment_payload(&data);
+                let difficulty = match env::var("GRUGCHAT_ATTACHMENT_POW_DIFFICULTY") {
+                    Ok(difficulty) => difficulty
+                        .parse()
+                        .context("Failed to parse GRUGCHAT_ATTACHMENT_POW_DIFFICULTY")?,
+                    Err(_) => RateLimitConfig::default().attachment_pow_difficulty,
+                };
+                let uploader = AttachmentUploader {
+                    user: key.verifying_key().into(),
+                    signature: key.sign(&payload).into(),
+                    pow_nonce: Some(mint_stamp(&payload, difficulty)?),
+                };
+                let mut request = client.post(format!("{}/attachments", server_url));
+                for (name, value) in uploader.headers() {
+                    request = request.header(name, value);
+                }
+                let response = request.body(data).send().await?;
+                if !response.status().is_success() {
+                    return Err(anyhow!("Server responded with: {}", response.text().await?));
+                }
+                let upload: AttachmentUpload = response.json().await?;
+                Ok((upload.height, upload.commitment))
+            }
+            Submitter::DirectToDa {
+                da_url,
+                auth_token,
+                namespace,
+                ..
+            } => {
+                let da = CelestiaDa::connect(da_url, auth_token.as_deref()).await?;
+                let blob = Blob::new(attachment_namespace(*namespace), data)?;
+                let commitment = blob.commitment.0;
+                let height = da.submit(&[blob]).await?.height;
+                Ok((height, commitment))
+            }
+        }
+    }
+}
+
+/// The v0 id of the namespace the full node's directory puts `channel` in,
+/// or `None` if it is in the app namespace.
+async fn channel_namespace(
+    client: &Client,
+    server_url: &str,
+    channel: &str,
+) -> Result<Option<NamespaceId>> {
+    let response = client
+        .get(format!("{}/directory", server_url))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        bail!(
+            "Failed to fetch the channel directory: {}",
+            response.text().await?
         );
     }
+    let directory: Vec<(String, ChannelAssignment)> = response.json().await?;
+    Ok(directory
+        .into_iter()
+        .find(|(name, _)| name == channel)
+        .map(|(_, assignment)| assignment.namespace))
+}
+
+/// Waits for the full node to apply `height` and fails if `msg`, included
+/// there, isn't in its channel, as nodes skip rejected transactions rather
+/// than failing the blob they are in.
+async fn check_message_applied(
+    client: &Client,
+    server_url: &str,
+    msg: &SendMessage,
+    height: u64,
+) -> Result<()> {
+    const ATTEMPTS: u32 = 60;
+    for attempt in 1.. {
+        let status: NodeStatus = client
+            .get(format!("{}/status", server_url))
+            .send()
+            .await?
+            .json()
+            .await?;
+        if status.last_height.is_some_and(|last| last >= height) {
+            break;
+        }
+        if attempt == ATTEMPTS {
+            bail!(
+                "{} did not reach DA height {} in time to check the message was applied",
+                server_url,
+                height
+            );
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+
+    let messages: Option<Vec<Message>> = client
+        .get(format!("{}/channels/{}", server_url, msg.channel))
+        .send()
+        .await?
+        .json()
+        .await?;
+    if !messages
+        .unwrap_or_default()
+        .iter()
+        .any(|applied| applied.signature == msg.signature)
+    {
+        bail!(
+            "The message was included at DA height {} but rejected by {}, see its logs for why",
+            height,
+            server_url
+        );
+    }
+    Ok(())
+}
+
+/// Transactions other than `Register` need a nonce greater than the
+/// signer's last; the time in milliseconds keeps growing without having to
+/// look it up.
+fn next_nonce() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Messages are signed with the session key in `GRUGCHAT_SESSION_KEY`, if
+/// set, and with the account key from the keychain otherwise.
+fn message_signing_key() -> Result<SigningKey> {
+    match env::var("GRUGCHAT_SESSION_KEY") {
+        Ok(secret_hex) => {
+            let secret: [u8; 32] = hex::decode(secret_hex)
+                .context("Failed to decode GRUGCHAT_SESSION_KEY")?
+                .try_into()
+                .map_err(|_| anyhow!("GRUGCHAT_SESSION_KEY must be 32 bytes"))?;
+            Ok(SigningKey::from_bytes(&secret))
+        }
+        Err(_) => KeyChain.get_signing_key().map_err(|e| anyhow!(e)),
+    }
+}
+
+/// Authorizes a freshly generated session key and prints its secret, to be
+/// handed to the client that will use it via `GRUGCHAT_SESSION_KEY`.
+async fn authorize_session(
+    submitter: &Submitter<'_>,
+    key: &SigningKey,
+    scope: SessionScope,
+    expires: u64,
+) -> Result<()> {
+    let session_key = keystore_rs::create_signing_key();
+    let tx = Transaction::AuthorizeSessionKey(AuthorizeSessionKey {
+        user: key.verifying_key().into(),
+        session_key: session_key.verifying_key().into(),
+        scope,
+        expires,
+        nonce: next_nonce(),
+        signature: Signature::new(Vec::new()),
+    });
+    match submitter.submit(key, tx).await {
+        Ok(()) => {
+            println!(
+                "Session key authorized until height {}: {}",
+                expires,
+                hex::encode(session_key.verifying_key().to_bytes())
+            );
+            println!(
+                "Session secret key: {}",
+                hex::encode(session_key.to_bytes())
+            );
+        }
+        Err(e) => println!("Failed to authorize session key. {}", e),
+    }
+    Ok(())
+}
+
+fn parse_public_key(key_hex: &str) -> Result<PublicKey> {
+    let key = PublicKey::new(hex::decode(key_hex).context("Failed to decode public key hex")?);
+    if !key.is_valid() {
+        bail!("{} is not a valid public key", key_hex);
+    }
+    Ok(key)
+}
+
+/// Moves the account to a freshly generated key and stores that key in
+/// the keychain in place of the current one.
+async fn rotate_key(submitter: &Submitter<'_>, key: &SigningKey) -> Result<()> {
+    let new_key = keystore_rs::create_signing_key();
+    let tx = Transaction::RotateKey(RotateKey {
+        user: key.verifying_key().into(),
+        new_key: new_key.verifying_key().into(),
+        nonce: next_nonce(),
+        signature: Signature::new(Vec::new()),
+    });
+    if let Err(e) = submitter.submit(key, tx).await {
+        println!("Failed to rotate key. {}", e);
+        return Ok(());
+    }
+
+    // The rotation might still be rejected when it is applied, so the old
+    // key is shown until the user has confirmed the move.
+    println!(
+        "Previous secret key (keep it until the rotation is applied): {}",
+        hex::encode(key.to_bytes())
+    );
+    if let Err(e) = KeyChain.add_signing_key(&new_key) {
+        println!("Error: failed to store the new key: {}", e);
+        println!("New secret key: {}", hex::encode(new_key.to_bytes()));
+        return Ok(());
+    }
+    println!(
+        "Key rotation sent. New public key: {}",
+        hex::encode(new_key.verifying_key().to_bytes())
+    );
+    Ok(())
+}
+
+async fn register_user(submitter: &Submitter<'_>, key: &SigningKey, id: &str) -> Result<()> {
+    let tx = Transaction::Register(Register {
+        user: key.verifying_key().into(),
+        id: id.to_string(),
+        signature: Signature::new(Vec::new()),
+    });
+
+    match submitter.submit(key, tx).await {
+        Ok(()) => println!("User registration request sent successfully."),
+        Err(e) => println!("Failed to register user. {}", e),
+    }
+    Ok(())
+}
+
+async fn send_message(
+    submitter: &Submitter<'_>,
+    key: &SigningKey,
+    channel: &str,
+    contents: Content,
+) -> Result<()> {
+    let tx = Transaction::SendMessage(SendMessage {
+        user: key.verifying_key().into(),
+        channel: channel.to_string(),
+        contents,
+        nonce: next_nonce(),
+        signature: Signature::new(Vec::new()),
+    });
+
+    match submitter.submit(key, tx).await {
+        Ok(()) => println!("Message sent successfully."),
+        Err(e) => println!("Failed to send message. {}", e),
+    }
     Ok(())
 }
@@ -0,0 +1,446 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub type Hash = [u8; 32];
+
+/// Hash of an empty subtree at any depth, which is what keeps the tree sparse.
+pub const EMPTY: Hash = [0; 32];
+
+const DEPTH: u16 = 256;
+
+pub fn hash(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+fn leaf_hash(key: &Hash, value: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0]);
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    if *left == EMPTY && *right == EMPTY {
+        return EMPTY;
+    }
+    let mut hasher = Sha256::new();
+    hasher.update([1]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Whether `key` takes the right branch below a node at `depth`.
+fn bit(key: &Hash, depth: u16) -> bool {
+    key[usize::from(depth / 8)] & (0x80 >> (depth % 8)) != 0
+}
+
+/// The path from the root to the node at `depth` above `key`.
+fn prefix(key: &Hash, depth: u16) -> Hash {
+    let mut prefix = EMPTY;
+    let full_bytes = usize::from(depth / 8);
+    prefix[..full_bytes].copy_from_slice(&key[..full_bytes]);
+    if !depth.is_multiple_of(8) {
+        prefix[full_bytes] = key[full_bytes] & (0xff << (8 - depth % 8));
+    }
+    prefix
+}
+
+/// How many leading bits `a` and `b` share.
+fn common_prefix_len(a: &Hash, b: &Hash) -> u16 {
+    (0..DEPTH)
+        .find(|&depth| bit(a, depth) != bit(b, depth))
+        .unwrap_or(DEPTH)
+}
+
+/// A subtree with at least one leaf. A leaf sits at the shortest prefix of
+/// its key that no other key shares, and a subtree with a single leaf hashes
+/// to that leaf. Only the nodes where two paths split are stored, so a tree
+/// holds one leaf and at most one internal node per key.
+#[derive(Clone)]
+enum Node {
+    Leaf {
+        key: Hash,
+        value: Hash,
+        hash: Hash,
+    },
+    Internal {
+        /// The depth of the split: the children are at `depth + 1`.
+        depth: u16,
+        /// Any key below, for the path down to the split.
+        key: Hash,
+        left: Box<Node>,
+        right: Box<Node>,
+        hash: Hash,
+    },
+}
+
+impl Node {
+    fn leaf(key: Hash, value: Hash) -> Self {
+        Node::Leaf {
+            key,
+            value,
+            hash: leaf_hash(&key, &value),
+        }
+    }
+
+    fn internal(depth: u16, left: Node, right: Node) -> Self {
+        let key = *left.key();
+        let hash = node_hash(&left.hash_at(depth + 1), &right.hash_at(depth + 1));
+        Node::Internal {
+            depth,
+            key,
+            left: Box::new(left),
+            right: Box::new(right),
+            hash,
+        }
+    }
+
+    /// Puts `a` and `b`, whose paths first differ at `depth`, under a node
+    /// at that depth.
+    fn split(depth: u16, a: Node, b: Node) -> Self {
+        if bit(a.key(), depth) {
+            Node::internal(depth, b, a)
+        } else {
+            Node::internal(depth, a, b)
+        }
+    }
+
+    fn key(&self) -> &Hash {
+        match self {
+            Node::Leaf { key, .. } | Node::Internal { key, .. } => key,
+        }
+    }
+
+    /// The hash of the subtree as seen from `depth`, at or above where it is
+    /// stored. Levels in between have an empty sibling; a leaf needs none,
+    /// as it moves up to its shortest unique prefix.
+    fn hash_at(&self, depth: u16) -> Hash {
+        match self {
+            Node::Leaf { hash, .. } => *hash,
+            Node::Internal {
+                depth: split,
+                key,
+                hash,
+                ..
+            } => (depth..*split).rev().fold(*hash, |current, level| {
+                if bit(key, level) {
+                    node_hash(&EMPTY, &current)
+                } else {
+                    node_hash(&current, &EMPTY)
+                }
+            }),
+        }
+    }
+
+    fn insert(self, key: Hash, value: Hash) -> Self {
+        let shared = common_prefix_len(self.key(), &key);
+        match self {
+            Node::Leaf { .. } if shared == DEPTH => Node::leaf(key, value),
+            Node::Internal {
+                depth, left, right, ..
+            } if shared >= depth => {
+                if bit(&key, depth) {
+                    Node::internal(depth, *left, right.insert(key, value))
+                } else {
+                    Node::internal(depth, left.insert(key, value), *right)
+                }
+            }
+            // `key` leaves the path to this subtree before it splits.
+            node => Node::split(shared, node, Node::leaf(key, value)),
+        }
+    }
+
+    /// The subtree without `key`, or `None` if that leaves it empty.
+    fn remove(self, key: &Hash) -> Option<Self> {
+        match self {
+            Node::Leaf { key: existing, .. } if existing == *key => None,
+            Node::Internal {
+                depth,
+                key: existing,
+                left,
+                right,
+                ..
+            } if common_prefix_len(&existing, key) >= depth => {
+                let (left, right) = if bit(key, depth) {
+                    (Some(*left), right.remove(key))
+                } else {
+                    (left.remove(key), Some(*right))
+                };
+                Some(match (left, right) {
+                    (Some(left), Some(right)) => Node::internal(depth, left, right),
+                    (Some(only), None) | (None, Some(only)) => only,
+                    (None, None) => unreachable!("both children of a split are non-empty"),
+                })
+            }
+            node => Some(node),
+        }
+    }
+}
+
+/// A sparse Merkle tree over 256-bit keys. Empty subtrees hash to `EMPTY`
+/// and aren't stored, and neither are the levels above a lone leaf (see
+/// `Node`), so its size is linear in the number of keys.
+#[derive(Clone, Default)]
+pub struct SparseMerkleTree {
+    root: Option<Node>,
+}
+
+impl SparseMerkleTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn root(&self) -> Hash {
+        self.root.as_ref().map_or(EMPTY, |root| root.hash_at(0))
+    }
+
+    /// Sets the value committed to under `key`, or removes it if `value` is
+    /// `None`.
+    pub fn update(&mut self, key: &Hash, value: Option<&Hash>) {
+        self.root = match (self.root.take(), value) {
+            (Some(root), Some(value)) => Some(root.insert(*key, *value)),
+            (None, Some(value)) => Some(Node::leaf(*key, *value)),
+            (Some(root), None) => root.remove(key),
+            (None, None) => None,
+        };
+    }
+
+    pub fn prove(&self, key: &Hash) -> MerkleProof {
+        let mut siblings = Vec::new();
+        let mut leaf = None;
+        let mut node = self.root.as_ref();
+        while let Some(current) = node {
+            node = match current {
+                Node::Leaf {
+                    key: existing,
+                    value,
+                    ..
+                } => {
+                    if existing != key {
+                        leaf = Some((*existing, *value));
+                    }
+                    None
+                }
+                Node::Internal {
+                    depth,
+                    key: existing,
+                    left,
+                    right,
+                    ..
+                } => {
+                    let shared = common_prefix_len(existing, key);
+                    if shared < *depth {
+                        // `key`'s path leaves this subtree's before the
+                        // split, into an empty one.
+                        siblings.push((shared + 1, current.hash_at(shared + 1)));
+                        None
+                    } else {
+                        let (next, sibling) = if bit(key, *depth) {
+                            (right, left)
+                        } else {
+                            (left, right)
+                        };
+                        siblings.push((depth + 1, sibling.hash_at(depth + 1)));
+                        Some(&**next)
+                    }
+                }
+            };
+        }
+        siblings.reverse();
+        MerkleProof { siblings, leaf }
+    }
+
+    /// How many nodes the tree stores.
+    #[cfg(test)]
+    fn node_count(&self) -> usize {
+        fn count(node: &Node) -> usize {
+            match node {
+                Node::Leaf { .. } => 1,
+                Node::Internal { left, right, .. } => 1 + count(left) + count(right),
+            }
+        }
+        self.root.as_ref().map_or(0, count)
+    }
+}
+
+/// Proof of the value (or absence) of a key in a `SparseMerkleTree`. Only
+/// the non-empty siblings along the path are included, keyed by depth,
+/// deepest first; the deepest is where the key's leaf sits. A key can be
+/// absent because that position is empty, or because another key's leaf is
+/// there, which is then included.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct MerkleProof {
+    siblings: Vec<(u16, Hash)>,
+    leaf: Option<(Hash, Hash)>,
+}
+
+impl MerkleProof {
+    /// Checks that `key` maps to `value` (or is absent, if `value` is `None`)
+    /// in the tree with the given `root`.
+    pub fn verify(&self, root: &Hash, key: &Hash, value: Option<&Hash>) -> bool {
+        let leaf_depth = self.siblings.first().map_or(0, |(depth, _)| *depth);
+        let mut current = match (value, &self.leaf) {
+            (Some(value), None) => leaf_hash(key, value),
+            (None, None) => EMPTY,
+            (None, Some((other, value)))
+                if other != key && prefix(other, leaf_depth) == prefix(key, leaf_depth) =>
+            {
+                leaf_hash(other, value)
+            }
+            _ => return false,
+        };
+
+        let mut siblings = self.siblings.iter().peekable();
+        for depth in (1..=leaf_depth).rev() {
+            let sibling = match siblings.next_if(|(d, _)| *d == depth) {
+                Some((_, sibling)) => *sibling,
+                None => EMPTY,
+            };
+            current = if bit(key, depth - 1) {
+                node_hash(&sibling, &current)
+            } else {
+                node_hash(&current, &sibling)
+            };
+        }
+
+        siblings.next().is_none() && current == *root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(n: u8) -> Hash {
+        hash(&[n])
+    }
+
+    fn value(n: u8) -> Hash {
+        hash(&[n, n])
+    }
+
+    fn sample_tree() -> SparseMerkleTree {
+        let mut tree = SparseMerkleTree::new();
+        for n in 0..16 {
+            tree.update(&key(n), Some(&value(n)));
+        }
+        tree
+    }
+
+    #[test]
+    fn empty_tree_has_empty_root() {
+        assert_eq!(SparseMerkleTree::new().root(), EMPTY);
+    }
+
+    #[test]
+    fn proves_inclusion() {
+        let tree = sample_tree();
+        let root = tree.root();
+        for n in 0..16 {
+            assert!(tree.prove(&key(n)).verify(&root, &key(n), Some(&value(n))));
+        }
+    }
+
+    #[test]
+    fn proves_absence() {
+        let tree = sample_tree();
+        let root = tree.root();
+        let proof = tree.prove(&key(100));
+        assert!(proof.verify(&root, &key(100), None));
+        assert!(!proof.verify(&root, &key(100), Some(&value(100))));
+    }
+
+    #[test]
+    fn removing_a_key_restores_the_previous_root() {
+        let mut tree = sample_tree();
+        let root = tree.root();
+        tree.update(&key(100), Some(&value(100)));
+        assert_ne!(tree.root(), root);
+        tree.update(&key(100), None);
+        assert_eq!(tree.root(), root);
+        assert!(tree.prove(&key(100)).verify(&root, &key(100), None));
+    }
+
+    #[test]
+    fn absence_proofs_only_accept_a_leaf_on_the_keys_path() {
+        let tree = sample_tree();
+        let root = tree.root();
+        for n in 100..132 {
+            let proof = tree.prove(&key(n));
+            assert!(proof.verify(&root, &key(n), None));
+            if let Some((other, value)) = proof.leaf {
+                let mut moved = proof.clone();
+                moved.leaf = Some((key(n), value));
+                assert!(!moved.verify(&root, &key(n), None));
+                assert!(!tree.prove(&other).verify(&root, &key(n), None));
+            }
+        }
+    }
+
+    #[test]
+    fn stores_at_most_two_nodes_per_key() {
+        let mut tree = SparseMerkleTree::new();
+        for n in 0..=255 {
+            tree.update(&key(n), Some(&value(n)));
+            assert!(tree.node_count() < 2 * (usize::from(n) + 1));
+        }
+        for n in (0..=255).step_by(2) {
+            tree.update(&key(n), None);
+        }
+        assert_eq!(tree.node_count(), 2 * 128 - 1);
+
+        let mut remaining = SparseMerkleTree::new();
+        for n in (1..=255).step_by(2) {
+            remaining.update(&key(n), Some(&value(n)));
+        }
+        assert_eq!(tree.root(), remaining.root());
+    }
+
+    #[test]
+    fn roots_do_not_depend_on_insertion_order() {
+        let mut tree = SparseMerkleTree::new();
+        for n in (0..16).rev() {
+            tree.update(&key(n), Some(&value(n)));
+        }
+        assert_eq!(tree.root(), sample_tree().root());
+    }
+
+    #[test]
+    fn rejects_tampered_proofs() {
+        let tree = sample_tree();
+        let root = tree.root();
+        let proof = tree.prove(&key(3));
+        assert!(proof.verify(&root, &key(3), Some(&value(3))));
+
+        // Wrong value, key or root.
+        assert!(!proof.verify(&root, &key(3), Some(&value(4))));
+        assert!(!proof.verify(&root, &key(3), None));
+        assert!(!proof.verify(&root, &key(4), Some(&value(3))));
+        assert!(!proof.verify(&value(0), &key(3), Some(&value(3))));
+
+        // A flipped bit in a sibling.
+        let mut tampered = proof.clone();
+        tampered.siblings[0].1[0] ^= 1;
+        assert!(!tampered.verify(&root, &key(3), Some(&value(3))));
+
+        // A sibling moved to another depth.
+        let mut tampered = proof.clone();
+        tampered.siblings[0].0 -= 1;
+        assert!(!tampered.verify(&root, &key(3), Some(&value(3))));
+
+        // A sibling dropped, or one added.
+        let mut tampered = proof.clone();
+        tampered.siblings.pop();
+        assert!(!tampered.verify(&root, &key(3), Some(&value(3))));
+        let mut tampered = proof.clone();
+        tampered.siblings.push((1, value(0)));
+        assert!(!tampered.verify(&root, &key(3), Some(&value(3))));
+
+        // Siblings out of order are not skipped over.
+        let mut tampered = proof;
+        tampered.siblings.reverse();
+        assert!(!tampered.verify(&root, &key(3), Some(&value(3))));
+    }
+}
@@ -0,0 +1,349 @@
+use crate::state::State;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Bucket bounds, in seconds, for request and submission latencies.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+const BATCH_BYTES_BUCKETS: &[f64] = &[
+    256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 2097152.0,
+];
+const BATCH_TXS_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters keyed by the values of a fixed set of labels.
+pub struct CounterVec {
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterVec {
+    fn new(labels: &'static [&'static str]) -> Self {
+        CounterVec {
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, values: &[&str]) {
+        self.add(values, 1);
+    }
+
+    pub fn add(&self, values: &[&str], n: u64) {
+        let key = values.iter().map(|v| v.to_string()).collect();
+        *self.values.lock().unwrap().entry(key).or_default() += n;
+    }
+}
+
+#[derive(Clone)]
+struct Buckets {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Buckets {
+    fn new(bounds: &'static [f64]) -> Self {
+        Buckets {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(&mut self.counts) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Histograms keyed by the values of a fixed set of labels, which may be
+/// empty for a plain histogram.
+pub struct HistogramVec {
+    labels: &'static [&'static str],
+    bounds: &'static [f64],
+    values: Mutex<BTreeMap<Vec<String>, Buckets>>,
+}
+
+impl HistogramVec {
+    fn new(labels: &'static [&'static str], bounds: &'static [f64]) -> Self {
+        HistogramVec {
+            labels,
+            bounds,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, values: &[&str], value: f64) {
+        let key = values.iter().map(|v| v.to_string()).collect();
+        self.values
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| Buckets::new(self.bounds))
+            .observe(value);
+    }
+
+    pub fn observe_duration(&self, values: &[&str], duration: Duration) {
+        self.observe(values, duration.as_secs_f64());
+    }
+}
+
+/// Formats `{a="x",b="y"}`, with `extra` appended, or nothing if there are
+/// no labels at all.
+fn format_labels(names: &[&str], values: &[String], extra: Option<(&str, &str)>) -> String {
+    let mut pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+        .collect();
+    if let Some((name, value)) = extra {
+        pairs.push(format!("{name}=\"{value}\""));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders metrics in the Prometheus text exposition format.
+struct Encoder(String);
+
+impl Encoder {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {name} {help}");
+        let _ = writeln!(self.0, "# TYPE {name} {kind}");
+    }
+
+    fn counter(&mut self, name: &str, help: &str, counter: &Counter) {
+        self.header(name, "counter", help);
+        let _ = writeln!(self.0, "{name} {}", counter.get());
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, "gauge", help);
+        let _ = writeln!(self.0, "{name} {value}");
+    }
+
+    fn counter_vec(&mut self, name: &str, help: &str, counters: &CounterVec) {
+        self.header(name, "counter", help);
+        for (values, count) in counters.values.lock().unwrap().iter() {
+            let labels = format_labels(counters.labels, values, None);
+            let _ = writeln!(self.0, "{name}{labels} {count}");
+        }
+    }
+
+    fn histogram_vec(&mut self, name: &str, help: &str, histograms: &HistogramVec) {
+        self.header(name, "histogram", help);
+        for (values, buckets) in histograms.values.lock().unwrap().iter() {
+            for (bound, count) in buckets.bounds.iter().zip(&buckets.counts) {
+                let labels =
+                    format_labels(histograms.labels, values, Some(("le", &bound.to_string())));
+                let _ = writeln!(self.0, "{name}_bucket{labels} {count}");
+            }
+            let labels = format_labels(histograms.labels, values, Some(("le", "+Inf")));
+            let _ = writeln!(self.0, "{name}_bucket{labels} {}", buckets.count);
+            let labels = format_labels(histograms.labels, values, None);
+            let _ = writeln!(self.0, "{name}_sum{labels} {}", buckets.sum);
+            let _ = writeln!(self.0, "{name}_count{labels} {}", buckets.count);
+        }
+    }
+}
+
+/// Everything a node or gateway reports on `/metrics`. Counts that can be
+/// read off the state, like users and channels, are taken from it when
+/// rendering.
+pub struct Metrics {
+    pub synced_height: Gauge,
+    pub network_height: Gauge,
+    pub sync_restarts: Counter,
+
+    pub txs_queued: Counter,
+    /// Transactions a read-only node passed on to its upstream.
+    pub txs_forwarded: Counter,
+    pub txs_posted: Counter,
+    pub txs_applied: Counter,
+    /// Labelled by `stage` (`api` or `apply`) and `reason`.
+    pub txs_rejected: CounterVec,
+
+    pub batch_bytes: HistogramVec,
+    pub batch_txs: HistogramVec,
+
+    /// Labelled by what was submitted: `batch` or `attachment`.
+    pub submit_seconds: HistogramVec,
+    pub submit_failures: CounterVec,
+    /// In utia.
+    pub submit_fees: CounterVec,
+
+    /// Labelled by matched route and response status.
+    pub http_request_seconds: HistogramVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            synced_height: Gauge::default(),
+            network_height: Gauge::default(),
+            sync_restarts: Counter::default(),
+            txs_queued: Counter::default(),
+            txs_forwarded: Counter::default(),
+            txs_posted: Counter::default(),
+            txs_applied: Counter::default(),
+            txs_rejected: CounterVec::new(&["stage", "reason"]),
+            batch_bytes: HistogramVec::new(&[], BATCH_BYTES_BUCKETS),
+            batch_txs: HistogramVec::new(&[], BATCH_TXS_BUCKETS),
+            submit_seconds: HistogramVec::new(&["kind"], LATENCY_BUCKETS),
+            submit_failures: CounterVec::new(&["kind"]),
+            submit_fees: CounterVec::new(&["kind"]),
+            http_request_seconds: HistogramVec::new(&["route", "status"], LATENCY_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Renders every metric, plus counts from `state` on nodes that keep
+    /// one.
+    pub fn render(&self, state: Option<&State>) -> String {
+        let mut out = Encoder(String::new());
+        out.gauge(
+            "grugchat_synced_height",
+            "Last DA height applied to the state.",
+            self.synced_height.get(),
+        );
+        out.gauge(
+            "grugchat_network_height",
+            "Latest DA height seen from the network.",
+            self.network_height.get(),
+        );
+        out.counter(
+            "grugchat_sync_restarts_total",
+            "Times sync failed and was restarted.",
+            &self.sync_restarts,
+        );
+        out.counter(
+            "grugchat_txs_queued_total",
+            "Transactions accepted into the pending batch.",
+            &self.txs_queued,
+        );
+        out.counter(
+            "grugchat_txs_forwarded_total",
+            "Transactions forwarded to the upstream node.",
+            &self.txs_forwarded,
+        );
+        out.counter(
+            "grugchat_txs_posted_total",
+            "Transactions posted to the DA layer in batches.",
+            &self.txs_posted,
+        );
+        out.counter(
+            "grugchat_txs_applied_total",
+            "Transactions applied to the state.",
+            &self.txs_applied,
+        );
+        out.counter_vec(
+            "grugchat_txs_rejected_total",
+            "Transactions rejected, by stage and reason.",
+            &self.txs_rejected,
+        );
+        out.histogram_vec(
+            "grugchat_batch_size_bytes",
+            "Encoded size of posted batches.",
+            &self.batch_bytes,
+        );
+        out.histogram_vec(
+            "grugchat_batch_size_txs",
+            "Transactions per posted batch.",
+            &self.batch_txs,
+        );
+        out.histogram_vec(
+            "grugchat_da_submit_seconds",
+            "Time taken to submit blobs to the DA layer.",
+            &self.submit_seconds,
+        );
+        out.counter_vec(
+            "grugchat_da_submit_failures_total",
+            "Failed blob submissions.",
+            &self.submit_failures,
+        );
+        out.counter_vec(
+            "grugchat_da_submit_fees_utia_total",
+            "Fees paid for blob submissions in utia, where the DA layer reports them.",
+            &self.submit_fees,
+        );
+        out.histogram_vec(
+            "grugchat_http_request_seconds",
+            "HTTP request latency, by route and status.",
+            &self.http_request_seconds,
+        );
+        if let Some(state) = state {
+            out.gauge(
+                "grugchat_users",
+                "Registered users.",
+                state.user_count() as u64,
+            );
+            out.gauge(
+                "grugchat_channels",
+                "Channels with at least one message.",
+                state.channel_count() as u64,
+            );
+            out.gauge(
+                "grugchat_messages",
+                "Messages across all channels.",
+                state.message_count() as u64,
+            );
+        }
+        out.0
+    }
+}
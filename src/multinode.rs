@@ -0,0 +1,155 @@
+use anyhow::{bail, Result};
+use celestia_types::nmt::Namespace;
+use std::sync::Arc;
+use tokio::spawn;
+use tokio::sync::watch;
+use tokio::time::Duration;
+use tracing::error;
+
+use crate::{
+    config::NodeConfig,
+    da::{CelestiaDa, DataAvailabilityLayer},
+    fullnode::{namespace_hex, run_until_shutdown, serve, FullNode},
+};
+
+/// Serves several grugchat networks from one process. Each namespace gets
+/// its own `FullNode`, with its own state, sync cursor and pending batch,
+/// and they share the DA connection and the HTTP server, where each is
+/// mounted under `/ns/<namespace_hex>`.
+pub struct MultiNode {
+    nodes: Vec<Arc<FullNode>>,
+
+    shutdown: watch::Sender<bool>,
+    shutdown_timeout: Duration,
+}
+
+impl MultiNode {
+    pub async fn new(namespaces: &[Namespace], start_height: u64) -> Result<Self> {
+        let config = NodeConfig::from_env()?;
+        let da = CelestiaDa::connect("ws://localhost:26658", None)
+            .await?
+            .with_gas_price(config.da_gas_price);
+        Self::with_da(Arc::new(da), namespaces, start_height, config)
+    }
+
+    /// The first namespace is the default, served to requests that don't
+    /// name one. With more than one namespace, each saves its snapshot to
+    /// `snapshot_path` suffixed with its namespace, and resumes from it as
+    /// `FullNode::resume` does.
+    pub fn with_da(
+        da: Arc<dyn DataAvailabilityLayer>,
+        namespaces: &[Namespace],
+        start_height: u64,
+        config: NodeConfig,
+    ) -> Result<Self> {
+        if namespaces.is_empty() {
+            bail!("at least one namespace is required");
+        }
+
+        let mut nodes: Vec<Arc<FullNode>> = Vec::new();
+        for &namespace in namespaces {
+            if nodes.iter().any(|node| node.namespace() == namespace) {
+                bail!("namespace {} is given twice", namespace_hex(&namespace));
+            }
+            let mut config = config.clone();
+            if namespaces.len() > 1 {
+                config.snapshot_path = config.snapshot_path.map(|path| {
+                    let mut path = path.into_os_string();
+                    path.push(format!(".{}", namespace_hex(&namespace)));
+                    path.into()
+                });
+            }
+            nodes.push(Arc::new(FullNode::resume(
+                da.clone(),
+                namespace,
+                start_height,
+                config,
+            )?));
+        }
+
+        Ok(MultiNode {
+            nodes,
+            shutdown: watch::channel(false).0,
+            shutdown_timeout: config.shutdown_timeout,
+        })
+    }
+
+    pub fn nodes(&self) -> &[Arc<FullNode>] {
+        &self.nodes
+    }
+
+    pub fn node(&self, namespace: Namespace) -> Option<&Arc<FullNode>> {
+        self.nodes.iter().find(|node| node.namespace() == namespace)
+    }
+
+    /// Starts a graceful shutdown of `start`, and of every node.
+    pub fn shutdown(&self) {
+        for node in &self.nodes {
+            node.shutdown();
+        }
+        self.shutdown.send_replace(true);
+    }
+
+    /// Resolves once shutdown has started.
+    fn shutdown_requested(&self) -> impl std::future::Future<Output = ()> {
+        let mut shutdown = self.shutdown.subscribe();
+        async move {
+            let _ = shutdown.wait_for(|shutting_down| *shutting_down).await;
+        }
+    }
+
+    /// Finishes every node as `FullNode::finish` does, even if some fail,
+    /// and reports the failures together. Called once shutdown has started.
+    pub async fn finish(&self) -> Result<()> {
+        let mut failures = Vec::new();
+        for node in &self.nodes {
+            if let Err(e) = node.clone().finish().await {
+                error!(
+                    namespace = namespace_hex(&node.namespace()),
+                    error = format!("{e:#}"),
+                    "Node failed to finish"
+                );
+                failures.push(format!("{}: {e:#}", namespace_hex(&node.namespace())));
+            }
+        }
+        if !failures.is_empty() {
+            bail!("shutdown failed for {}", failures.join("; "));
+        }
+        Ok(())
+    }
+
+    /// Runs every node until SIGINT, SIGTERM or `shutdown`, then shuts them
+    /// all down gracefully as `FullNode::start` does for one.
+    pub async fn start(self: Arc<Self>) -> Result<()> {
+        let run_handles: Vec<_> = self
+            .nodes
+            .iter()
+            .map(|node| spawn(node.clone().run()))
+            .collect();
+
+        let server_handle = spawn({
+            let multinode = self.clone();
+            async move { serve(&multinode.nodes, multinode.shutdown_requested()).await }
+        });
+
+        let tasks = async {
+            tokio::try_join!(
+                async {
+                    for handle in run_handles {
+                        handle.await??;
+                    }
+                    anyhow::Ok(())
+                },
+                async { server_handle.await? },
+            )
+        };
+        run_until_shutdown(
+            tasks,
+            self.shutdown_requested(),
+            || self.shutdown(),
+            self.shutdown_timeout,
+            self.finish(),
+        )
+        .await
+    }
+}
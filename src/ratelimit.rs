@@ -0,0 +1,273 @@
+use crate::config::RateLimitConfig;
+use crate::tx::PublicKey;
+use anyhow::{anyhow, bail, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// The most leading zero bits a stamp can be asked for, which takes around
+/// 16 million hashes to mint.
+pub const MAX_POW_DIFFICULTY: u32 = 24;
+
+/// Counters are dropped once a map grows past this and their window ended.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+/// Fixed-window request counters, keyed by anything hashable.
+struct Counters<K> {
+    windows: Mutex<HashMap<K, Window>>,
+}
+
+impl<K: Eq + Hash> Counters<K> {
+    fn new() -> Self {
+        Counters {
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request for `key`, returning false if it is over `limit`.
+    async fn hit(&self, key: K, limit: u32, window: Duration) -> bool {
+        let mut windows = self.windows.lock().await;
+        if windows.len() > PRUNE_THRESHOLD {
+            windows.retain(|_, w| w.started.elapsed() < window);
+        }
+
+        let entry = windows.entry(key).or_insert(Window {
+            started: Instant::now(),
+            count: 0,
+        });
+        if entry.started.elapsed() >= window {
+            entry.started = Instant::now();
+            entry.count = 0;
+        }
+        if entry.count >= limit {
+            return false;
+        }
+        entry.count += 1;
+        true
+    }
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    by_key: Counters<PublicKey>,
+    by_ip: Counters<IpAddr>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            by_key: Counters::new(),
+            by_ip: Counters::new(),
+        }
+    }
+
+    pub async fn check(&self, key: &PublicKey, ip: IpAddr) -> Result<(), String> {
+        self.check_ip(ip).await?;
+        if !self
+            .by_key
+            .hit(key.clone(), self.config.per_key, self.config.window)
+            .await
+        {
+            return Err("rate limit exceeded for this key".to_string());
+        }
+        Ok(())
+    }
+
+    /// Counts a request that isn't tied to a key, such as an attachment
+    /// upload, against `ip` only.
+    pub async fn check_ip(&self, ip: IpAddr) -> Result<(), String> {
+        if !self
+            .by_ip
+            .hit(ip, self.config.per_ip, self.config.window)
+            .await
+        {
+            return Err(format!("rate limit exceeded for {ip}"));
+        }
+        Ok(())
+    }
+
+    /// The address a request is limited by: the client IP it was forwarded
+    /// for if `peer` is a trusted proxy, and otherwise `peer` itself.
+    pub fn client_ip(&self, peer: IpAddr, forwarded_for: Option<IpAddr>) -> IpAddr {
+        match forwarded_for {
+            Some(ip) if self.config.trusted_proxies.contains(&peer) => ip,
+            _ => peer,
+        }
+    }
+
+    /// The stamp difficulty required of messages from `ip`, if any.
+    pub fn pow_difficulty(&self, ip: IpAddr) -> Option<u32> {
+        if self.config.pow_difficulty == 0 || self.config.trusted_ips.contains(&ip) {
+            return None;
+        }
+        Some(self.config.pow_difficulty)
+    }
+
+    /// The stamp difficulty required of attachment uploads from `ip`, if any.
+    pub fn attachment_pow_difficulty(&self, ip: IpAddr) -> Option<u32> {
+        if self.config.attachment_pow_difficulty == 0 || self.config.trusted_ips.contains(&ip) {
+            return None;
+        }
+        Some(self.config.attachment_pow_difficulty)
+    }
+}
+
+fn stamp_hash(tx_bytes: &[u8], nonce: u64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(tx_bytes);
+    hasher.update(nonce.to_le_bytes());
+    hasher.finalize().into()
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// Checks a proof-of-work stamp over an encoded transaction.
+pub fn verify_stamp(tx_bytes: &[u8], nonce: u64, difficulty: u32) -> bool {
+    leading_zero_bits(&stamp_hash(tx_bytes, nonce)) >= difficulty
+}
+
+/// Finds a nonce whose stamp over `tx_bytes` meets `difficulty`. Each
+/// nonce tried has a `2^-difficulty` chance, so giving up after `2^8` times
+/// the expected number of tries only fails on bad luck that never happens.
+pub fn mint_stamp(tx_bytes: &[u8], difficulty: u32) -> Result<u64> {
+    if difficulty > MAX_POW_DIFFICULTY {
+        bail!("proof-of-work difficulty {difficulty} is over the maximum of {MAX_POW_DIFFICULTY}");
+    }
+    let attempts = 1u64 << (difficulty + 8);
+    (0..attempts)
+        .find(|nonce| verify_stamp(tx_bytes, *nonce, difficulty))
+        .ok_or_else(|| anyhow!("no stamp of difficulty {difficulty} found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::sleep;
+
+    fn limiter(window: Duration) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            window,
+            per_key: 2,
+            per_ip: 3,
+            pow_difficulty: 8,
+            attachment_pow_difficulty: 12,
+            trusted_ips: vec!["10.0.0.1".parse().unwrap()],
+            trusted_proxies: vec!["10.0.0.2".parse().unwrap()],
+        })
+    }
+
+    fn key(n: u8) -> PublicKey {
+        PublicKey::new(vec![n; 32])
+    }
+
+    #[tokio::test]
+    async fn limits_each_key_and_ip() {
+        let limiter = limiter(Duration::from_secs(60));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(limiter.check(&key(1), ip).await.is_ok());
+        assert!(limiter.check(&key(1), ip).await.is_ok());
+        assert!(limiter.check(&key(1), ip).await.is_err());
+
+        // The rejected request still counted against the IP.
+        assert!(limiter.check(&key(2), ip).await.is_err());
+        let other_ip: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(limiter.check(&key(2), other_ip).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn limits_reset_after_the_window() {
+        let window = Duration::from_millis(50);
+        let limiter = limiter(window);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(limiter.check(&key(1), ip).await.is_ok());
+        assert!(limiter.check(&key(1), ip).await.is_ok());
+        assert!(limiter.check(&key(1), ip).await.is_err());
+
+        sleep(window).await;
+        assert!(limiter.check(&key(1), ip).await.is_ok());
+    }
+
+    #[test]
+    fn trusted_ips_need_no_stamp() {
+        let limiter = limiter(Duration::from_secs(60));
+        assert_eq!(
+            limiter.pow_difficulty("192.0.2.1".parse().unwrap()),
+            Some(8)
+        );
+        assert_eq!(limiter.pow_difficulty("10.0.0.1".parse().unwrap()), None);
+        assert_eq!(
+            limiter.attachment_pow_difficulty("192.0.2.1".parse().unwrap()),
+            Some(12)
+        );
+        assert_eq!(
+            limiter.attachment_pow_difficulty("10.0.0.1".parse().unwrap()),
+            None
+        );
+
+        let disabled = RateLimiter::new(RateLimitConfig::default());
+        assert_eq!(disabled.pow_difficulty("192.0.2.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn forwarded_ips_are_only_believed_from_trusted_proxies() {
+        let limiter = limiter(Duration::from_secs(60));
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let proxy: IpAddr = "10.0.0.2".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        assert_eq!(limiter.client_ip(proxy, Some(client)), client);
+        assert_eq!(limiter.client_ip(proxy, None), proxy);
+        assert_eq!(limiter.client_ip(other, Some(client)), other);
+        // A proxy can't forward its way out of needing a stamp.
+        let trusted: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(limiter.client_ip(other, Some(trusted)), other);
+    }
+
+    #[test]
+    fn minted_stamps_verify() {
+        let tx_bytes = b"some transaction";
+        let nonce = mint_stamp(tx_bytes, 12).unwrap();
+        assert!(verify_stamp(tx_bytes, nonce, 12));
+        assert!(!verify_stamp(b"another transaction", nonce, 12));
+        assert!(verify_stamp(tx_bytes, 0, 0));
+    }
+
+    #[test]
+    fn stamps_are_checked_against_the_difficulty() {
+        let tx_bytes = b"some transaction";
+        let nonce = (0..)
+            .find(|nonce| leading_zero_bits(&stamp_hash(tx_bytes, *nonce)) == 4)
+            .unwrap();
+        assert!(verify_stamp(tx_bytes, nonce, 4));
+        assert!(!verify_stamp(tx_bytes, nonce, 5));
+    }
+
+    #[test]
+    fn minting_is_bounded() {
+        assert!(mint_stamp(b"some transaction", MAX_POW_DIFFICULTY + 1).is_err());
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0x00, 0x00, 0x10]), 19);
+        assert_eq!(leading_zero_bits(&[0x80]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+}
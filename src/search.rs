@@ -0,0 +1,344 @@
+use crate::tx::Content;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+/// Searches return at most this many messages, newest first.
+pub const MAX_RESULTS: usize = 100;
+
+/// A search request. `q` is a list of terms that must all match: plain
+/// words, `prefix*` terms, and `"quoted phrases"`. The other fields narrow
+/// the results to a channel, a user id and a range of DA heights.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct SearchQuery {
+    pub q: String,
+    pub channel: Option<String>,
+    pub user: Option<String>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+/// Where a matching message is, so it can be looked up in the state.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Hit<'a> {
+    pub channel: &'a str,
+    pub index: u64,
+}
+
+#[derive(PartialEq, Debug)]
+enum Clause {
+    Term(String),
+    Prefix(String),
+    Phrase(Vec<String>),
+}
+
+struct Document {
+    channel: String,
+    index: u64,
+    user_id: String,
+    height: u64,
+    tokens: Vec<String>,
+}
+
+/// An inverted index over every applied message. It is derived from the
+/// channels, so it isn't committed to and is rebuilt when restoring from a
+/// snapshot.
+#[derive(Default)]
+pub struct SearchIndex {
+    documents: Vec<Document>,
+    /// Each token's documents, in the order they were added.
+    postings: BTreeMap<String, Vec<usize>>,
+}
+
+/// Lowercased runs of alphanumeric characters.
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn searchable_text(content: &Content) -> String {
+    match content {
+        Content::Text(text) | Content::Markdown(text) => text.clone(),
+        Content::Attachment(attachment) => attachment.name.clone(),
+        Content::Link(link) => [
+            Some(link.url.as_str()),
+            link.title.as_deref(),
+            link.description.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" "),
+    }
+}
+
+fn parse(q: &str) -> Result<Vec<Clause>> {
+    if q.matches('"').count() % 2 == 1 {
+        bail!("unterminated phrase in search query");
+    }
+    let mut clauses = Vec::new();
+    for (i, part) in q.split('"').enumerate() {
+        if i % 2 == 1 {
+            let phrase = tokenize(part);
+            if !phrase.is_empty() {
+                clauses.push(Clause::Phrase(phrase));
+            }
+            continue;
+        }
+        for word in part.split_whitespace() {
+            let (word, is_prefix) = match word.strip_suffix('*') {
+                Some(prefix) => (prefix, true),
+                None => (word, false),
+            };
+            // Punctuation inside a word splits it like it does in messages,
+            // so `don't` searches for the phrase "don t".
+            let mut tokens = tokenize(word);
+            match (tokens.len(), is_prefix) {
+                (0, _) => {}
+                (1, false) => clauses.push(Clause::Term(tokens.remove(0))),
+                (1, true) => clauses.push(Clause::Prefix(tokens.remove(0))),
+                (_, _) => clauses.push(Clause::Phrase(tokens)),
+            }
+        }
+    }
+    if clauses.is_empty() {
+        bail!("search query must contain at least one word");
+    }
+    Ok(clauses)
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(
+        &mut self,
+        channel: &str,
+        index: u64,
+        user_id: &str,
+        height: u64,
+        content: &Content,
+    ) {
+        let id = self.documents.len();
+        let tokens = tokenize(&searchable_text(content));
+        for token in &tokens {
+            let docs = self.postings.entry(token.clone()).or_default();
+            if docs.last() != Some(&id) {
+                docs.push(id);
+            }
+        }
+        self.documents.push(Document {
+            channel: channel.to_string(),
+            index,
+            user_id: user_id.to_string(),
+            height,
+            tokens,
+        });
+    }
+
+    fn term_docs(&self, term: &str) -> HashSet<usize> {
+        self.postings
+            .get(term)
+            .map(|docs| docs.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn prefix_docs(&self, prefix: &str) -> HashSet<usize> {
+        self.postings
+            .range(prefix.to_string()..)
+            .take_while(|(token, _)| token.starts_with(prefix))
+            .flat_map(|(_, docs)| docs.iter().copied())
+            .collect()
+    }
+
+    fn phrase_docs(&self, phrase: &[String]) -> HashSet<usize> {
+        let mut docs = self.term_docs(&phrase[0]);
+        for term in &phrase[1..] {
+            let term_docs = self.term_docs(term);
+            docs.retain(|doc| term_docs.contains(doc));
+        }
+        docs.retain(|doc| {
+            self.documents[*doc]
+                .tokens
+                .windows(phrase.len())
+                .any(|window| window == phrase)
+        });
+        docs
+    }
+
+    /// Finds the messages matching every clause of `query` and its filters,
+    /// newest first.
+    pub fn search(&self, query: &SearchQuery) -> Result<Vec<Hit<'_>>> {
+        let mut matches: Option<HashSet<usize>> = None;
+        for clause in parse(&query.q)? {
+            let docs = match &clause {
+                Clause::Term(term) => self.term_docs(term),
+                Clause::Prefix(prefix) => self.prefix_docs(prefix),
+                Clause::Phrase(phrase) => self.phrase_docs(phrase),
+            };
+            matches = Some(match matches {
+                Some(mut matches) => {
+                    matches.retain(|doc| docs.contains(doc));
+                    matches
+                }
+                None => docs,
+            });
+        }
+
+        let mut matches: Vec<usize> = matches
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|doc| {
+                let doc = &self.documents[*doc];
+                query.channel.as_ref().is_none_or(|c| *c == doc.channel)
+                    && query.user.as_ref().is_none_or(|u| *u == doc.user_id)
+                    && query.from.is_none_or(|from| doc.height >= from)
+                    && query.to.is_none_or(|to| doc.height <= to)
+            })
+            .collect();
+        // Documents are added in the order messages are applied.
+        matches.sort_unstable_by(|a, b| b.cmp(a));
+        matches.truncate(MAX_RESULTS);
+
+        Ok(matches
+            .into_iter()
+            .map(|doc| Hit {
+                channel: &self.documents[doc].channel,
+                index: self.documents[doc].index,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> Content {
+        Content::Text(text.to_string())
+    }
+
+    fn query(q: &str) -> SearchQuery {
+        SearchQuery {
+            q: q.to_string(),
+            ..SearchQuery::default()
+        }
+    }
+
+    fn sample_index() -> SearchIndex {
+        let mut index = SearchIndex::new();
+        index.add("general", 0, "alice", 1, &text("Hello, world!"));
+        index.add("general", 1, "bob", 2, &text("the world says hello back"));
+        index.add("random", 0, "alice", 3, &text("helicopters are loud"));
+        index.add("random", 1, "bob", 4, &text("don't panic"));
+        index
+    }
+
+    fn hits(index: &SearchIndex, query: &SearchQuery) -> Vec<(String, u64)> {
+        index
+            .search(query)
+            .unwrap()
+            .into_iter()
+            .map(|hit| (hit.channel.to_string(), hit.index))
+            .collect()
+    }
+
+    #[test]
+    fn parses_terms_prefixes_and_phrases() {
+        assert_eq!(
+            parse(r#"Hello wor* "big  World" don't"#).unwrap(),
+            vec![
+                Clause::Term("hello".to_string()),
+                Clause::Prefix("wor".to_string()),
+                Clause::Phrase(vec!["big".to_string(), "world".to_string()]),
+                Clause::Phrase(vec!["don".to_string(), "t".to_string()]),
+            ]
+        );
+        // A phrase of one word is still a phrase, and a lone `*` is nothing.
+        assert_eq!(
+            parse(r#""hello" *"#).unwrap(),
+            vec![Clause::Phrase(vec!["hello".to_string()])]
+        );
+    }
+
+    #[test]
+    fn rejects_queries_without_words() {
+        assert!(parse("").is_err());
+        assert!(parse("  ?! * \"\"").is_err());
+        assert!(parse(r#"hello "world"#).is_err());
+    }
+
+    #[test]
+    fn every_clause_must_match() {
+        let index = sample_index();
+        assert_eq!(
+            hits(&index, &query("hello")),
+            vec![("general".to_string(), 1), ("general".to_string(), 0)]
+        );
+        assert_eq!(
+            hits(&index, &query("hello back")),
+            vec![("general".to_string(), 1)]
+        );
+        assert!(hits(&index, &query("hello panic")).is_empty());
+    }
+
+    #[test]
+    fn matches_prefixes_and_phrases_in_order() {
+        let index = sample_index();
+        assert_eq!(
+            hits(&index, &query("hel*")),
+            vec![
+                ("random".to_string(), 0),
+                ("general".to_string(), 1),
+                ("general".to_string(), 0),
+            ]
+        );
+        assert_eq!(
+            hits(&index, &query(r#""hello world""#)),
+            vec![("general".to_string(), 0)]
+        );
+        assert_eq!(
+            hits(&index, &query("don't")),
+            vec![("random".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn filters_by_channel_user_and_height() {
+        let index = sample_index();
+        let filtered = |channel: Option<&str>, user: Option<&str>, from, to| SearchQuery {
+            q: "hel*".to_string(),
+            channel: channel.map(str::to_string),
+            user: user.map(str::to_string),
+            from,
+            to,
+        };
+        assert_eq!(
+            hits(&index, &filtered(Some("random"), None, None, None)),
+            vec![("random".to_string(), 0)]
+        );
+        assert_eq!(
+            hits(&index, &filtered(None, Some("bob"), None, None)),
+            vec![("general".to_string(), 1)]
+        );
+        assert_eq!(
+            hits(&index, &filtered(None, None, Some(2), Some(2))),
+            vec![("general".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn returns_the_newest_results() {
+        let mut index = SearchIndex::new();
+        for i in 0..MAX_RESULTS as u64 + 10 {
+            index.add("general", i, "alice", i, &text("spam"));
+        }
+        let results = hits(&index, &query("spam"));
+        assert_eq!(results.len(), MAX_RESULTS);
+        assert_eq!(results[0].1, MAX_RESULTS as u64 + 9);
+        assert_eq!(results[MAX_RESULTS - 1].1, 10);
+    }
+}
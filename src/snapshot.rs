@@ -0,0 +1,38 @@
+use crate::merkle::Hash;
+use crate::state::{ChannelAssignment, Message, PendingRecovery, Session};
+use crate::tx::{NamespaceId, Profile, PublicKey};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// A copy of the full `State` after applying DA height `height`, which lets
+/// new nodes skip replaying everything before it.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub height: u64,
+    pub users: Vec<(PublicKey, String)>,
+    pub profiles: Vec<(PublicKey, Profile)>,
+    pub recovery_keys: Vec<(PublicKey, Vec<PublicKey>)>,
+    pub pending_recoveries: Vec<(PublicKey, PendingRecovery)>,
+    /// Keys that were rotated away from, and the key each moved to.
+    pub successors: Vec<(PublicKey, PublicKey)>,
+    pub sessions: Vec<(PublicKey, Session)>,
+    pub nonces: Vec<(PublicKey, u64)>,
+    pub channel_nonces: Vec<(NamespaceId, Vec<(PublicKey, u64)>)>,
+    pub channels: Vec<(String, Vec<Message>)>,
+    pub directory: Vec<(String, ChannelAssignment)>,
+    pub state_root: Hash,
+}
+
+impl Snapshot {
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let bytes = fs::read(path).context("Failed to read snapshot file")?;
+        bincode::deserialize(&bytes).context("Failed to decode snapshot")
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let bytes = bincode::serialize(self)?;
+        fs::write(path, bytes).context("Failed to write snapshot file")
+    }
+}
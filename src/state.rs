@@ -1,25 +1,935 @@
-use crate::tx::{PublicKey, Transaction};
+use crate::config::{ChainConfig, IndexFilter};
+use crate::legacy;
+use crate::merkle::{self, Hash, MerkleProof, SparseMerkleTree};
+use crate::search::{SearchIndex, SearchQuery};
+use crate::snapshot::Snapshot;
+use crate::tx::{
+    AssignChannel, AuthorizeSessionKey, CancelRecovery, Content, NamespaceId, Profile, PublicKey,
+    RevokeSessionKey, RotateKey, SendMessage, SessionScope, SetRecoveryKeys, Signature,
+    Transaction, UpdateProfile,
+};
+use crate::validation;
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
 
-#[derive(Serialize, Deserialize, Clone)]
+/// How many messages an account may send within `QUOTA_WINDOW` DA heights,
+/// counted separately in each namespace so that nodes agree on a namespace
+/// without following the others. This is a consensus rule, so every node
+/// must use the same values. It applies from the `message_quota` activation
+/// height.
+pub const MESSAGE_QUOTA: usize = 100;
+pub const QUOTA_WINDOW: u64 = 10;
+
+/// How many DA heights a recovery waits before the account is moved, so
+/// the owner has time to notice and cancel it. Also a consensus rule.
+pub const RECOVERY_DELAY: u64 = 14_400;
+
+/// Session keys are short-lived: they expire at most this many DA heights
+/// after the height they were authorized at, and each account may have at
+/// most `MAX_SESSION_KEYS` at once. Also consensus rules.
+pub const MAX_SESSION_LIFETIME: u64 = 100_800;
+pub const MAX_SESSION_KEYS: usize = 16;
+
+/// How many of the most recent heights' state roots are kept for
+/// `GET /root/:height`. Unlike the above, nodes may differ on this.
+pub const ROOT_RETENTION: u64 = 100_800;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Message {
     pub user_id: String,
-    pub contents: String,
+    pub contents: Content,
+    /// The sender and signature of the original `SendMessage`, so clients
+    /// can check the message wasn't forged by the node serving it.
+    pub user: PublicKey,
+    pub signature: Signature,
+    /// The account `user` was a session key of when the message was sent,
+    /// if it wasn't signed by the account's own key.
+    pub parent: Option<PublicKey>,
+    /// The nonce it was signed with, or zero if it came from an unversioned
+    /// blob.
+    pub nonce: u64,
+    /// The DA height the message was included at.
+    pub height: u64,
+}
+
+impl Message {
+    /// The key of the account that sent the message, as of when it was sent.
+    pub fn author(&self) -> &PublicKey {
+        self.parent.as_ref().unwrap_or(&self.user)
+    }
+
+    /// Messages from unversioned blobs were signed in the `legacy` encoding,
+    /// so text messages are checked against that too.
+    pub fn verify_signature(&self, channel: &str) -> bool {
+        let current = Transaction::SendMessage(SendMessage {
+            user: self.user.clone(),
+            contents: self.contents.clone(),
+            channel: channel.to_string(),
+            nonce: self.nonce,
+            signature: self.signature.clone(),
+        });
+        if current.verify_signature() {
+            return true;
+        }
+        match &self.contents {
+            Content::Text(text) => legacy::Transaction::SendMessage(legacy::SendMessage {
+                user: self.user.clone(),
+                contents: text.clone(),
+                channel: channel.to_string(),
+                signature: self.signature.clone(),
+            })
+            .verify_signature(),
+            _ => false,
+        }
+    }
+}
+
+/// Why a transaction was rejected, coarse enough to label metrics with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RejectReason {
+    Signature,
+    Invalid,
+    Unauthorized,
+    Quota,
+    Conflict,
+    /// Posted to a namespace other than the one its channel is in.
+    Namespace,
+}
+
+impl RejectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectReason::Signature => "signature",
+            RejectReason::Invalid => "invalid",
+            RejectReason::Unauthorized => "unauthorized",
+            RejectReason::Quota => "quota",
+            RejectReason::Conflict => "conflict",
+            RejectReason::Namespace => "namespace",
+        }
+    }
+}
+
+/// The error transactions are rejected with. It displays as its message, so
+/// callers that don't care about the reason can treat it like any other
+/// error, and those that do can downcast to it.
+#[derive(Debug)]
+pub struct Rejected {
+    pub reason: RejectReason,
+    message: String,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+fn reject(reason: RejectReason, message: impl Into<String>) -> anyhow::Error {
+    Rejected {
+        reason,
+        message: message.into(),
+    }
+    .into()
+}
+
+/// A key allowed to send messages for `parent` until height `expires`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Session {
+    pub parent: PublicKey,
+    pub scope: SessionScope,
+    pub expires: u64,
+}
+
+/// The namespace a channel was moved to by `AssignChannel`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ChannelAssignment {
+    /// The namespace's v0 id.
+    pub namespace: NamespaceId,
+    /// The first height the channel's messages are accepted at there.
+    pub since: u64,
+}
+
+/// A message along with where it is, for views that span channels.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IndexedMessage {
+    pub channel: String,
+    pub index: u64,
+    pub message: Message,
+}
+
+/// A recovery that moves an account to `new_key` once the DA height
+/// reaches `started + RECOVERY_DELAY`, unless the owner cancels it.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct PendingRecovery {
+    pub recovery_key: PublicKey,
+    pub new_key: PublicKey,
+    pub started: u64,
+}
+
+impl PendingRecovery {
+    pub fn due(&self) -> u64 {
+        self.started + RECOVERY_DELAY
+    }
+}
+
+/// What `GET /users/:id` serves about a registered user.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UserInfo {
+    pub user: PublicKey,
+    pub user_id: String,
+    pub profile: Profile,
+    pub message_count: u64,
+    pub recovery_keys: Vec<PublicKey>,
+    pub pending_recovery: Option<PendingRecovery>,
 }
 
+#[derive(Default)]
 pub struct State {
+    chain: ChainConfig,
     users: HashMap<PublicKey, String>,
+    profiles: HashMap<PublicKey, Profile>,
+    recovery_keys: HashMap<PublicKey, Vec<PublicKey>>,
+    pending_recoveries: HashMap<PublicKey, PendingRecovery>,
+    /// Keys that accounts were moved away from, which can't be used again.
+    successors: HashMap<PublicKey, PublicKey>,
+    sessions: HashMap<PublicKey, Session>,
+    /// The nonce of the last transaction applied from each key in the app
+    /// namespace.
+    nonces: HashMap<PublicKey, u64>,
+    /// The same for the messages in each channel namespace, which are
+    /// committed to its tree.
+    channel_nonces: HashMap<NamespaceId, HashMap<PublicKey, u64>>,
     channels: HashMap<String, Vec<Message>>,
+    /// Channels whose messages are posted to their own namespace instead of
+    /// the app namespace.
+    directory: HashMap<String, ChannelAssignment>,
+    /// The app namespace and its attachment namespace, which channels can't
+    /// be moved to.
+    app_namespaces: Vec<NamespaceId>,
+    /// The last DA height whose transactions have been applied.
+    last_height: Option<u64>,
+    /// The DA height whose transactions are currently being applied.
+    block_height: u64,
+    /// Heights of each account's messages within the last `QUOTA_WINDOW`,
+    /// with the channel namespace each was sent in, if not the app's.
+    recent_messages: HashMap<PublicKey, VecDeque<(u64, Option<NamespaceId>)>>,
+
+    /// Commits to every user, every channel's message count and every
+    /// message, so that nodes can prove what they serve.
+    tree: SparseMerkleTree,
+    /// The root after each of the last `ROOT_RETENTION` heights.
+    roots: BTreeMap<u64, Hash>,
+    /// Commit to the channels moved to each channel namespace in place of
+    /// `tree`, which then only depends on the app namespace, so nodes
+    /// following different channels still agree on its root.
+    channel_trees: HashMap<NamespaceId, SparseMerkleTree>,
+    /// Their roots after each height in `roots`.
+    channel_roots: BTreeMap<u64, HashMap<NamespaceId, Hash>>,
+
+    search: SearchIndex,
+    /// Where each user's messages are, in the order they were applied.
+    user_messages: HashMap<PublicKey, Vec<(String, u64)>>,
+
+    /// Which messages are stored. Those that aren't still count towards
+    /// quotas, and their channels are noted in `unindexed_channels` so
+    /// they can't be moved to their own namespace, just as on other nodes.
+    index_filter: IndexFilter,
+    unindexed_channels: HashSet<String>,
+}
+
+fn leaf_value<T: Serialize + ?Sized>(value: &T) -> Hash {
+    merkle::hash(&bincode::serialize(value).expect("state values are serializable"))
+}
+
+fn user_key(user: &PublicKey) -> Hash {
+    leaf_value(&("user", user))
+}
+
+fn profile_key(user: &PublicKey) -> Hash {
+    leaf_value(&("profile", user))
+}
+
+fn recovery_key(user: &PublicKey) -> Hash {
+    leaf_value(&("recovery", user))
+}
+
+fn pending_recovery_key(user: &PublicKey) -> Hash {
+    leaf_value(&("pending_recovery", user))
+}
+
+fn successor_key(user: &PublicKey) -> Hash {
+    leaf_value(&("successor", user))
+}
+
+fn session_key(key: &PublicKey) -> Hash {
+    leaf_value(&("session", key))
+}
+
+fn nonce_key(key: &PublicKey) -> Hash {
+    leaf_value(&("nonce", key))
+}
+
+fn sorted_by_key<V: Clone>(map: &HashMap<PublicKey, V>) -> Vec<(PublicKey, V)> {
+    let mut entries: Vec<_> = map
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    entries.sort_by_key(|(key, _)| key.to_bytes());
+    entries
+}
+
+fn channel_key(channel: &str) -> Hash {
+    leaf_value(&("channel", channel))
+}
+
+fn message_key(channel: &str, index: u64) -> Hash {
+    leaf_value(&("message", channel, index))
+}
+
+fn assignment_key(channel: &str) -> Hash {
+    leaf_value(&("assignment", channel))
+}
+
+/// Proves which id, if any, `user` is registered under as of `height`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserProof {
+    pub height: Option<u64>,
+    pub root: Hash,
+    pub user: PublicKey,
+    pub user_id: Option<String>,
+    pub proof: MerkleProof,
+}
+
+impl UserProof {
+    pub fn verify(&self) -> bool {
+        let value = self.user_id.as_ref().map(leaf_value);
+        self.proof
+            .verify(&self.root, &user_key(&self.user), value.as_ref())
+    }
+}
+
+/// Proves which message, if any, is at `index` in `channel` as of `height`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MessageProof {
+    pub height: Option<u64>,
+    pub root: Hash,
+    /// The channel namespace `root` is the root of, or `None` for the app
+    /// namespace.
+    pub namespace: Option<NamespaceId>,
+    pub channel: String,
+    pub index: u64,
+    pub message: Option<Message>,
+    pub proof: MerkleProof,
+}
+
+impl MessageProof {
+    pub fn verify(&self) -> bool {
+        let value = self.message.as_ref().map(leaf_value);
+        self.proof.verify(
+            &self.root,
+            &message_key(&self.channel, self.index),
+            value.as_ref(),
+        )
+    }
+}
+
+/// Proves how many messages `channel` has as of `height`, along with a proof
+/// for each message from index `from` on, up to a page of them, so that
+/// omitted messages can be detected.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChannelProof {
+    pub height: Option<u64>,
+    pub root: Hash,
+    /// As in `MessageProof`.
+    pub namespace: Option<NamespaceId>,
+    pub channel: String,
+    pub count: Option<u64>,
+    pub proof: MerkleProof,
+    pub from: u64,
+    pub messages: Vec<MessageProof>,
+}
+
+impl ChannelProof {
+    /// Checks the message count and that there is a valid proof for every
+    /// message from `from` on, all against the same root. Unless `from` is
+    /// past the end, there is at least one.
+    pub fn verify(&self) -> bool {
+        let value = self.count.map(|count| leaf_value(&count));
+        if !self
+            .proof
+            .verify(&self.root, &channel_key(&self.channel), value.as_ref())
+        {
+            return false;
+        }
+
+        let count = self.count.unwrap_or(0);
+        let end = self.from.saturating_add(self.messages.len() as u64);
+        end <= count
+            && (end == count || !self.messages.is_empty())
+            && self.messages.iter().enumerate().all(|(offset, msg)| {
+                msg.index == self.from + offset as u64
+                    && msg.channel == self.channel
+                    && msg.root == self.root
+                    && msg.namespace == self.namespace
+                    && msg.message.is_some()
+                    && msg.verify()
+            })
+    }
 }
 
 impl State {
     pub fn new() -> Self {
         State {
+            chain: ChainConfig::default(),
             users: HashMap::new(),
+            profiles: HashMap::new(),
+            recovery_keys: HashMap::new(),
+            pending_recoveries: HashMap::new(),
+            successors: HashMap::new(),
+            sessions: HashMap::new(),
+            nonces: HashMap::new(),
+            channel_nonces: HashMap::new(),
             channels: HashMap::new(),
+            directory: HashMap::new(),
+            app_namespaces: Vec::new(),
+            last_height: None,
+            block_height: 0,
+            recent_messages: HashMap::new(),
+            tree: SparseMerkleTree::new(),
+            roots: BTreeMap::new(),
+            channel_trees: HashMap::new(),
+            channel_roots: BTreeMap::new(),
+            search: SearchIndex::new(),
+            user_messages: HashMap::new(),
+            index_filter: IndexFilter::default(),
+            unindexed_channels: HashSet::new(),
+        }
+    }
+
+    /// Sets the consensus parameters blocks are applied by.
+    pub fn set_chain_config(&mut self, chain: ChainConfig) {
+        self.chain = chain;
+    }
+
+    /// Sets the namespaces the app posts to itself, by v0 id, which channels
+    /// can't be moved to.
+    pub fn set_app_namespaces(&mut self, namespaces: Vec<NamespaceId>) {
+        self.app_namespaces = namespaces;
+    }
+
+    /// Limits which messages are stored from now on.
+    pub fn set_index_filter(&mut self, filter: IndexFilter) {
+        self.index_filter = filter;
+    }
+
+    pub fn index_filter(&self) -> &IndexFilter {
+        &self.index_filter
+    }
+
+    pub fn last_height(&self) -> Option<u64> {
+        self.last_height
+    }
+
+    /// Starts applying the transactions at `height`, first dropping expired
+    /// session keys and completing the recoveries whose delay is over.
+    pub fn begin_block(&mut self, height: u64) {
+        self.block_height = height;
+
+        let expired: Vec<_> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.expires <= height)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.sessions.remove(&key);
+            self.commit_session(&key);
+        }
+
+        let mut due: Vec<_> = self
+            .pending_recoveries
+            .iter()
+            .filter(|(_, pending)| pending.due() <= height)
+            .map(|(account, pending)| (account.clone(), pending.new_key.clone()))
+            .collect();
+        due.sort_by_key(|(account, _)| account.to_bytes());
+        for (account, new_key) in due {
+            self.pending_recoveries.remove(&account);
+            self.commit_account(&account);
+            // Another account may have taken the key while this one waited.
+            if self.is_key_available(&new_key) {
+                self.move_account(&account, &new_key);
+            }
+        }
+    }
+
+    /// Marks `height` as applied and records the state root after it,
+    /// forgetting roots that have fallen out of `ROOT_RETENTION`.
+    pub fn set_last_height(&mut self, height: u64) {
+        self.last_height = Some(height);
+        self.roots.insert(height, self.root());
+        let channel_roots = self
+            .channel_trees
+            .iter()
+            .map(|(namespace, tree)| (*namespace, tree.root()))
+            .collect();
+        self.channel_roots.insert(height, channel_roots);
+        while self
+            .roots
+            .first_key_value()
+            .is_some_and(|(&oldest, _)| height - oldest >= ROOT_RETENTION)
+        {
+            self.roots.pop_first();
+            self.channel_roots.pop_first();
+        }
+    }
+
+    /// The root of everything in the app namespace.
+    pub fn root(&self) -> Hash {
+        self.tree.root()
+    }
+
+    pub fn root_at(&self, height: u64) -> Option<Hash> {
+        self.roots.get(&height).copied()
+    }
+
+    /// The root of the channels moved to `namespace`, if any were.
+    pub fn channel_root_at(&self, height: u64, namespace: NamespaceId) -> Option<Hash> {
+        self.channel_roots.get(&height)?.get(&namespace).copied()
+    }
+
+    /// The tree `channel`'s messages are committed to, and the channel
+    /// namespace it is for, if not the app's.
+    fn channel_tree(&self, channel: &str) -> (Option<NamespaceId>, &SparseMerkleTree) {
+        match self.channel_namespace(channel) {
+            Some(namespace) => (Some(namespace), &self.channel_trees[&namespace]),
+            None => (None, &self.tree),
+        }
+    }
+
+    fn in_quota_window(&self, height: u64) -> bool {
+        self.block_height.saturating_sub(height) < QUOTA_WINDOW
+    }
+
+    /// Checks that `author` may send another message in `namespace`, on top
+    /// of `pending` ones that aren't on the DA layer yet.
+    fn check_quota(
+        &self,
+        author: &PublicKey,
+        namespace: Option<NamespaceId>,
+        pending: usize,
+    ) -> Result<()> {
+        if self.block_height < self.chain.activations.message_quota {
+            return Ok(());
+        }
+        let recent = self.recent_messages.get(author).map_or(0, |heights| {
+            heights
+                .iter()
+                .filter(|(h, ns)| *ns == namespace && self.in_quota_window(*h))
+                .count()
+        });
+        if recent + pending >= MESSAGE_QUOTA {
+            return Err(reject(
+                RejectReason::Quota,
+                format!(
+                    "message quota of {} per {} blocks exceeded",
+                    MESSAGE_QUOTA, QUOTA_WINDOW
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks, before `msg` is posted, that its sender has quota left for it
+    /// on top of `pending`, the messages accepted but not yet posted. Only
+    /// the quota is checked, since `msg` may depend on transactions that
+    /// are pending too, like the sender's `Register`.
+    pub fn check_pending_quota(&self, msg: &SendMessage, pending: &[SendMessage]) -> Result<()> {
+        let Ok(author) = self.message_author(&msg.user, &msg.channel) else {
+            return Ok(());
+        };
+        let namespace = self.channel_namespace(&msg.channel);
+        let pending = pending
+            .iter()
+            .filter(|other| {
+                self.channel_namespace(&other.channel) == namespace
+                    && self
+                        .message_author(&other.user, &other.channel)
+                        .is_ok_and(|other_author| other_author == author)
+            })
+            .count();
+        self.check_quota(&author, namespace, pending)
+    }
+
+    fn record_message(&mut self, user: &PublicKey, height: u64, namespace: Option<NamespaceId>) {
+        let block_height = self.block_height;
+        let heights = self.recent_messages.entry(user.clone()).or_default();
+        heights.push_back((height, namespace));
+        while heights
+            .front()
+            .is_some_and(|(h, _)| block_height.saturating_sub(*h) >= QUOTA_WINDOW)
+        {
+            heights.pop_front();
+        }
+    }
+
+    fn commit_user(&mut self, user: &PublicKey) {
+        let value = self.users.get(user).map(leaf_value);
+        self.tree.update(&user_key(user), value.as_ref());
+    }
+
+    fn commit_profile(&mut self, user: &PublicKey) {
+        let value = self.profiles.get(user).map(leaf_value);
+        self.tree.update(&profile_key(user), value.as_ref());
+    }
+
+    /// Commits everything stored under `user`'s key.
+    fn commit_account(&mut self, user: &PublicKey) {
+        self.commit_user(user);
+        self.commit_profile(user);
+        let recovery = self.recovery_keys.get(user).map(leaf_value);
+        self.tree.update(&recovery_key(user), recovery.as_ref());
+        let pending = self.pending_recoveries.get(user).map(leaf_value);
+        self.tree
+            .update(&pending_recovery_key(user), pending.as_ref());
+        let successor = self.successors.get(user).map(leaf_value);
+        self.tree.update(&successor_key(user), successor.as_ref());
+    }
+
+    fn commit_session(&mut self, key: &PublicKey) {
+        let value = self.sessions.get(key).map(leaf_value);
+        self.tree.update(&session_key(key), value.as_ref());
+    }
+
+    fn last_nonce(&self, namespace: Option<NamespaceId>, key: &PublicKey) -> Option<u64> {
+        match namespace {
+            Some(namespace) => self.channel_nonces.get(&namespace)?.get(key).copied(),
+            None => self.nonces.get(key).copied(),
+        }
+    }
+
+    fn set_nonce(&mut self, namespace: Option<NamespaceId>, key: &PublicKey, nonce: u64) {
+        match namespace {
+            Some(namespace) => self.channel_nonces.entry(namespace).or_default(),
+            None => &mut self.nonces,
+        }
+        .insert(key.clone(), nonce);
+        let value = leaf_value(&nonce);
+        self.tree_mut(namespace)
+            .update(&nonce_key(key), Some(&value));
+    }
+
+    /// The tree the transactions posted to `namespace` are committed to.
+    fn tree_mut(&mut self, namespace: Option<NamespaceId>) -> &mut SparseMerkleTree {
+        match namespace {
+            Some(namespace) => self
+                .channel_trees
+                .get_mut(&namespace)
+                .expect("every assigned namespace has a tree"),
+            None => &mut self.tree,
+        }
+    }
+
+    fn commit_assignment(&mut self, channel: &str) {
+        let value = self.directory.get(channel).map(leaf_value);
+        self.tree.update(&assignment_key(channel), value.as_ref());
+    }
+
+    /// Whether an account could be moved to `key`, or `key` be made a
+    /// session key.
+    fn is_key_available(&self, key: &PublicKey) -> bool {
+        !self.users.contains_key(key)
+            && !self.successors.contains_key(key)
+            && !self.sessions.contains_key(key)
+    }
+
+    /// The key an account signing as `key` is currently registered under,
+    /// following any rotations since.
+    fn current_key<'a>(&'a self, mut key: &'a PublicKey) -> &'a PublicKey {
+        while let Some(successor) = self.successors.get(key) {
+            key = successor;
+        }
+        key
+    }
+
+    /// The account a message from `signer` to `channel` is sent by: the
+    /// signer itself, or the parent of a session key allowed to post there.
+    fn message_author(&self, signer: &PublicKey, channel: &str) -> Result<PublicKey> {
+        if self.users.contains_key(signer) {
+            return Ok(signer.clone());
+        }
+        let Some(session) = self.sessions.get(signer) else {
+            return Err(reject(
+                RejectReason::Unauthorized,
+                "user not yet registered",
+            ));
+        };
+        if session.expires <= self.block_height {
+            return Err(reject(
+                RejectReason::Unauthorized,
+                "session key has expired",
+            ));
+        }
+        if !session.scope.allows(channel) {
+            return Err(reject(
+                RejectReason::Unauthorized,
+                format!("session key may not post in {channel:?}"),
+            ));
+        }
+        Ok(session.parent.clone())
+    }
+
+    /// Moves an account and everything attached to it from `old` to `new`.
+    /// Its messages keep the key they were signed with, but are listed in
+    /// the history of `new`. The old key is retired.
+    fn move_account(&mut self, old: &PublicKey, new: &PublicKey) {
+        if let Some(id) = self.users.remove(old) {
+            self.users.insert(new.clone(), id);
+        }
+        if let Some(profile) = self.profiles.remove(old) {
+            self.profiles.insert(new.clone(), profile);
+        }
+        if let Some(keys) = self.recovery_keys.remove(old) {
+            self.recovery_keys.insert(new.clone(), keys);
+        }
+        self.pending_recoveries.remove(old);
+        if let Some(heights) = self.recent_messages.remove(old) {
+            self.recent_messages.insert(new.clone(), heights);
+        }
+        if let Some(locations) = self.user_messages.remove(old) {
+            self.user_messages.insert(new.clone(), locations);
+        }
+        self.successors.insert(old.clone(), new.clone());
+
+        // Session keys were authorized by the old key, which may have
+        // leaked, so none of them carry over.
+        let sessions: Vec<_> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.parent == *old)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in sessions {
+            self.sessions.remove(&key);
+            self.commit_session(&key);
+        }
+
+        self.commit_account(old);
+        self.commit_account(new);
+    }
+
+    fn commit_message(&mut self, channel: &str, index: u64) {
+        let messages = self.channels.get(channel);
+        let value = messages
+            .and_then(|msgs| msgs.get(index as usize))
+            .map(leaf_value);
+        let count = messages.map(|msgs| leaf_value(&(msgs.len() as u64)));
+        let tree = self.tree_mut(self.channel_namespace(channel));
+        tree.update(&message_key(channel, index), value.as_ref());
+        tree.update(&channel_key(channel), count.as_ref());
+    }
+
+    pub fn prove_user(&self, user: &PublicKey) -> UserProof {
+        UserProof {
+            height: self.last_height,
+            root: self.root(),
+            user: user.clone(),
+            user_id: self.users.get(user).cloned(),
+            proof: self.tree.prove(&user_key(user)),
+        }
+    }
+
+    /// Proves `channel`'s message count and up to `limit` of its messages
+    /// from index `from` on.
+    pub fn prove_channel(&self, channel: &str, from: u64, limit: u64) -> ChannelProof {
+        let count = self.channels.get(channel).map(|msgs| msgs.len() as u64);
+        let (namespace, tree) = self.channel_tree(channel);
+        let end = count.unwrap_or(0).min(from.saturating_add(limit));
+        ChannelProof {
+            height: self.last_height,
+            root: tree.root(),
+            namespace,
+            channel: channel.to_string(),
+            count,
+            proof: tree.prove(&channel_key(channel)),
+            from,
+            messages: (from..end)
+                .map(|index| self.prove_message(channel, index))
+                .collect(),
+        }
+    }
+
+    pub fn prove_message(&self, channel: &str, index: u64) -> MessageProof {
+        let (namespace, tree) = self.channel_tree(channel);
+        MessageProof {
+            height: self.last_height,
+            root: tree.root(),
+            namespace,
+            channel: channel.to_string(),
+            index,
+            message: self
+                .channels
+                .get(channel)
+                .and_then(|msgs| msgs.get(index as usize))
+                .cloned(),
+            proof: tree.prove(&message_key(channel, index)),
+        }
+    }
+
+    fn sorted_channel_nonces(&self) -> Vec<(NamespaceId, Vec<(PublicKey, u64)>)> {
+        let mut nonces: Vec<_> = self
+            .channel_nonces
+            .iter()
+            .map(|(namespace, nonces)| (*namespace, sorted_by_key(nonces)))
+            .collect();
+        nonces.sort_by_key(|(namespace, _)| *namespace);
+        nonces
+    }
+
+    fn sorted_channels(&self) -> Vec<(String, Vec<Message>)> {
+        let mut channels: Vec<_> = self
+            .channels
+            .iter()
+            .map(|(name, messages)| (name.clone(), messages.clone()))
+            .collect();
+        channels.sort_by(|(a, _), (b, _)| a.cmp(b));
+        channels
+    }
+
+    pub fn to_snapshot(&self) -> Result<Snapshot> {
+        let height = self
+            .last_height
+            .ok_or_else(|| anyhow!("no height has been applied yet"))?;
+        if !self.index_filter.is_everything() {
+            return Err(anyhow!(
+                "snapshots can only be taken of nodes that index every message"
+            ));
+        }
+
+        Ok(Snapshot {
+            height,
+            users: sorted_by_key(&self.users),
+            profiles: sorted_by_key(&self.profiles),
+            recovery_keys: sorted_by_key(&self.recovery_keys),
+            pending_recoveries: sorted_by_key(&self.pending_recoveries),
+            successors: sorted_by_key(&self.successors),
+            sessions: sorted_by_key(&self.sessions),
+            nonces: sorted_by_key(&self.nonces),
+            channel_nonces: self.sorted_channel_nonces(),
+            channels: self.sorted_channels(),
+            directory: self.directory(),
+            state_root: self.root(),
+        })
+    }
+
+    /// Rebuilds the state from `snapshot`, checking that its contents match
+    /// both the root it claims and the `trusted_root` obtained out of band.
+    pub fn from_snapshot(snapshot: Snapshot, trusted_root: Hash) -> Result<Self> {
+        if snapshot.state_root != trusted_root {
+            return Err(anyhow!(
+                "snapshot root {} does not match trusted root {}",
+                hex::encode(snapshot.state_root),
+                hex::encode(trusted_root)
+            ));
+        }
+
+        let mut state = State::new();
+        state.users.extend(snapshot.users);
+        state.profiles.extend(snapshot.profiles);
+        state.recovery_keys.extend(snapshot.recovery_keys);
+        state.successors.extend(snapshot.successors);
+        // Set after `begin_block`, since any recoveries due at the snapshot
+        // height were completed before it was taken.
+        state.begin_block(snapshot.height);
+        state.pending_recoveries.extend(snapshot.pending_recoveries);
+        let accounts: HashSet<PublicKey> = state
+            .users
+            .keys()
+            .chain(state.profiles.keys())
+            .chain(state.recovery_keys.keys())
+            .chain(state.pending_recoveries.keys())
+            .chain(state.successors.keys())
+            .cloned()
+            .collect();
+        for account in &accounts {
+            state.commit_account(account);
+        }
+        state.sessions.extend(snapshot.sessions);
+        let sessions: Vec<_> = state.sessions.keys().cloned().collect();
+        for key in &sessions {
+            state.commit_session(key);
+        }
+        for (key, nonce) in snapshot.nonces {
+            state.set_nonce(None, &key, nonce);
+        }
+        for (channel, assignment) in snapshot.directory {
+            state.channel_trees.entry(assignment.namespace).or_default();
+            state.directory.insert(channel.clone(), assignment);
+            state.commit_assignment(&channel);
+        }
+        for (namespace, nonces) in snapshot.channel_nonces {
+            if !state.channel_trees.contains_key(&namespace) {
+                return Err(anyhow!(
+                    "snapshot has nonces for namespace {}, which no channel is in",
+                    hex::encode(namespace)
+                ));
+            }
+            for (key, nonce) in nonces {
+                state.set_nonce(Some(namespace), &key, nonce);
+            }
+        }
+        for (channel, messages) in snapshot.channels {
+            let namespace = state.channel_namespace(&channel);
+            for (index, msg) in (0..).zip(&messages) {
+                let author = state.current_key(msg.author()).clone();
+                if state.in_quota_window(msg.height) {
+                    state
+                        .recent_messages
+                        .entry(author.clone())
+                        .or_default()
+                        .push_back((msg.height, namespace));
+                }
+                state
+                    .user_messages
+                    .entry(author)
+                    .or_default()
+                    .push((channel.clone(), index));
+            }
+            let count = messages.len() as u64;
+            state.channels.insert(channel.clone(), messages);
+            for index in 0..count {
+                state.commit_message(&channel, index);
+            }
+        }
+        for heights in state.recent_messages.values_mut() {
+            heights.make_contiguous().sort_unstable();
         }
+        // Snapshots don't record the order messages in different channels
+        // were applied in, so fall back to ordering by height.
+        let channels = &state.channels;
+        for locations in state.user_messages.values_mut() {
+            locations.sort_by_key(|(channel, index)| channels[channel][*index as usize].height);
+        }
+        let mut documents: Vec<_> = channels
+            .iter()
+            .flat_map(|(channel, messages)| (0..).zip(messages).map(move |(i, m)| (channel, i, m)))
+            .collect();
+        documents.sort_by_key(|(channel, index, msg)| (msg.height, *channel, *index));
+        for (channel, index, msg) in documents {
+            state
+                .search
+                .add(channel, index, &msg.user_id, msg.height, &msg.contents);
+        }
+        state.set_last_height(snapshot.height);
+
+        if state.root() != trusted_root {
+            return Err(anyhow!("snapshot contents do not match its state root"));
+        }
+        Ok(state)
     }
 
     pub fn read_channel(&self, channel: String) -> Option<&Vec<Message>> {
@@ -30,58 +940,1277 @@ impl State {
         self.channels.keys().collect()
     }
 
+    /// The v0 id of the namespace `channel` was moved to, if it was.
+    pub fn channel_namespace(&self, channel: &str) -> Option<NamespaceId> {
+        self.directory
+            .get(channel)
+            .map(|assignment| assignment.namespace)
+    }
+
+    /// Every channel that was moved to its own namespace, by name.
+    pub fn directory(&self) -> Vec<(String, ChannelAssignment)> {
+        let mut directory: Vec<_> = self
+            .directory
+            .iter()
+            .map(|(channel, assignment)| (channel.clone(), assignment.clone()))
+            .collect();
+        directory.sort_by(|(a, _), (b, _)| a.cmp(b));
+        directory
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn message_count(&self) -> usize {
+        self.channels.values().map(Vec::len).sum()
+    }
+
+    pub fn user_info(&self, user: &PublicKey) -> Option<UserInfo> {
+        Some(UserInfo {
+            user: user.clone(),
+            user_id: self.users.get(user)?.clone(),
+            profile: self.profiles.get(user).cloned().unwrap_or_default(),
+            message_count: self.user_messages.get(user).map_or(0, |m| m.len() as u64),
+            recovery_keys: self.recovery_keys.get(user).cloned().unwrap_or_default(),
+            pending_recovery: self.pending_recoveries.get(user).cloned(),
+        })
+    }
+
+    /// `user`'s messages, newest first, skipping the newest `offset`.
+    pub fn user_messages(
+        &self,
+        user: &PublicKey,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<IndexedMessage> {
+        self.user_messages
+            .get(user)
+            .into_iter()
+            .flat_map(|locations| locations.iter().rev())
+            .skip(offset)
+            .take(limit)
+            .filter_map(|(channel, index)| self.indexed_message(channel, *index))
+            .collect()
+    }
+
+    fn indexed_message(&self, channel: &str, index: u64) -> Option<IndexedMessage> {
+        let message = self.channels.get(channel)?.get(index as usize)?;
+        Some(IndexedMessage {
+            channel: channel.to_string(),
+            index,
+            message: message.clone(),
+        })
+    }
+
+    pub fn search(&self, query: &SearchQuery) -> Result<Vec<IndexedMessage>> {
+        Ok(self
+            .search
+            .search(query)?
+            .into_iter()
+            .filter_map(|hit| self.indexed_message(hit.channel, hit.index))
+            .collect())
+    }
+
+    /// Checks that a message to `channel` was posted to the namespace the
+    /// directory puts it in: the app namespace if `namespace` is `None`,
+    /// otherwise the channel namespace with that v0 id.
+    fn check_namespace(&self, channel: &str, namespace: Option<NamespaceId>) -> Result<()> {
+        match (self.directory.get(channel), namespace) {
+            (None, None) => Ok(()),
+            (Some(assignment), Some(namespace)) if assignment.namespace == namespace => {
+                if assignment.since > self.block_height {
+                    return Err(reject(
+                        RejectReason::Namespace,
+                        format!(
+                            "channel {channel:?} only moves to this namespace at height {}",
+                            assignment.since
+                        ),
+                    ));
+                }
+                Ok(())
+            }
+            (Some(assignment), _) => Err(reject(
+                RejectReason::Namespace,
+                format!(
+                    "channel {channel:?} is in namespace {}",
+                    hex::encode(assignment.namespace)
+                ),
+            )),
+            (None, Some(_)) => Err(reject(
+                RejectReason::Namespace,
+                format!("channel {channel:?} is in the app namespace"),
+            )),
+        }
+    }
+
+    /// Checks `tx` as posted to the app namespace.
     pub fn validate_tx(&self, tx: Transaction) -> Result<()> {
-        let res = tx
-            .clone()
-            .signature()
-            .verify(&tx.pubkey(), &bincode::serialize(&tx.without_signature())?);
-        if !res {
-            return Err(anyhow!("signature verification failed"));
+        self.validate_tx_in(None, tx)
+    }
+
+    /// Checks `tx` as posted to `namespace`: the app namespace if `None`,
+    /// otherwise the channel namespace with that v0 id, where only messages
+    /// to the channels assigned to it are accepted.
+    pub fn validate_tx_in(&self, namespace: Option<NamespaceId>, tx: Transaction) -> Result<()> {
+        if !tx.verify_signature() {
+            return Err(reject(
+                RejectReason::Signature,
+                "signature verification failed",
+            ));
+        }
+        if let Some(nonce) = tx.nonce() {
+            if self
+                .last_nonce(namespace, &tx.pubkey())
+                .is_some_and(|last| nonce <= last)
+            {
+                return Err(reject(
+                    RejectReason::Conflict,
+                    "nonce must be greater than the signer's last one",
+                ));
+            }
+        }
+        self.check_tx_in(namespace, tx)
+    }
+
+    /// Everything `validate_tx_in` checks but the signature and nonce.
+    fn check_tx_in(&self, namespace: Option<NamespaceId>, tx: Transaction) -> Result<()> {
+        // Messages and registrations from before content limits applied are
+        // taken as they were posted.
+        let predates_limits = matches!(tx, Transaction::SendMessage(_) | Transaction::Register(_))
+            && self.block_height < self.chain.activations.content_limits;
+        if !predates_limits {
+            validation::validate_tx(&tx)
+                .map_err(|e| reject(RejectReason::Invalid, e.to_string()))?;
+        }
+        if namespace.is_some() && !matches!(tx, Transaction::SendMessage(_)) {
+            return Err(reject(
+                RejectReason::Namespace,
+                "only messages are accepted outside the app namespace",
+            ));
         }
         match tx {
             Transaction::SendMessage(contents) => {
-                if !self.users.contains_key(&contents.user) {
-                    return Err(anyhow!("user not yet registered"));
-                }
+                self.check_namespace(&contents.channel, namespace)?;
+                let author = self.message_author(&contents.user, &contents.channel)?;
+                self.check_quota(&author, namespace, 0)?;
             }
             Transaction::Register(contents) => {
                 if self.users.contains_key(&contents.user) {
-                    return Err(anyhow!("user already exists"));
+                    return Err(reject(RejectReason::Conflict, "user already exists"));
+                }
+                if self.successors.contains_key(&contents.user) {
+                    return Err(reject(
+                        RejectReason::Conflict,
+                        "key was rotated away from and can't be reused",
+                    ));
+                }
+                if self.sessions.contains_key(&contents.user) {
+                    return Err(reject(
+                        RejectReason::Conflict,
+                        "key is in use as a session key",
+                    ));
+                }
+            }
+            Transaction::UpdateProfile(UpdateProfile { user, .. })
+            | Transaction::RotateKey(RotateKey { user, .. })
+            | Transaction::SetRecoveryKeys(SetRecoveryKeys { user, .. })
+            | Transaction::CancelRecovery(CancelRecovery { user, .. })
+            | Transaction::AuthorizeSessionKey(AuthorizeSessionKey { user, .. })
+            | Transaction::RevokeSessionKey(RevokeSessionKey { user, .. })
+            | Transaction::AssignChannel(AssignChannel { user, .. })
+                if !self.users.contains_key(&user) =>
+            {
+                return Err(reject(
+                    RejectReason::Unauthorized,
+                    "user not yet registered",
+                ));
+            }
+            Transaction::UpdateProfile(_) | Transaction::SetRecoveryKeys(_) => {}
+            Transaction::RotateKey(rotate) => {
+                if !self.is_key_available(&rotate.new_key) {
+                    return Err(reject(RejectReason::Conflict, "new key is already in use"));
+                }
+            }
+            Transaction::RecoverAccount(recover) => {
+                let allowed = self
+                    .recovery_keys
+                    .get(&recover.account)
+                    .is_some_and(|keys| keys.contains(&recover.recovery_key));
+                if !allowed {
+                    return Err(reject(
+                        RejectReason::Unauthorized,
+                        "not a recovery key of this account",
+                    ));
+                }
+                if self.pending_recoveries.contains_key(&recover.account) {
+                    return Err(reject(
+                        RejectReason::Conflict,
+                        "a recovery of this account is already pending",
+                    ));
+                }
+                if !self.is_key_available(&recover.new_key) {
+                    return Err(reject(RejectReason::Conflict, "new key is already in use"));
+                }
+            }
+            Transaction::CancelRecovery(cancel) => {
+                if !self.pending_recoveries.contains_key(&cancel.user) {
+                    return Err(reject(
+                        RejectReason::Conflict,
+                        "no recovery of this account is pending",
+                    ));
+                }
+            }
+            Transaction::AuthorizeSessionKey(authorize) => {
+                // An account may re-authorize its own session key to change
+                // its scope or expiry.
+                let renewing = self
+                    .sessions
+                    .get(&authorize.session_key)
+                    .is_some_and(|session| session.parent == authorize.user);
+                if !renewing && !self.is_key_available(&authorize.session_key) {
+                    return Err(reject(
+                        RejectReason::Conflict,
+                        "session key is already in use",
+                    ));
+                }
+                if authorize.expires <= self.block_height {
+                    return Err(reject(
+                        RejectReason::Invalid,
+                        "session key would already be expired",
+                    ));
+                }
+                if authorize.expires - self.block_height > MAX_SESSION_LIFETIME {
+                    return Err(reject(
+                        RejectReason::Invalid,
+                        format!(
+                            "session keys may last at most {} blocks",
+                            MAX_SESSION_LIFETIME
+                        ),
+                    ));
+                }
+                let count = self
+                    .sessions
+                    .values()
+                    .filter(|session| session.parent == authorize.user)
+                    .count();
+                if !renewing && count >= MAX_SESSION_KEYS {
+                    return Err(reject(
+                        RejectReason::Quota,
+                        format!(
+                            "an account may have at most {} session keys",
+                            MAX_SESSION_KEYS
+                        ),
+                    ));
+                }
+            }
+            Transaction::RevokeSessionKey(revoke) => {
+                let owned = self
+                    .sessions
+                    .get(&revoke.session_key)
+                    .is_some_and(|session| session.parent == revoke.user);
+                if !owned {
+                    return Err(reject(
+                        RejectReason::Unauthorized,
+                        "not a session key of this account",
+                    ));
+                }
+            }
+            Transaction::AssignChannel(assign) => {
+                if !self.chain.directory_admins.contains(&assign.user) {
+                    return Err(reject(
+                        RejectReason::Unauthorized,
+                        "only directory admins can move channels",
+                    ));
+                }
+                if self.app_namespaces.contains(&assign.namespace) {
+                    return Err(reject(
+                        RejectReason::Invalid,
+                        "channels can't be moved to a namespace the app posts to",
+                    ));
+                }
+                if self.directory.contains_key(&assign.channel) {
+                    return Err(reject(
+                        RejectReason::Conflict,
+                        "channel is already in its own namespace",
+                    ));
+                }
+                if self.channels.contains_key(&assign.channel)
+                    || self.unindexed_channels.contains(&assign.channel)
+                {
+                    return Err(reject(
+                        RejectReason::Conflict,
+                        "only channels without messages can be moved",
+                    ));
                 }
             }
         }
         Ok(())
     }
 
+    /// Applies `tx` as posted to the app namespace.
     pub fn process_tx(&mut self, tx: Transaction) -> Result<()> {
-        self.validate_tx(tx.clone())?;
+        self.process_tx_in(None, tx)
+    }
+
+    /// Applies `tx` as posted to `namespace`, as `validate_tx_in` takes it.
+    pub fn process_tx_in(&mut self, namespace: Option<NamespaceId>, tx: Transaction) -> Result<()> {
+        self.validate_tx_in(namespace, tx.clone())?;
+        if let Some(nonce) = tx.nonce() {
+            self.set_nonce(namespace, &tx.pubkey(), nonce);
+        }
+        self.apply_tx_in(namespace, tx)
+    }
+
+    /// Applies a transaction from an unversioned blob, as `process_tx_in`
+    /// does a current one. Its signature is over its own encoding, so it is
+    /// checked before the transaction is upgraded, and it has no nonce.
+    pub fn process_legacy_tx_in(
+        &mut self,
+        namespace: Option<NamespaceId>,
+        tx: legacy::Transaction,
+    ) -> Result<()> {
+        if self.block_height >= self.chain.activations.versioned_batches {
+            return Err(reject(
+                RejectReason::Invalid,
+                "unversioned batches are no longer accepted",
+            ));
+        }
+        if !tx.verify_signature() {
+            return Err(reject(
+                RejectReason::Signature,
+                "signature verification failed",
+            ));
+        }
+        let tx = tx.upgrade();
+        self.check_tx_in(namespace, tx.clone())?;
+        self.apply_tx_in(namespace, tx)
+    }
 
+    /// Applies `tx`, once it has been checked.
+    fn apply_tx_in(&mut self, namespace: Option<NamespaceId>, tx: Transaction) -> Result<()> {
         match tx {
             Transaction::SendMessage(contents) => {
+                let author = self.message_author(&contents.user, &contents.channel)?;
+                if !self.index_filter.indexes(&contents.channel, &author) {
+                    self.record_message(&author, self.block_height, namespace);
+                    self.unindexed_channels.insert(contents.channel);
+                    return Ok(());
+                }
                 let messages = self.channels.get_mut(&contents.channel);
-                let user = self.users.get(&contents.user).unwrap();
+                let user = self.users.get(&author).unwrap();
 
+                self.search.add(
+                    &contents.channel,
+                    messages.as_ref().map_or(0, |msgs| msgs.len() as u64),
+                    user,
+                    self.block_height,
+                    &contents.contents,
+                );
                 let msg = Message {
                     user_id: user.clone(),
                     contents: contents.contents,
+                    parent: (author != contents.user).then(|| author.clone()),
+                    user: contents.user,
+                    nonce: contents.nonce,
+                    signature: contents.signature,
+                    height: self.block_height,
                 };
 
-                match messages {
-                    Some(msgs) => msgs.push(msg),
+                let index = match messages {
+                    Some(msgs) => {
+                        msgs.push(msg);
+                        msgs.len() - 1
+                    }
                     None => {
-                        self.channels.insert(contents.channel, vec![msg]);
+                        self.channels.insert(contents.channel.clone(), vec![msg]);
+                        0
                     }
-                }
+                };
+                self.commit_message(&contents.channel, index as u64);
+                self.record_message(&author, self.block_height, namespace);
+                self.user_messages
+                    .entry(author)
+                    .or_default()
+                    .push((contents.channel, index as u64));
             }
             Transaction::Register(contents) => {
                 if self.users.contains_key(&contents.user) {
-                    return Err(anyhow!("user already exists"));
+                    return Err(reject(RejectReason::Conflict, "user already exists"));
                 }
 
-                self.users.insert(contents.user, contents.id);
+                self.users.insert(contents.user.clone(), contents.id);
+                self.commit_user(&contents.user);
+            }
+            Transaction::UpdateProfile(update) => {
+                self.profiles.insert(update.user.clone(), update.profile);
+                self.commit_profile(&update.user);
+            }
+            // Rotating also cancels any pending recovery, since the owner
+            // evidently still has their key.
+            Transaction::RotateKey(rotate) => self.move_account(&rotate.user, &rotate.new_key),
+            Transaction::SetRecoveryKeys(set) => {
+                if set.recovery_keys.is_empty() {
+                    self.recovery_keys.remove(&set.user);
+                } else {
+                    self.recovery_keys
+                        .insert(set.user.clone(), set.recovery_keys);
+                }
+                self.commit_account(&set.user);
+            }
+            Transaction::RecoverAccount(recover) => {
+                self.pending_recoveries.insert(
+                    recover.account.clone(),
+                    PendingRecovery {
+                        recovery_key: recover.recovery_key,
+                        new_key: recover.new_key,
+                        started: self.block_height,
+                    },
+                );
+                self.commit_account(&recover.account);
+            }
+            Transaction::CancelRecovery(cancel) => {
+                self.pending_recoveries.remove(&cancel.user);
+                self.commit_account(&cancel.user);
+            }
+            Transaction::AuthorizeSessionKey(authorize) => {
+                self.sessions.insert(
+                    authorize.session_key.clone(),
+                    Session {
+                        parent: authorize.user,
+                        scope: authorize.scope,
+                        expires: authorize.expires,
+                    },
+                );
+                self.commit_session(&authorize.session_key);
+            }
+            Transaction::RevokeSessionKey(revoke) => {
+                self.sessions.remove(&revoke.session_key);
+                self.commit_session(&revoke.session_key);
+            }
+            Transaction::AssignChannel(assign) => {
+                self.channel_trees.entry(assign.namespace).or_default();
+                self.directory.insert(
+                    assign.channel.clone(),
+                    ChannelAssignment {
+                        namespace: assign.namespace,
+                        since: self.block_height + 1,
+                    },
+                );
+                self.commit_assignment(&assign.channel);
             }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Activations;
+    use crate::tx::{RecoverAccount, Register};
+    use ed25519_dalek::SigningKey;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn register(key: &SigningKey, id: &str) -> Transaction {
+        Transaction::Register(Register {
+            user: key.verifying_key().into(),
+            id: id.to_string(),
+            signature: Signature::new(Vec::new()),
+        })
+        .sign(key)
+        .unwrap()
+    }
+
+    /// A nonce greater than any returned before, for transactions that
+    /// may follow messages from the same key.
+    fn next_nonce() -> u64 {
+        static NONCE: AtomicU64 = AtomicU64::new(1);
+        NONCE.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn send(key: &SigningKey, channel: &str, text: &str) -> Transaction {
+        send_with_nonce(key, channel, text, next_nonce())
+    }
+
+    fn send_with_nonce(key: &SigningKey, channel: &str, text: &str, nonce: u64) -> Transaction {
+        Transaction::SendMessage(SendMessage {
+            user: key.verifying_key().into(),
+            contents: Content::Text(text.to_string()),
+            channel: channel.to_string(),
+            nonce,
+            signature: Signature::new(Vec::new()),
+        })
+        .sign(key)
+        .unwrap()
+    }
+
+    /// Applies each block of transactions at consecutive heights from 1,
+    /// panicking if any is rejected.
+    fn apply_blocks(state: &mut State, blocks: Vec<Vec<Transaction>>) {
+        let start = state.last_height().map_or(1, |height| height + 1);
+        for (height, txs) in (start..).zip(blocks) {
+            state.begin_block(height);
+            for tx in txs {
+                state.process_tx(tx).unwrap();
+            }
+            state.set_last_height(height);
+        }
+    }
+
+    fn sample_state() -> State {
+        let alice = signing_key(1);
+        let bob = signing_key(2);
+        let mut state = State::new();
+        apply_blocks(
+            &mut state,
+            vec![
+                vec![register(&alice, "alice"), register(&bob, "bob")],
+                vec![send(&alice, "general", "hi"), send(&bob, "random", "yo")],
+                vec![send(&bob, "general", "hello")],
+            ],
+        );
+        state
+    }
+
+    fn reject_reason(result: Result<()>) -> RejectReason {
+        result
+            .unwrap_err()
+            .downcast_ref::<Rejected>()
+            .unwrap()
+            .reason
+    }
+
+    /// Registers alice at height 1 and has her send `MESSAGE_QUOTA`
+    /// messages at height 2, leaving the state at height 3.
+    fn state_at_quota(chain: ChainConfig) -> State {
+        let alice = signing_key(1);
+        let mut state = State::new();
+        state.set_chain_config(chain);
+        apply_blocks(
+            &mut state,
+            vec![
+                vec![register(&alice, "alice")],
+                (0..MESSAGE_QUOTA)
+                    .map(|n| send(&alice, "general", &n.to_string()))
+                    .collect(),
+            ],
+        );
+        state.begin_block(3);
+        state
+    }
+
+    #[test]
+    fn enforces_message_quota() {
+        let alice = signing_key(1);
+        let mut state = state_at_quota(ChainConfig::default());
+        let over = send(&alice, "general", "one too many");
+        assert_eq!(
+            reject_reason(state.process_tx(over.clone())),
+            RejectReason::Quota
+        );
+
+        // Quotas are per account, not per channel.
+        assert_eq!(
+            reject_reason(state.validate_tx(send(&alice, "random", "hi"))),
+            RejectReason::Quota
+        );
+
+        state.set_last_height(3);
+        state.begin_block(2 + QUOTA_WINDOW);
+        state.process_tx(over).unwrap();
+    }
+
+    #[test]
+    fn replayed_messages_do_not_use_up_the_quota() {
+        let alice = signing_key(1);
+        let mut state = State::new();
+        apply_blocks(&mut state, vec![vec![register(&alice, "alice")]]);
+        state.begin_block(2);
+
+        let first = send(&alice, "general", "first");
+        state.process_tx(first.clone()).unwrap();
+        for _ in 0..MESSAGE_QUOTA {
+            assert_eq!(
+                reject_reason(state.process_tx(first.clone())),
+                RejectReason::Conflict
+            );
+        }
+        assert_eq!(state.message_count(), 1);
+
+        for n in 1..MESSAGE_QUOTA {
+            state
+                .process_tx(send(&alice, "general", &n.to_string()))
+                .unwrap();
+        }
+        assert_eq!(
+            reject_reason(state.process_tx(send(&alice, "general", "over"))),
+            RejectReason::Quota
+        );
+    }
+
+    #[test]
+    fn message_quota_applies_from_its_activation_height() {
+        let alice = signing_key(1);
+        let chain = ChainConfig {
+            activations: Activations {
+                message_quota: 4,
+                ..Activations::default()
+            },
+            ..ChainConfig::default()
+        };
+        let mut state = state_at_quota(chain);
+        state
+            .process_tx(send(&alice, "general", "allowed"))
+            .unwrap();
+        state.set_last_height(3);
+
+        state.begin_block(4);
+        assert_eq!(
+            reject_reason(state.validate_tx(send(&alice, "general", "rejected"))),
+            RejectReason::Quota
+        );
+    }
+
+    #[test]
+    fn pending_messages_count_towards_quota() {
+        let alice = signing_key(1);
+        let bob = signing_key(2);
+        let mut state = State::new();
+        apply_blocks(
+            &mut state,
+            vec![vec![register(&alice, "alice"), register(&bob, "bob")]],
+        );
+        let message = |key: &SigningKey, text: &str| match send(key, "general", text) {
+            Transaction::SendMessage(msg) => msg,
+            _ => unreachable!(),
+        };
+
+        let pending: Vec<_> = (1..MESSAGE_QUOTA)
+            .map(|n| message(&alice, &n.to_string()))
+            .chain([message(&bob, "not alice's")])
+            .collect();
+        state
+            .check_pending_quota(&message(&alice, "last"), &pending)
+            .unwrap();
+
+        let pending: Vec<_> = (0..MESSAGE_QUOTA)
+            .map(|n| message(&alice, &n.to_string()))
+            .collect();
+        assert_eq!(
+            reject_reason(state.check_pending_quota(&message(&alice, "over"), &pending)),
+            RejectReason::Quota
+        );
+    }
+
+    #[test]
+    fn content_limits_apply_from_their_activation_height() {
+        let alice = signing_key(1);
+        let mut state = State::new();
+        state.set_chain_config(ChainConfig {
+            activations: Activations {
+                content_limits: 3,
+                ..Activations::default()
+            },
+            ..ChainConfig::default()
+        });
+        apply_blocks(
+            &mut state,
+            vec![vec![
+                register(&alice, " alice "),
+                send(&alice, "General Chat", ""),
+            ]],
+        );
+        assert_eq!(state.message_count(), 1);
+
+        state.begin_block(3);
+        assert_eq!(
+            reject_reason(state.validate_tx(send(&alice, "General Chat", "hi"))),
+            RejectReason::Invalid
+        );
+        assert_eq!(
+            reject_reason(state.validate_tx(register(&signing_key(2), " bob "))),
+            RejectReason::Invalid
+        );
+    }
+
+    #[test]
+    fn channel_proofs_are_paged() {
+        let state = sample_state();
+        let first = state.prove_channel("general", 0, 1);
+        let second = state.prove_channel("general", 1, 1);
+        assert!(first.verify() && second.verify());
+        assert_eq!((first.count, first.messages.len()), (Some(2), 1));
+        assert_eq!(second.messages[0].index, 1);
+        assert_eq!(first.root, second.root);
+        assert!(state.prove_channel("general", 2, 1).verify());
+
+        // A page can't come back empty before the end, nor skip a message.
+        let mut empty = first.clone();
+        empty.messages.clear();
+        assert!(!empty.verify());
+        let mut skipped = second;
+        skipped.from = 0;
+        assert!(!skipped.verify());
+    }
+
+    #[test]
+    fn keeps_roots_within_retention() {
+        let mut state = sample_state();
+        let first = state.root_at(1);
+        assert!(first.is_some());
+        let last = ROOT_RETENTION;
+        for height in 4..=last {
+            state.begin_block(height);
+            state.set_last_height(height);
+        }
+        assert_eq!(state.root_at(1), first);
+        assert_eq!(state.root_at(last), Some(state.root()));
+
+        state.begin_block(last + 1);
+        state.set_last_height(last + 1);
+        assert_eq!(state.root_at(1), None);
+        assert!(state.root_at(2).is_some());
+    }
+
+    #[test]
+    fn snapshot_round_trips() {
+        let state = sample_state();
+        let snapshot = state.to_snapshot().unwrap();
+        let path = std::env::temp_dir().join(format!("grugchat-snapshot-{}", std::process::id()));
+        snapshot.write(&path).unwrap();
+        let snapshot = Snapshot::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let restored = State::from_snapshot(snapshot, state.root()).unwrap();
+        assert_eq!(restored.root(), state.root());
+        assert_eq!(restored.last_height(), Some(3));
+        assert_eq!(restored.user_count(), 2);
+        for channel in ["general", "random"] {
+            assert!(
+                restored.read_channel(channel.to_string())
+                    == state.read_channel(channel.to_string())
+            );
+        }
+
+        // The restored state carries on from the snapshot height like the
+        // original does.
+        let mut state = state;
+        let mut restored = restored;
+        let next = vec![vec![send(&signing_key(1), "random", "again")]];
+        apply_blocks(&mut state, next.clone());
+        apply_blocks(&mut restored, next);
+        assert_eq!(restored.root(), state.root());
+    }
+
+    #[test]
+    fn restored_states_search_newest_first() {
+        let alice = signing_key(1);
+        let mut state = State::new();
+        apply_blocks(
+            &mut state,
+            vec![
+                vec![register(&alice, "alice")],
+                vec![send(&alice, "general", "grug one")],
+                vec![send(&alice, "random", "grug two")],
+                vec![send(&alice, "general", "grug three")],
+            ],
+        );
+        let snapshot = state.to_snapshot().unwrap();
+        let restored = State::from_snapshot(snapshot, state.root()).unwrap();
+
+        let query = SearchQuery {
+            q: "grug".to_string(),
+            ..SearchQuery::default()
+        };
+        let hits = |state: &State| -> Vec<(String, u64)> {
+            state
+                .search(&query)
+                .unwrap()
+                .into_iter()
+                .map(|hit| (hit.channel, hit.index))
+                .collect()
+        };
+        let expected = vec![
+            ("general".to_string(), 1),
+            ("random".to_string(), 0),
+            ("general".to_string(), 0),
+        ];
+        assert_eq!(hits(&state), expected);
+        assert_eq!(hits(&restored), expected);
+    }
+
+    #[test]
+    fn snapshot_with_another_root_is_rejected() {
+        let state = sample_state();
+        let snapshot = state.to_snapshot().unwrap();
+        let err = State::from_snapshot(snapshot, [0; 32]).err().unwrap();
+        assert!(err.to_string().contains("does not match trusted root"));
+    }
+
+    #[test]
+    fn snapshot_with_tampered_contents_is_rejected() {
+        let state = sample_state();
+        let mut snapshot = state.to_snapshot().unwrap();
+        snapshot.channels[0].1[0].contents = Content::Text("forged".to_string());
+        let err = State::from_snapshot(snapshot, state.root()).err().unwrap();
+        assert!(err.to_string().contains("do not match its state root"));
+    }
+
+    fn set_recovery_keys(
+        key: &SigningKey,
+        recovery_keys: &[&SigningKey],
+        nonce: u64,
+    ) -> Transaction {
+        Transaction::SetRecoveryKeys(SetRecoveryKeys {
+            user: key.verifying_key().into(),
+            recovery_keys: recovery_keys
+                .iter()
+                .map(|key| key.verifying_key().into())
+                .collect(),
+            nonce,
+            signature: Signature::new(Vec::new()),
+        })
+        .sign(key)
+        .unwrap()
+    }
+
+    fn recover(
+        recovery_key: &SigningKey,
+        account: &SigningKey,
+        new_key: &SigningKey,
+        nonce: u64,
+    ) -> Transaction {
+        Transaction::RecoverAccount(RecoverAccount {
+            recovery_key: recovery_key.verifying_key().into(),
+            account: account.verifying_key().into(),
+            new_key: new_key.verifying_key().into(),
+            nonce,
+            signature: Signature::new(Vec::new()),
+        })
+        .sign(recovery_key)
+        .unwrap()
+    }
+
+    fn cancel_recovery(key: &SigningKey, nonce: u64) -> Transaction {
+        Transaction::CancelRecovery(CancelRecovery {
+            user: key.verifying_key().into(),
+            nonce,
+            signature: Signature::new(Vec::new()),
+        })
+        .sign(key)
+        .unwrap()
+    }
+
+    /// Skips ahead to `height` without applying anything at it.
+    fn skip_to(state: &mut State, height: u64) {
+        state.begin_block(height);
+        state.set_last_height(height);
+    }
+
+    #[test]
+    fn recovery_moves_the_account_once_due() {
+        let alice = signing_key(1);
+        let carol = signing_key(3);
+        let new_key = signing_key(4);
+        let account: PublicKey = alice.verifying_key().into();
+        let moved_to: PublicKey = new_key.verifying_key().into();
+        let mut state = State::new();
+        apply_blocks(
+            &mut state,
+            vec![
+                vec![register(&alice, "alice")],
+                vec![
+                    set_recovery_keys(&alice, &[&carol], 1),
+                    recover(&carol, &alice, &new_key, 1),
+                ],
+            ],
+        );
+
+        skip_to(&mut state, 2 + RECOVERY_DELAY - 1);
+        assert!(state
+            .user_info(&account)
+            .unwrap()
+            .pending_recovery
+            .is_some());
+        assert!(state.user_info(&moved_to).is_none());
+
+        skip_to(&mut state, 2 + RECOVERY_DELAY);
+        assert!(state.user_info(&account).is_none());
+        let info = state.user_info(&moved_to).unwrap();
+        assert_eq!(info.user_id, "alice");
+        assert_eq!(info.recovery_keys, vec![carol.verifying_key().into()]);
+        assert_eq!(
+            reject_reason(state.process_tx(register(&alice, "alice"))),
+            RejectReason::Conflict
+        );
+    }
+
+    #[test]
+    fn cancelled_recovery_cannot_be_replayed() {
+        let alice = signing_key(1);
+        let carol = signing_key(3);
+        let new_key = signing_key(4);
+        let account: PublicKey = alice.verifying_key().into();
+        let recovery = recover(&carol, &alice, &new_key, 1);
+        let cancel = cancel_recovery(&alice, 2);
+        let mut state = State::new();
+        apply_blocks(
+            &mut state,
+            vec![
+                vec![register(&alice, "alice")],
+                vec![set_recovery_keys(&alice, &[&carol], 1)],
+                vec![recovery.clone()],
+                vec![cancel.clone()],
+            ],
+        );
+
+        state.begin_block(5);
+        assert_eq!(
+            reject_reason(state.process_tx(recovery)),
+            RejectReason::Conflict
+        );
+        assert!(state
+            .user_info(&account)
+            .unwrap()
+            .pending_recovery
+            .is_none());
+
+        // A new recovery needs a new nonce, and can't be cancelled by
+        // replaying the old cancellation either.
+        state
+            .process_tx(recover(&carol, &alice, &new_key, 2))
+            .unwrap();
+        assert_eq!(
+            reject_reason(state.process_tx(cancel)),
+            RejectReason::Conflict
+        );
+        assert!(state
+            .user_info(&account)
+            .unwrap()
+            .pending_recovery
+            .is_some());
+    }
+
+    #[test]
+    fn removed_recovery_keys_cannot_be_restored_by_replay() {
+        let alice = signing_key(1);
+        let carol = signing_key(3);
+        let account: PublicKey = alice.verifying_key().into();
+        let old_keys = set_recovery_keys(&alice, &[&carol], 1);
+        let mut state = State::new();
+        apply_blocks(
+            &mut state,
+            vec![
+                vec![register(&alice, "alice")],
+                vec![old_keys.clone()],
+                vec![set_recovery_keys(&alice, &[], 2)],
+            ],
+        );
+
+        state.begin_block(4);
+        assert_eq!(
+            reject_reason(state.process_tx(old_keys)),
+            RejectReason::Conflict
+        );
+        assert!(state.user_info(&account).unwrap().recovery_keys.is_empty());
+    }
+
+    #[test]
+    fn nonces_are_kept_in_snapshots() {
+        let alice = signing_key(1);
+        let carol = signing_key(3);
+        let mut state = sample_state();
+        let keys = set_recovery_keys(&alice, &[&carol], next_nonce());
+        apply_blocks(&mut state, vec![vec![keys.clone()]]);
+
+        let snapshot = state.to_snapshot().unwrap();
+        let mut restored = State::from_snapshot(snapshot, state.root()).unwrap();
+        restored.begin_block(5);
+        assert_eq!(
+            reject_reason(restored.process_tx(keys)),
+            RejectReason::Conflict
+        );
+    }
+
+    fn authorize_session(
+        key: &SigningKey,
+        session: &SigningKey,
+        scope: SessionScope,
+        expires: u64,
+        nonce: u64,
+    ) -> Transaction {
+        Transaction::AuthorizeSessionKey(AuthorizeSessionKey {
+            user: key.verifying_key().into(),
+            session_key: session.verifying_key().into(),
+            scope,
+            expires,
+            nonce,
+            signature: Signature::new(Vec::new()),
+        })
+        .sign(key)
+        .unwrap()
+    }
+
+    fn revoke_session(key: &SigningKey, session: &SigningKey, nonce: u64) -> Transaction {
+        Transaction::RevokeSessionKey(RevokeSessionKey {
+            user: key.verifying_key().into(),
+            session_key: session.verifying_key().into(),
+            nonce,
+            signature: Signature::new(Vec::new()),
+        })
+        .sign(key)
+        .unwrap()
+    }
+
+    #[test]
+    fn session_keys_send_for_their_account_within_scope() {
+        let alice = signing_key(1);
+        let session = signing_key(5);
+        let mut state = State::new();
+        apply_blocks(
+            &mut state,
+            vec![
+                vec![register(&alice, "alice")],
+                vec![authorize_session(
+                    &alice,
+                    &session,
+                    SessionScope::Channels(vec!["general".to_string()]),
+                    10,
+                    1,
+                )],
+                vec![send(&session, "general", "from my phone")],
+            ],
+        );
+
+        let msg = &state.read_channel("general".to_string()).unwrap()[0];
+        assert_eq!(msg.user_id, "alice");
+        assert_eq!(msg.author(), &PublicKey::from(alice.verifying_key()));
+        assert!(msg.verify_signature("general"));
+
+        state.begin_block(4);
+        assert_eq!(
+            reject_reason(state.process_tx(send(&session, "random", "hi"))),
+            RejectReason::Unauthorized
+        );
+        skip_to(&mut state, 10);
+        assert_eq!(
+            reject_reason(state.process_tx(send(&session, "general", "expired"))),
+            RejectReason::Unauthorized
+        );
+    }
+
+    #[test]
+    fn revoked_session_keys_cannot_be_reauthorized_by_replay() {
+        let alice = signing_key(1);
+        let session = signing_key(5);
+        let authorize = authorize_session(&alice, &session, SessionScope::AnyChannel, 100, 1);
+        let mut state = State::new();
+        apply_blocks(
+            &mut state,
+            vec![
+                vec![register(&alice, "alice")],
+                vec![authorize.clone()],
+                vec![revoke_session(&alice, &session, 2)],
+            ],
+        );
+
+        state.begin_block(4);
+        assert_eq!(
+            reject_reason(state.process_tx(authorize)),
+            RejectReason::Conflict
+        );
+        assert_eq!(
+            reject_reason(state.process_tx(send(&session, "general", "still here?"))),
+            RejectReason::Unauthorized
+        );
+
+        // The account can still authorize it again itself.
+        state
+            .process_tx(authorize_session(
+                &alice,
+                &session,
+                SessionScope::AnyChannel,
+                100,
+                3,
+            ))
+            .unwrap();
+        state
+            .process_tx(send(&session, "general", "back again"))
+            .unwrap();
+    }
+
+    #[test]
+    fn rotating_the_account_key_drops_its_sessions() {
+        let alice = signing_key(1);
+        let new_key = signing_key(4);
+        let session = signing_key(5);
+        let mut state = State::new();
+        apply_blocks(
+            &mut state,
+            vec![
+                vec![register(&alice, "alice")],
+                vec![authorize_session(
+                    &alice,
+                    &session,
+                    SessionScope::AnyChannel,
+                    100,
+                    1,
+                )],
+                vec![Transaction::RotateKey(RotateKey {
+                    user: alice.verifying_key().into(),
+                    new_key: new_key.verifying_key().into(),
+                    nonce: 2,
+                    signature: Signature::new(Vec::new()),
+                })
+                .sign(&alice)
+                .unwrap()],
+            ],
+        );
+
+        state.begin_block(4);
+        assert_eq!(
+            reject_reason(state.process_tx(send(&session, "general", "hi"))),
+            RejectReason::Unauthorized
+        );
+        state.process_tx(send(&new_key, "general", "hi")).unwrap();
+    }
+
+    const CHANNEL_NAMESPACE: NamespaceId = *b"grugchat-c";
+
+    fn assign(key: &SigningKey, channel: &str, namespace: NamespaceId) -> Transaction {
+        Transaction::AssignChannel(AssignChannel {
+            user: key.verifying_key().into(),
+            channel: channel.to_string(),
+            namespace,
+            nonce: 1,
+            signature: Signature::new(Vec::new()),
+        })
+        .sign(key)
+        .unwrap()
+    }
+
+    /// A state with alice and bob registered at height 1 and alice as the
+    /// only directory admin, leaving it at height 2.
+    fn directory_state() -> State {
+        let alice = signing_key(1);
+        let bob = signing_key(2);
+        let mut state = State::new();
+        state.set_chain_config(ChainConfig {
+            directory_admins: vec![alice.verifying_key().into()],
+            ..ChainConfig::default()
+        });
+        state.set_app_namespaces(vec![*b"grugchat-a", *b"grugchat-b"]);
+        apply_blocks(
+            &mut state,
+            vec![vec![register(&alice, "alice"), register(&bob, "bob")]],
+        );
+        state.begin_block(2);
+        state
+    }
+
+    #[test]
+    fn only_directory_admins_move_channels() {
+        let mut state = directory_state();
+        assert_eq!(
+            reject_reason(state.process_tx(assign(&signing_key(2), "general", CHANNEL_NAMESPACE))),
+            RejectReason::Unauthorized
+        );
+        state
+            .process_tx(assign(&signing_key(1), "general", CHANNEL_NAMESPACE))
+            .unwrap();
+        assert_eq!(state.channel_namespace("general"), Some(CHANNEL_NAMESPACE));
+    }
+
+    #[test]
+    fn channels_are_not_moved_to_app_namespaces() {
+        let alice = signing_key(1);
+        let mut state = directory_state();
+        for namespace in [*b"grugchat-a", *b"grugchat-b"] {
+            assert_eq!(
+                reject_reason(state.process_tx(assign(&alice, "general", namespace))),
+                RejectReason::Invalid
+            );
+        }
+    }
+
+    #[test]
+    fn moved_channels_do_not_change_the_app_root() {
+        let alice = signing_key(1);
+        let bob = signing_key(2);
+        let mut follower = directory_state();
+        let mut other = directory_state();
+        let yo = send(&bob, "random", "yo");
+        for state in [&mut follower, &mut other] {
+            state
+                .process_tx(assign(&alice, "general", CHANNEL_NAMESPACE))
+                .unwrap();
+            state.set_last_height(2);
+            state.begin_block(3);
+            state.process_tx(yo.clone()).unwrap();
+        }
+        // Only the follower downloads the channel's namespace.
+        follower
+            .process_tx_in(Some(CHANNEL_NAMESPACE), send(&bob, "general", "hi"))
+            .unwrap();
+        follower.set_last_height(3);
+        other.set_last_height(3);
+
+        assert_eq!(follower.root_at(3), other.root_at(3));
+        assert_ne!(
+            follower.channel_root_at(3, CHANNEL_NAMESPACE),
+            other.channel_root_at(3, CHANNEL_NAMESPACE)
+        );
+        let proof = follower.prove_message("general", 0);
+        assert_eq!(proof.namespace, Some(CHANNEL_NAMESPACE));
+    }
+
+    #[test]
+    fn messages_cannot_be_replayed_in_any_namespace() {
+        let alice = signing_key(1);
+        let bob = signing_key(2);
+        let mut state = directory_state();
+        state
+            .process_tx(assign(&alice, "general", CHANNEL_NAMESPACE))
+            .unwrap();
+        state.set_last_height(2);
+        state.begin_block(3);
+
+        let hi = send(&bob, "random", "hi");
+        state.process_tx(hi.clone()).unwrap();
+        assert_eq!(reject_reason(state.process_tx(hi)), RejectReason::Conflict);
+
+        // Nonces are counted in each namespace on its own, as not every node
+        // sees every namespace.
+        let there = send_with_nonce(&bob, "general", "there", 1);
+        state
+            .process_tx_in(Some(CHANNEL_NAMESPACE), there.clone())
+            .unwrap();
+        assert_eq!(
+            reject_reason(state.process_tx_in(Some(CHANNEL_NAMESPACE), there.clone())),
+            RejectReason::Conflict
+        );
+        state.set_last_height(3);
+
+        let snapshot = state.to_snapshot().unwrap();
+        let mut restored = State::from_snapshot(snapshot, state.root()).unwrap();
+        restored.begin_block(4);
+        assert_eq!(
+            reject_reason(restored.process_tx_in(Some(CHANNEL_NAMESPACE), there)),
+            RejectReason::Conflict
+        );
+        assert_eq!(
+            restored.channel_root_at(3, CHANNEL_NAMESPACE),
+            state.channel_root_at(3, CHANNEL_NAMESPACE)
+        );
+    }
+}
@@ -1,10 +1,22 @@
-use ed25519_dalek::{Signature as Ed25519Signature, Verifier, VerifyingKey};
+use ed25519_dalek::{
+    ed25519::signature::Signer, Signature as Ed25519Signature, SigningKey, Verifier, VerifyingKey,
+};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Transaction {
     SendMessage(SendMessage),
     Register(Register),
+    UpdateProfile(UpdateProfile),
+    RotateKey(RotateKey),
+    SetRecoveryKeys(SetRecoveryKeys),
+    RecoverAccount(RecoverAccount),
+    CancelRecovery(CancelRecovery),
+    AuthorizeSessionKey(AuthorizeSessionKey),
+    RevokeSessionKey(RevokeSessionKey),
+    AssignChannel(AssignChannel),
 }
 
 impl Transaction {
@@ -12,6 +24,16 @@ impl Transaction {
         match self {
             Transaction::SendMessage(SendMessage { signature, .. }) => signature.clone(),
             Transaction::Register(Register { signature, .. }) => signature.clone(),
+            Transaction::UpdateProfile(UpdateProfile { signature, .. }) => signature.clone(),
+            Transaction::RotateKey(RotateKey { signature, .. }) => signature.clone(),
+            Transaction::SetRecoveryKeys(SetRecoveryKeys { signature, .. }) => signature.clone(),
+            Transaction::RecoverAccount(RecoverAccount { signature, .. }) => signature.clone(),
+            Transaction::CancelRecovery(CancelRecovery { signature, .. }) => signature.clone(),
+            Transaction::AuthorizeSessionKey(AuthorizeSessionKey { signature, .. }) => {
+                signature.clone()
+            }
+            Transaction::RevokeSessionKey(RevokeSessionKey { signature, .. }) => signature.clone(),
+            Transaction::AssignChannel(AssignChannel { signature, .. }) => signature.clone(),
         }
     }
 
@@ -19,6 +41,107 @@ impl Transaction {
         match self {
             Transaction::SendMessage(SendMessage { user, .. }) => user.clone(),
             Transaction::Register(Register { user, .. }) => user.clone(),
+            Transaction::UpdateProfile(UpdateProfile { user, .. }) => user.clone(),
+            Transaction::RotateKey(RotateKey { user, .. }) => user.clone(),
+            Transaction::SetRecoveryKeys(SetRecoveryKeys { user, .. }) => user.clone(),
+            Transaction::RecoverAccount(RecoverAccount { recovery_key, .. }) => {
+                recovery_key.clone()
+            }
+            Transaction::CancelRecovery(CancelRecovery { user, .. }) => user.clone(),
+            Transaction::AuthorizeSessionKey(AuthorizeSessionKey { user, .. }) => user.clone(),
+            Transaction::RevokeSessionKey(RevokeSessionKey { user, .. }) => user.clone(),
+            Transaction::AssignChannel(AssignChannel { user, .. }) => user.clone(),
+        }
+    }
+
+    /// Messages and account transactions carry a nonce, which must be
+    /// greater than the last one applied from the same signer in the same
+    /// namespace, so that none can be applied twice, nor replayed to use up
+    /// the sender's message quota. Clients use the time in milliseconds;
+    /// devices sending at the same time should each use a session key.
+    /// `Register` goes without, as a key only registers once.
+    pub fn nonce(&self) -> Option<u64> {
+        match self {
+            Transaction::Register(_) => None,
+            Transaction::SendMessage(SendMessage { nonce, .. })
+            | Transaction::UpdateProfile(UpdateProfile { nonce, .. })
+            | Transaction::RotateKey(RotateKey { nonce, .. })
+            | Transaction::SetRecoveryKeys(SetRecoveryKeys { nonce, .. })
+            | Transaction::RecoverAccount(RecoverAccount { nonce, .. })
+            | Transaction::CancelRecovery(CancelRecovery { nonce, .. })
+            | Transaction::AuthorizeSessionKey(AuthorizeSessionKey { nonce, .. })
+            | Transaction::RevokeSessionKey(RevokeSessionKey { nonce, .. })
+            | Transaction::AssignChannel(AssignChannel { nonce, .. }) => Some(*nonce),
+        }
+    }
+
+    /// The bytes a transaction's signature is over: its encoding with an
+    /// empty signature.
+    pub fn signing_payload(&self) -> Result<Vec<u8>, bincode::Error> {
+        bincode::serialize(&self.without_signature())
+    }
+
+    /// Identifies a transaction in logs: the hash of its signed encoding.
+    pub fn hash(&self) -> Result<[u8; 32], bincode::Error> {
+        Ok(Sha256::digest(bincode::serialize(self)?).into())
+    }
+
+    pub fn verify_signature(&self) -> bool {
+        match self.signing_payload() {
+            Ok(payload) => self.signature().verify(&self.pubkey(), &payload),
+            Err(_) => false,
+        }
+    }
+
+    pub fn sign(&self, key: &SigningKey) -> Result<Transaction, bincode::Error> {
+        let signature = key.sign(&self.signing_payload()?);
+        Ok(self.with_signature(signature.into()))
+    }
+
+    pub fn with_signature(&self, signature: Signature) -> Transaction {
+        match self.without_signature() {
+            Transaction::SendMessage(msg) => {
+                Transaction::SendMessage(SendMessage { signature, ..msg })
+            }
+            Transaction::Register(register) => Transaction::Register(Register {
+                signature,
+                ..register
+            }),
+            Transaction::UpdateProfile(update) => Transaction::UpdateProfile(UpdateProfile {
+                signature,
+                ..update
+            }),
+            Transaction::RotateKey(rotate) => Transaction::RotateKey(RotateKey {
+                signature,
+                ..rotate
+            }),
+            Transaction::SetRecoveryKeys(set) => {
+                Transaction::SetRecoveryKeys(SetRecoveryKeys { signature, ..set })
+            }
+            Transaction::RecoverAccount(recover) => Transaction::RecoverAccount(RecoverAccount {
+                signature,
+                ..recover
+            }),
+            Transaction::CancelRecovery(cancel) => Transaction::CancelRecovery(CancelRecovery {
+                signature,
+                ..cancel
+            }),
+            Transaction::AuthorizeSessionKey(authorize) => {
+                Transaction::AuthorizeSessionKey(AuthorizeSessionKey {
+                    signature,
+                    ..authorize
+                })
+            }
+            Transaction::RevokeSessionKey(revoke) => {
+                Transaction::RevokeSessionKey(RevokeSessionKey {
+                    signature,
+                    ..revoke
+                })
+            }
+            Transaction::AssignChannel(assign) => Transaction::AssignChannel(AssignChannel {
+                signature,
+                ..assign
+            }),
         }
     }
 
@@ -28,11 +151,13 @@ impl Transaction {
                 user,
                 contents,
                 channel,
+                nonce,
                 ..
             }) => Transaction::SendMessage(SendMessage {
                 user: user.clone(),
                 contents: contents.clone(),
                 channel: channel.clone(),
+                nonce: *nonce,
                 signature: Signature(Vec::new()),
             }),
             Transaction::Register(Register { user, id, .. }) => Transaction::Register(Register {
@@ -40,6 +165,98 @@ impl Transaction {
                 id: id.clone(),
                 signature: Signature(Vec::new()),
             }),
+            Transaction::UpdateProfile(UpdateProfile {
+                user,
+                profile,
+                nonce,
+                ..
+            }) => Transaction::UpdateProfile(UpdateProfile {
+                user: user.clone(),
+                profile: profile.clone(),
+                nonce: *nonce,
+                signature: Signature(Vec::new()),
+            }),
+            Transaction::RotateKey(RotateKey {
+                user,
+                new_key,
+                nonce,
+                ..
+            }) => Transaction::RotateKey(RotateKey {
+                user: user.clone(),
+                new_key: new_key.clone(),
+                nonce: *nonce,
+                signature: Signature(Vec::new()),
+            }),
+            Transaction::SetRecoveryKeys(SetRecoveryKeys {
+                user,
+                recovery_keys,
+                nonce,
+                ..
+            }) => Transaction::SetRecoveryKeys(SetRecoveryKeys {
+                user: user.clone(),
+                recovery_keys: recovery_keys.clone(),
+                nonce: *nonce,
+                signature: Signature(Vec::new()),
+            }),
+            Transaction::RecoverAccount(RecoverAccount {
+                recovery_key,
+                account,
+                new_key,
+                nonce,
+                ..
+            }) => Transaction::RecoverAccount(RecoverAccount {
+                recovery_key: recovery_key.clone(),
+                account: account.clone(),
+                new_key: new_key.clone(),
+                nonce: *nonce,
+                signature: Signature(Vec::new()),
+            }),
+            Transaction::CancelRecovery(CancelRecovery { user, nonce, .. }) => {
+                Transaction::CancelRecovery(CancelRecovery {
+                    user: user.clone(),
+                    nonce: *nonce,
+                    signature: Signature(Vec::new()),
+                })
+            }
+            Transaction::AuthorizeSessionKey(AuthorizeSessionKey {
+                user,
+                session_key,
+                scope,
+                expires,
+                nonce,
+                ..
+            }) => Transaction::AuthorizeSessionKey(AuthorizeSessionKey {
+                user: user.clone(),
+                session_key: session_key.clone(),
+                scope: scope.clone(),
+                expires: *expires,
+                nonce: *nonce,
+                signature: Signature(Vec::new()),
+            }),
+            Transaction::RevokeSessionKey(RevokeSessionKey {
+                user,
+                session_key,
+                nonce,
+                ..
+            }) => Transaction::RevokeSessionKey(RevokeSessionKey {
+                user: user.clone(),
+                session_key: session_key.clone(),
+                nonce: *nonce,
+                signature: Signature(Vec::new()),
+            }),
+            Transaction::AssignChannel(AssignChannel {
+                user,
+                channel,
+                namespace,
+                nonce,
+                ..
+            }) => Transaction::AssignChannel(AssignChannel {
+                user: user.clone(),
+                channel: channel.clone(),
+                namespace: *namespace,
+                nonce: *nonce,
+                signature: Signature(Vec::new()),
+            }),
         }
     }
 }
@@ -55,6 +272,13 @@ impl PublicKey {
     pub fn to_bytes(&self) -> Vec<u8> {
         self.0.clone()
     }
+
+    /// Whether this is a well-formed ed25519 key that signatures can be
+    /// checked against.
+    pub fn is_valid(&self) -> bool {
+        <[u8; 32]>::try_from(self.0.as_slice())
+            .is_ok_and(|bytes| VerifyingKey::from_bytes(&bytes).is_ok())
+    }
 }
 
 impl From<VerifyingKey> for PublicKey {
@@ -69,7 +293,7 @@ impl From<&PublicKey> for VerifyingKey {
     }
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct Signature(Vec<u8>);
 
 impl From<Ed25519Signature> for Signature {
@@ -89,9 +313,19 @@ impl Signature {
         Signature(bytes)
     }
 
+    /// Returns false rather than panicking if the key or signature are
+    /// malformed, since both come from untrusted input.
     pub fn verify(&self, pk: &PublicKey, msg: &[u8]) -> bool {
-        let vk: VerifyingKey = pk.into();
-        vk.verify(msg, &self.into()).is_ok()
+        let Ok(pk_bytes) = pk.to_bytes().try_into() else {
+            return false;
+        };
+        let Ok(vk) = VerifyingKey::from_bytes(&pk_bytes) else {
+            return false;
+        };
+        let Ok(sig) = Ed25519Signature::from_slice(&self.0) else {
+            return false;
+        };
+        vk.verify(msg, &sig).is_ok()
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -102,8 +336,9 @@ impl Signature {
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct SendMessage {
     pub user: PublicKey,
-    pub contents: String,
+    pub contents: Content,
     pub channel: String,
+    pub nonce: u64,
     pub signature: Signature,
 }
 
@@ -113,3 +348,196 @@ pub struct Register {
     pub id: String,
     pub signature: Signature,
 }
+
+/// Replaces the sender's profile as a whole.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct UpdateProfile {
+    pub user: PublicKey,
+    pub profile: Profile,
+    pub nonce: u64,
+    pub signature: Signature,
+}
+
+/// Moves an account, along with its id, profile and message history, from
+/// `user` to `new_key`. Signed by the old key.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RotateKey {
+    pub user: PublicKey,
+    pub new_key: PublicKey,
+    pub nonce: u64,
+    pub signature: Signature,
+}
+
+/// Replaces the keys allowed to start a recovery of `user`'s account. An
+/// empty list disables recovery.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SetRecoveryKeys {
+    pub user: PublicKey,
+    pub recovery_keys: Vec<PublicKey>,
+    pub nonce: u64,
+    pub signature: Signature,
+}
+
+/// Starts moving `account` to `new_key`, signed by one of its recovery
+/// keys. The move only happens once the recovery delay has passed, which
+/// gives the owner time to cancel it.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RecoverAccount {
+    pub recovery_key: PublicKey,
+    pub account: PublicKey,
+    pub new_key: PublicKey,
+    pub nonce: u64,
+    pub signature: Signature,
+}
+
+/// Cancels a pending recovery of the signer's account.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct CancelRecovery {
+    pub user: PublicKey,
+    pub nonce: u64,
+    pub signature: Signature,
+}
+
+/// Lets `session_key` send messages on `user`'s behalf, within `scope`,
+/// until DA height `expires`. Signed by the account's own key.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AuthorizeSessionKey {
+    pub user: PublicKey,
+    pub session_key: PublicKey,
+    pub scope: SessionScope,
+    pub expires: u64,
+    pub nonce: u64,
+    pub signature: Signature,
+}
+
+/// What a session key may do. Session keys can only ever send messages.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum SessionScope {
+    AnyChannel,
+    Channels(Vec<String>),
+}
+
+impl SessionScope {
+    pub fn allows(&self, channel: &str) -> bool {
+        match self {
+            SessionScope::AnyChannel => true,
+            SessionScope::Channels(channels) => channels.iter().any(|c| c == channel),
+        }
+    }
+}
+
+/// Revokes one of the signer's session keys before it expires.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RevokeSessionKey {
+    pub user: PublicKey,
+    pub session_key: PublicKey,
+    pub nonce: u64,
+    pub signature: Signature,
+}
+
+/// The version 0 id of a Celestia namespace, the part after its zero
+/// prefix.
+pub type NamespaceId = [u8; 10];
+
+/// Moves `channel` out of the app namespace into the namespace with v0 id
+/// `namespace`, so only nodes that follow the channel download its
+/// messages. Several channels may share a namespace. Only a channel without
+/// messages can be moved, and only once, by one of the chain's
+/// `directory_admins`; its messages are accepted in the new namespace from
+/// the next height on. Neither the app's own namespaces nor those Celestia
+/// reserves can be assigned.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AssignChannel {
+    pub user: PublicKey,
+    pub channel: String,
+    pub namespace: NamespaceId,
+    pub nonce: u64,
+    pub signature: Signature,
+}
+
+/// Optional details a registered user can publish about themselves. The
+/// avatar is an image posted the same way as message attachments.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Default, Debug)]
+pub struct Profile {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar: Option<Attachment>,
+}
+
+/// What a message carries. Attachments are too large to go in a
+/// transaction, so they are posted as their own blob and referenced here.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum Content {
+    Text(String),
+    Markdown(String),
+    Attachment(Attachment),
+    Link(LinkPreview),
+}
+
+impl fmt::Display for Content {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Content::Text(text) | Content::Markdown(text) => write!(f, "{text}"),
+            Content::Attachment(attachment) => write!(f, "{attachment}"),
+            Content::Link(link) => write!(f, "{link}"),
+        }
+    }
+}
+
+/// A file posted as a separate blob in the network's attachment namespace,
+/// which syncing nodes don't download. Older attachments were posted in the
+/// app namespace. `hash` is the SHA-256 of the file, so readers can check
+/// what the DA layer returns; `height` and `commitment` locate the blob.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct Attachment {
+    pub name: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub hash: [u8; 32],
+    pub height: u64,
+    pub commitment: [u8; 32],
+}
+
+impl fmt::Display for Attachment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[attachment] {} ({}, {} bytes) at {}/{}",
+            self.name,
+            self.mime_type,
+            self.size,
+            self.height,
+            hex::encode(self.commitment)
+        )
+    }
+}
+
+/// What the uploader of an attachment signs, and what the upload's
+/// proof-of-work stamp is over: a domain tag, so the signature can't be
+/// passed off as one over a transaction, and the SHA-256 of the file.
+pub fn attachment_payload(data: &[u8]) -> Vec<u8> {
+    let mut payload = b"grugchat-attachment".to_vec();
+    payload.extend_from_slice(&Sha256::digest(data));
+    payload
+}
+
+/// Preview metadata for a link, as chosen by the sender.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+}
+
+impl fmt::Display for LinkPreview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[link] {}", self.url)?;
+        if let Some(title) = &self.title {
+            write!(f, " \"{title}\"")?;
+        }
+        if let Some(description) = &self.description {
+            write!(f, " - {description}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use crate::fullnode::namespace_hex;
+use crate::state::ChannelAssignment;
+use crate::tx::{PublicKey, Transaction};
+use crate::webserver::{
+    AttachmentUpload, AttachmentUploader, SubmitTransactionRequest, CLIENT_IP_HEADER,
+    NAMESPACE_HEADER,
+};
+use axum::http::StatusCode;
+use celestia_types::nmt::Namespace;
+use reqwest::Client;
+use std::net::IpAddr;
+
+/// A node that posts to the DA layer, which nodes that don't post forward
+/// writes to. Its responses are passed back as they are, so clients see the
+/// same errors they would talking to it directly. Writes carry the client
+/// they are forwarded for, which the upstream limits and asks stamps of in
+/// place of the forwarding node if it lists that node in `trusted_proxies`.
+/// Gateways, which keep no state, also look up accounts and the channel
+/// directory on one. Requests name the namespace they are for, in case the
+/// upstream serves several.
+pub struct Upstream {
+    url: String,
+    namespace: String,
+    client: Client,
+}
+
+/// Maps an upstream response onto the error a handler returns, keeping the
+/// upstream's status and message.
+async fn check(response: reqwest::Response) -> Result<reqwest::Response, (StatusCode, String)> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status =
+        StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let message = response.text().await.unwrap_or_default();
+    Err((status, message))
+}
+
+fn unreachable(e: reqwest::Error) -> (StatusCode, String) {
+    (
+        StatusCode::BAD_GATEWAY,
+        format!("upstream node is unreachable: {e}"),
+    )
+}
+
+impl Upstream {
+    pub fn new(url: &str, namespace: Namespace) -> Self {
+        Upstream {
+            url: url.trim_end_matches('/').to_string(),
+            namespace: namespace_hex(&namespace),
+            client: Client::new(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn submit_transaction(
+        &self,
+        tx: &Transaction,
+        pow_nonce: Option<u64>,
+        client_ip: IpAddr,
+    ) -> Result<(), (StatusCode, String)> {
+        let tx_bytes = bincode::serialize(tx)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        let response = self
+            .client
+            .post(format!("{}/tx", self.url))
+            .header(NAMESPACE_HEADER, &self.namespace)
+            .header(CLIENT_IP_HEADER, client_ip.to_string())
+            .json(&SubmitTransactionRequest {
+                tx: hex::encode(tx_bytes),
+                pow_nonce,
+            })
+            .send()
+            .await
+            .map_err(unreachable)?;
+        check(response).await?;
+        Ok(())
+    }
+
+    pub async fn upload_attachment(
+        &self,
+        uploader: &AttachmentUploader,
+        client_ip: IpAddr,
+        data: Vec<u8>,
+    ) -> Result<AttachmentUpload, (StatusCode, String)> {
+        let mut request = self
+            .client
+            .post(format!("{}/attachments", self.url))
+            .header(NAMESPACE_HEADER, &self.namespace)
+            .header(CLIENT_IP_HEADER, client_ip.to_string());
+        for (name, value) in uploader.headers() {
+            request = request.header(name, value);
+        }
+        let response = request.body(data).send().await.map_err(unreachable)?;
+        check(response)
+            .await?
+            .json()
+            .await
+            .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))
+    }
+
+    /// The channels moved to their own namespace, as the upstream has them.
+    pub async fn directory(
+        &self,
+    ) -> Result<Vec<(String, ChannelAssignment)>, (StatusCode, String)> {
+        let response = self
+            .client
+            .get(format!("{}/directory", self.url))
+            .header(NAMESPACE_HEADER, &self.namespace)
+            .send()
+            .await
+            .map_err(unreachable)?;
+        check(response)
+            .await?
+            .json()
+            .await
+            .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))
+    }
+
+    /// Whether `user` is a registered account on the upstream.
+    pub async fn is_registered(&self, user: &PublicKey) -> Result<bool, (StatusCode, String)> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/users/{}",
+                self.url,
+                hex::encode(user.to_bytes())
+            ))
+            .header(NAMESPACE_HEADER, &self.namespace)
+            .send()
+            .await
+            .map_err(unreachable)?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        check(response).await?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,456 @@
+use crate::tx::{
+    Attachment, Content, LinkPreview, NamespaceId, Profile, PublicKey, SessionScope, Transaction,
+};
+use anyhow::{bail, Result};
+use celestia_types::nmt::Namespace;
+use unicode_normalization::is_nfc;
+
+/// Limits on transaction fields, in bytes. These are consensus rules, so
+/// every node must use the same values.
+pub const MAX_CONTENTS_LEN: usize = 4096;
+pub const MAX_CHANNEL_LEN: usize = 64;
+pub const MAX_USER_ID_LEN: usize = 32;
+pub const MAX_ATTACHMENT_SIZE: u64 = 1024 * 1024;
+pub const MAX_FILE_NAME_LEN: usize = 255;
+pub const MAX_MIME_TYPE_LEN: usize = 127;
+pub const MAX_URL_LEN: usize = 2048;
+pub const MAX_LINK_TITLE_LEN: usize = 256;
+pub const MAX_DISPLAY_NAME_LEN: usize = 64;
+pub const MAX_BIO_LEN: usize = 1024;
+pub const MAX_RECOVERY_KEYS: usize = 5;
+pub const MAX_SESSION_CHANNELS: usize = 16;
+
+/// Checks that `text` is non-empty, within `max_len`, NFC-normalized and
+/// free of control characters other than those in `allowed_controls`.
+fn validate_text(field: &str, text: &str, max_len: usize, allowed_controls: &[char]) -> Result<()> {
+    if text.is_empty() {
+        bail!("{field} must not be empty");
+    }
+    if text.len() > max_len {
+        bail!(
+            "{field} must be at most {max_len} bytes, got {}",
+            text.len()
+        );
+    }
+    if !is_nfc(text) {
+        bail!("{field} must be NFC-normalized");
+    }
+    if let Some(c) = text
+        .chars()
+        .find(|c| c.is_control() && !allowed_controls.contains(c))
+    {
+        bail!("{field} contains forbidden control character {c:?}");
+    }
+    Ok(())
+}
+
+/// Channel names are lowercase ASCII letters, digits, `-` and `_`, starting
+/// with a letter or digit.
+pub fn validate_channel(channel: &str) -> Result<()> {
+    if channel.is_empty() {
+        bail!("channel name must not be empty");
+    }
+    if channel.len() > MAX_CHANNEL_LEN {
+        bail!("channel name must be at most {MAX_CHANNEL_LEN} bytes");
+    }
+    if !channel.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit()) {
+        bail!("channel name must start with a lowercase letter or digit");
+    }
+    if let Some(c) = channel
+        .chars()
+        .find(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '-' || *c == '_'))
+    {
+        bail!("channel name contains invalid character {c:?}");
+    }
+    Ok(())
+}
+
+pub fn validate_user_id(id: &str) -> Result<()> {
+    validate_text("user id", id, MAX_USER_ID_LEN, &[])?;
+    if id.trim() != id {
+        bail!("user id must not start or end with whitespace");
+    }
+    Ok(())
+}
+
+pub fn validate_contents(contents: &str) -> Result<()> {
+    validate_text("message", contents, MAX_CONTENTS_LEN, &['\n', '\t'])
+}
+
+/// MIME types are `type/subtype` in printable ASCII without spaces, with
+/// any parameters left off.
+pub fn validate_mime_type(mime_type: &str) -> Result<()> {
+    if mime_type.len() > MAX_MIME_TYPE_LEN {
+        bail!("MIME type must be at most {MAX_MIME_TYPE_LEN} bytes");
+    }
+    let valid_part = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?=".contains(c))
+    };
+    match mime_type.split_once('/') {
+        Some((kind, subtype)) if valid_part(kind) && valid_part(subtype) => Ok(()),
+        _ => bail!("invalid MIME type {mime_type:?}"),
+    }
+}
+
+pub fn validate_attachment(attachment: &Attachment) -> Result<()> {
+    validate_text("file name", &attachment.name, MAX_FILE_NAME_LEN, &[])?;
+    if attachment.name.contains(['/', '\\']) {
+        bail!("file name must not contain path separators");
+    }
+    validate_mime_type(&attachment.mime_type)?;
+    if attachment.size == 0 || attachment.size > MAX_ATTACHMENT_SIZE {
+        bail!("attachment size must be between 1 and {MAX_ATTACHMENT_SIZE} bytes");
+    }
+    Ok(())
+}
+
+/// Only absolute http(s) URLs are accepted, so clients never get handed
+/// `javascript:` or `file:` links.
+pub fn validate_link(link: &LinkPreview) -> Result<()> {
+    validate_text("url", &link.url, MAX_URL_LEN, &[])?;
+    let Some(rest) = link
+        .url
+        .strip_prefix("https://")
+        .or_else(|| link.url.strip_prefix("http://"))
+    else {
+        bail!("url must start with http:// or https://");
+    };
+    if rest.is_empty() || rest.starts_with('/') {
+        bail!("url must have a host");
+    }
+    if link.url.contains(char::is_whitespace) {
+        bail!("url must not contain whitespace");
+    }
+    if let Some(title) = &link.title {
+        validate_text("link title", title, MAX_LINK_TITLE_LEN, &[])?;
+    }
+    if let Some(description) = &link.description {
+        validate_text("link description", description, MAX_CONTENTS_LEN, &['\n'])?;
+    }
+    Ok(())
+}
+
+pub fn validate_content(content: &Content) -> Result<()> {
+    match content {
+        Content::Text(text) | Content::Markdown(text) => validate_contents(text),
+        Content::Attachment(attachment) => validate_attachment(attachment),
+        Content::Link(link) => validate_link(link),
+    }
+}
+
+pub fn validate_profile(profile: &Profile) -> Result<()> {
+    if let Some(name) = &profile.display_name {
+        validate_text("display name", name, MAX_DISPLAY_NAME_LEN, &[])?;
+    }
+    if let Some(bio) = &profile.bio {
+        validate_text("bio", bio, MAX_BIO_LEN, &['\n'])?;
+    }
+    if let Some(avatar) = &profile.avatar {
+        validate_attachment(avatar)?;
+        if !avatar.mime_type.starts_with("image/") {
+            bail!("avatar must be an image");
+        }
+    }
+    Ok(())
+}
+
+/// Keys that accounts are moved to or recovered by must be usable, or the
+/// account would be lost.
+fn validate_key(field: &str, key: &PublicKey) -> Result<()> {
+    if !key.is_valid() {
+        bail!("{field} is not a valid ed25519 public key");
+    }
+    Ok(())
+}
+
+pub fn validate_recovery_keys(user: &PublicKey, keys: &[PublicKey]) -> Result<()> {
+    if keys.len() > MAX_RECOVERY_KEYS {
+        bail!("at most {MAX_RECOVERY_KEYS} recovery keys are allowed");
+    }
+    for (i, key) in keys.iter().enumerate() {
+        validate_key("recovery key", key)?;
+        if key == user {
+            bail!("an account can't be its own recovery key");
+        }
+        if keys[..i].contains(key) {
+            bail!("duplicate recovery key");
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_session_scope(scope: &SessionScope) -> Result<()> {
+    let SessionScope::Channels(channels) = scope else {
+        return Ok(());
+    };
+    if channels.is_empty() {
+        bail!("a session key must be allowed at least one channel");
+    }
+    if channels.len() > MAX_SESSION_CHANNELS {
+        bail!("a session key may be limited to at most {MAX_SESSION_CHANNELS} channels");
+    }
+    for (i, channel) in channels.iter().enumerate() {
+        validate_channel(channel)?;
+        if channels[..i].contains(channel) {
+            bail!("duplicate channel {channel:?} in session scope");
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a channel can be moved to the namespace with v0 id
+/// `namespace`, which must not be one Celestia reserves for itself.
+pub fn validate_channel_namespace(namespace: NamespaceId) -> Result<()> {
+    if Namespace::const_v0(namespace) <= Namespace::MAX_PRIMARY_RESERVED {
+        bail!("namespace {} is reserved", hex::encode(namespace));
+    }
+    Ok(())
+}
+
+/// Checks the fields of `tx` against the content rules. This doesn't look
+/// at signatures or state.
+pub fn validate_tx(tx: &Transaction) -> Result<()> {
+    match tx {
+        Transaction::SendMessage(msg) => {
+            validate_channel(&msg.channel)?;
+            validate_content(&msg.contents)
+        }
+        Transaction::Register(register) => validate_user_id(&register.id),
+        Transaction::UpdateProfile(update) => validate_profile(&update.profile),
+        Transaction::RotateKey(rotate) => {
+            validate_key("new key", &rotate.new_key)?;
+            if rotate.new_key == rotate.user {
+                bail!("new key must differ from the current key");
+            }
+            Ok(())
+        }
+        Transaction::SetRecoveryKeys(set) => validate_recovery_keys(&set.user, &set.recovery_keys),
+        Transaction::RecoverAccount(recover) => {
+            validate_key("new key", &recover.new_key)?;
+            if recover.new_key == recover.account {
+                bail!("new key must differ from the account's current key");
+            }
+            Ok(())
+        }
+        Transaction::CancelRecovery(_) => Ok(()),
+        Transaction::AuthorizeSessionKey(authorize) => {
+            validate_key("session key", &authorize.session_key)?;
+            if authorize.session_key == authorize.user {
+                bail!("an account can't be its own session key");
+            }
+            validate_session_scope(&authorize.scope)
+        }
+        Transaction::RevokeSessionKey(_) => Ok(()),
+        Transaction::AssignChannel(assign) => {
+            validate_channel(&assign.channel)?;
+            validate_channel_namespace(assign.namespace)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx::{Register, SendMessage, Signature};
+
+    fn key(seed: u8) -> PublicKey {
+        ed25519_dalek::SigningKey::from_bytes(&[seed; 32])
+            .verifying_key()
+            .into()
+    }
+
+    fn message(channel: &str, contents: Content) -> Transaction {
+        Transaction::SendMessage(SendMessage {
+            user: key(1),
+            contents,
+            channel: channel.to_string(),
+            nonce: 1,
+            signature: Signature::new(Vec::new()),
+        })
+    }
+
+    fn attachment(name: &str, mime_type: &str, size: u64) -> Attachment {
+        Attachment {
+            name: name.to_string(),
+            mime_type: mime_type.to_string(),
+            size,
+            hash: [0; 32],
+            height: 1,
+            commitment: [0; 32],
+        }
+    }
+
+    #[test]
+    fn channel_names() {
+        for valid in [
+            "general",
+            "0day",
+            "rust-lang",
+            "a_b",
+            &"x".repeat(MAX_CHANNEL_LEN),
+        ] {
+            assert!(validate_channel(valid).is_ok(), "{valid:?}");
+        }
+        for invalid in [
+            "",
+            "General",
+            "-general",
+            "_general",
+            "gen eral",
+            "général",
+            "gen/eral",
+            &"x".repeat(MAX_CHANNEL_LEN + 1),
+        ] {
+            assert!(validate_channel(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn user_ids() {
+        assert!(validate_user_id("alice").is_ok());
+        assert!(validate_user_id("Zoë Smith").is_ok());
+        assert!(validate_user_id(&"a".repeat(MAX_USER_ID_LEN)).is_ok());
+        for invalid in [
+            "",
+            " alice",
+            "alice ",
+            "ali\nce",
+            "ali\u{7}ce",
+            // "Zoë" with a combining diaeresis isn't NFC.
+            "Zoe\u{308}",
+            &"a".repeat(MAX_USER_ID_LEN + 1),
+        ] {
+            assert!(validate_user_id(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn message_contents() {
+        assert!(validate_contents("hello\n\tworld").is_ok());
+        assert!(validate_contents(&"a".repeat(MAX_CONTENTS_LEN)).is_ok());
+        assert!(validate_contents("").is_err());
+        assert!(validate_contents("carriage\rreturn").is_err());
+        assert!(validate_contents("null\0byte").is_err());
+        assert!(validate_contents("Zoe\u{308}").is_err());
+        assert!(validate_contents(&"a".repeat(MAX_CONTENTS_LEN + 1)).is_err());
+        // The limit is in bytes, not characters.
+        assert!(validate_contents(&"é".repeat(MAX_CONTENTS_LEN / 2 + 1)).is_err());
+    }
+
+    #[test]
+    fn mime_types() {
+        for valid in ["text/plain", "image/svg+xml", "application/vnd.ms-excel"] {
+            assert!(validate_mime_type(valid).is_ok(), "{valid:?}");
+        }
+        for invalid in [
+            "text",
+            "text/",
+            "/plain",
+            "text/plain; charset=utf-8",
+            "a/b/c",
+        ] {
+            assert!(validate_mime_type(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn attachments() {
+        assert!(validate_attachment(&attachment("cat.png", "image/png", 10)).is_ok());
+        assert!(validate_attachment(&attachment("../cat.png", "image/png", 10)).is_err());
+        assert!(validate_attachment(&attachment("cat.png", "image", 10)).is_err());
+        assert!(validate_attachment(&attachment("cat.png", "image/png", 0)).is_err());
+        assert!(
+            validate_attachment(&attachment("cat.png", "image/png", MAX_ATTACHMENT_SIZE + 1))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn links() {
+        let link = |url: &str| LinkPreview {
+            url: url.to_string(),
+            title: None,
+            description: None,
+        };
+        assert!(validate_link(&link("https://example.com/page")).is_ok());
+        assert!(validate_link(&link("http://example.com")).is_ok());
+        for invalid in [
+            "javascript:alert(1)",
+            "file:///etc/passwd",
+            "https://",
+            "https:///path",
+            "https://exa mple.com",
+        ] {
+            assert!(validate_link(&link(invalid)).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn profiles() {
+        let mut profile = Profile {
+            display_name: Some("Alice".to_string()),
+            bio: Some("Line one\nline two".to_string()),
+            avatar: Some(attachment("me.png", "image/png", 100)),
+        };
+        assert!(validate_profile(&profile).is_ok());
+        profile.avatar = Some(attachment("me.pdf", "application/pdf", 100));
+        assert!(validate_profile(&profile).is_err());
+        profile.avatar = None;
+        profile.display_name = Some("x".repeat(MAX_DISPLAY_NAME_LEN + 1));
+        assert!(validate_profile(&profile).is_err());
+    }
+
+    #[test]
+    fn recovery_keys() {
+        let user = key(1);
+        assert!(validate_recovery_keys(&user, &[]).is_ok());
+        assert!(validate_recovery_keys(&user, &[key(2), key(3)]).is_ok());
+        assert!(validate_recovery_keys(&user, &[key(2), key(2)]).is_err());
+        assert!(validate_recovery_keys(&user, std::slice::from_ref(&user)).is_err());
+        assert!(validate_recovery_keys(&user, &[PublicKey::new(vec![1, 2, 3])]).is_err());
+        let too_many: Vec<_> = (2..=MAX_RECOVERY_KEYS as u8 + 2).map(key).collect();
+        assert!(validate_recovery_keys(&user, &too_many).is_err());
+    }
+
+    #[test]
+    fn session_scopes() {
+        let channels = |names: &[&str]| {
+            SessionScope::Channels(names.iter().map(|name| name.to_string()).collect())
+        };
+        assert!(validate_session_scope(&SessionScope::AnyChannel).is_ok());
+        assert!(validate_session_scope(&channels(&["general", "random"])).is_ok());
+        assert!(validate_session_scope(&channels(&[])).is_err());
+        assert!(validate_session_scope(&channels(&["general", "general"])).is_err());
+        assert!(validate_session_scope(&channels(&["General"])).is_err());
+    }
+
+    #[test]
+    fn transactions() {
+        assert!(validate_tx(&message("general", Content::Text("hi".to_string()))).is_ok());
+        assert!(validate_tx(&message("General", Content::Text("hi".to_string()))).is_err());
+        assert!(validate_tx(&message("general", Content::Markdown(String::new()))).is_err());
+
+        let register = |id: &str| {
+            Transaction::Register(Register {
+                user: key(1),
+                id: id.to_string(),
+                signature: Signature::new(Vec::new()),
+            })
+        };
+        assert!(validate_tx(&register("alice")).is_ok());
+        assert!(validate_tx(&register("")).is_err());
+    }
+
+    #[test]
+    fn channel_namespaces() {
+        assert!(validate_channel_namespace(*b"grugchat-1").is_ok());
+        assert!(validate_channel_namespace([0, 0, 0, 0, 0, 0, 0, 0, 1, 0]).is_ok());
+        // Transactions, blob payments and padding.
+        assert!(validate_channel_namespace([0; 10]).is_err());
+        assert!(validate_channel_namespace([0, 0, 0, 0, 0, 0, 0, 0, 0, 1]).is_err());
+        assert!(validate_channel_namespace([0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff]).is_err());
+    }
+}
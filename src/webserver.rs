@@ -1,23 +1,353 @@
-use crate::fullnode::FullNode;
-use crate::state::Message;
-use crate::tx::{PublicKey, Register, SendMessage, Signature, Transaction};
-use axum::{extract::State as AxumState, http::StatusCode, Json};
-use serde::Deserialize;
-use std::sync::Arc;
+use crate::fullnode::{FullNode, NodeStatus};
+use crate::gateway::{Gateway, GatewayStatus};
+use crate::merkle::Hash;
+use crate::metrics::Metrics;
+use crate::ratelimit::{verify_stamp, RateLimiter};
+use crate::search::SearchQuery;
+use crate::state::{
+    ChannelAssignment, ChannelProof, IndexedMessage, Message, MessageProof, RejectReason, Rejected,
+    UserInfo, UserProof,
+};
+use crate::tx::{
+    attachment_payload, Content, NamespaceId, PublicKey, Register, SendMessage, Signature,
+    Transaction,
+};
+use crate::upstream::Upstream;
+use crate::validation;
+use async_trait::async_trait;
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, MatchedPath, Query, State as AxumState},
+    http::{HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use celestia_types::{nmt::Namespace, Commitment};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::time::Instant;
+use tracing::{debug, info_span, Instrument};
 
 #[derive(Deserialize)]
 pub(crate) struct SendMessageRequest {
     user: Vec<u8>,
     contents: String,
     channel: String,
+    nonce: u64,
     signature: Vec<u8>,
 }
+
 #[derive(Deserialize)]
 pub(crate) struct RegisterUserRequest {
     public_key: Vec<u8>,
     id: String,
     signature: Vec<u8>,
 }
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SubmitTransactionRequest {
+    /// Hex-encoded bincode of a signed `Transaction`.
+    pub(crate) tx: String,
+    /// Proof-of-work stamp over the encoded transaction, required on
+    /// messages from untrusted IPs if the node asks for one.
+    pub(crate) pow_nonce: Option<u64>,
+}
+
+/// Pages through `GET /users/:id/messages`, newest first.
+#[derive(Deserialize)]
+pub(crate) struct HistoryQuery {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// Pages through the message proofs of `GET /proof/channel/:channel`,
+/// oldest first.
+#[derive(Deserialize)]
+pub(crate) struct ProofQuery {
+    from: Option<u64>,
+    limit: Option<u64>,
+}
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Picks the namespace a request without a `/ns/<namespace_hex>` prefix
+/// is for, on a node serving several.
+pub(crate) const NAMESPACE_HEADER: &str = "x-grugchat-namespace";
+/// The client a read-only node forwards a write for, which rate limits and
+/// stamps apply to if the node is one of the upstream's `trusted_proxies`.
+pub(crate) const CLIENT_IP_HEADER: &str = "x-grugchat-client-ip";
+
+/// Largest page of a user's history served at once.
+const MAX_HISTORY_PAGE: usize = 100;
+
+/// Most message proofs served with one channel proof.
+const MAX_PROOF_PAGE: u64 = 100;
+
+/// Where an uploaded attachment was posted.
+#[derive(Serialize, Deserialize)]
+pub struct AttachmentUpload {
+    pub height: u64,
+    pub commitment: Hash,
+}
+
+/// Headers an attachment upload is authorized by: the hex key of the
+/// registered user uploading it, their hex signature over
+/// `attachment_payload` of the body, and the proof-of-work stamp over the
+/// same payload, if the node asks for one.
+pub(crate) const USER_HEADER: &str = "x-grugchat-user";
+pub(crate) const SIGNATURE_HEADER: &str = "x-grugchat-signature";
+pub(crate) const POW_NONCE_HEADER: &str = "x-grugchat-pow-nonce";
+
+/// Who an attachment upload is from, as read from its headers.
+pub struct AttachmentUploader {
+    pub user: PublicKey,
+    pub signature: Signature,
+    pub pow_nonce: Option<u64>,
+}
+
+impl AttachmentUploader {
+    fn from_headers(headers: &HeaderMap) -> Result<Self, (StatusCode, String)> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or((
+                    StatusCode::UNAUTHORIZED,
+                    format!("attachment uploads must be signed, with the {name} header missing"),
+                ))
+        };
+        let decode = |name: &str| {
+            hex::decode(header(name)?)
+                .map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid {name}: {e}")))
+        };
+        let pow_nonce = match headers.get(POW_NONCE_HEADER) {
+            Some(_) => Some(header(POW_NONCE_HEADER)?.parse().map_err(|e| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid {POW_NONCE_HEADER}: {e}"),
+                )
+            })?),
+            None => None,
+        };
+        Ok(AttachmentUploader {
+            user: PublicKey::new(decode(USER_HEADER)?),
+            signature: Signature::new(decode(SIGNATURE_HEADER)?),
+            pow_nonce,
+        })
+    }
+
+    /// The headers to send the upload on with.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            (USER_HEADER, hex::encode(self.user.to_bytes())),
+            (SIGNATURE_HEADER, hex::encode(self.signature.to_bytes())),
+        ];
+        if let Some(nonce) = self.pow_nonce {
+            headers.push((POW_NONCE_HEADER, nonce.to_string()));
+        }
+        headers
+    }
+}
+
+/// What the write endpoints need from the server they run on: a full node,
+/// or a gateway that only posts.
+#[async_trait]
+pub(crate) trait Ingress: Send + Sync + 'static {
+    fn rate_limiter(&self) -> &RateLimiter;
+    fn metrics(&self) -> &Metrics;
+    fn is_shutting_down(&self) -> bool;
+
+    /// Whether writes are passed on to an upstream, which then decides
+    /// what stamps they need.
+    fn forwards_writes(&self) -> bool;
+
+    /// Takes a transaction that passed every check, queueing it for the
+    /// next batch or forwarding it on behalf of `client_ip`.
+    async fn accept_transaction(
+        &self,
+        tx: Transaction,
+        pow_nonce: Option<u64>,
+        client_ip: IpAddr,
+    ) -> Result<(), (StatusCode, String)>;
+
+    /// Takes an attachment whose upload was signed and stamped, posting it
+    /// if `uploader` is a registered user.
+    async fn accept_attachment(
+        &self,
+        uploader: &AttachmentUploader,
+        client_ip: IpAddr,
+        data: Vec<u8>,
+    ) -> Result<AttachmentUpload, (StatusCode, String)>;
+}
+
+/// The error for an upload from a key that isn't a registered account.
+pub(crate) fn unregistered_uploader() -> (StatusCode, String) {
+    (
+        StatusCode::FORBIDDEN,
+        "attachments may only be uploaded by registered users".to_string(),
+    )
+}
+
+/// Where a node sends the writes it accepts: `None` if it posts them itself,
+/// or the upstream a read-only node forwards them to.
+fn write_target(node: &FullNode) -> Result<Option<&Upstream>, (StatusCode, String)> {
+    if !node.is_read_only() {
+        return Ok(None);
+    }
+    match &node.upstream {
+        Some(upstream) => Ok(Some(upstream)),
+        None => Err((
+            StatusCode::FORBIDDEN,
+            "this node is read-only and does not accept writes".to_string(),
+        )),
+    }
+}
+
+#[async_trait]
+impl Ingress for FullNode {
+    fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        FullNode::is_shutting_down(self)
+    }
+
+    fn forwards_writes(&self) -> bool {
+        self.is_read_only() && self.upstream.is_some()
+    }
+
+    async fn accept_transaction(
+        &self,
+        tx: Transaction,
+        pow_nonce: Option<u64>,
+        client_ip: IpAddr,
+    ) -> Result<(), (StatusCode, String)> {
+        if let Some(upstream) = write_target(self)? {
+            upstream
+                .submit_transaction(&tx, pow_nonce, client_ip)
+                .await?;
+            self.metrics.txs_forwarded.inc();
+            return Ok(());
+        }
+        self.queue_transaction(tx).await.map_err(|e| {
+            match e.downcast_ref::<Rejected>().map(|rejected| rejected.reason) {
+                Some(reason) => {
+                    let status = match reason {
+                        RejectReason::Quota => StatusCode::TOO_MANY_REQUESTS,
+                        _ => StatusCode::BAD_REQUEST,
+                    };
+                    reject_api(&self.metrics, reason.as_str(), status, e.to_string())
+                }
+                None => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            }
+        })
+    }
+
+    async fn accept_attachment(
+        &self,
+        uploader: &AttachmentUploader,
+        client_ip: IpAddr,
+        data: Vec<u8>,
+    ) -> Result<AttachmentUpload, (StatusCode, String)> {
+        if let Some(upstream) = write_target(self)? {
+            return upstream.upload_attachment(uploader, client_ip, data).await;
+        }
+        if !self.is_registered(&uploader.user).await {
+            return Err(unregistered_uploader());
+        }
+        let (height, commitment) = self
+            .post_attachment(data)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        Ok(AttachmentUpload {
+            height,
+            commitment: commitment.0,
+        })
+    }
+}
+
+/// Counts a transaction turned away by the API under `reason` and builds
+/// the response for it.
+fn reject_api(
+    metrics: &Metrics,
+    reason: &str,
+    status: StatusCode,
+    message: String,
+) -> (StatusCode, String) {
+    debug!(reason, error = message, "Rejected transaction");
+    metrics.txs_rejected.inc(&["api", reason]);
+    (status, message)
+}
+
+/// The address a request is limited by, which is the one it came from unless
+/// a trusted proxy forwarded it for a client.
+fn client_ip<N: Ingress>(node: &N, addr: SocketAddr, headers: &HeaderMap) -> IpAddr {
+    let forwarded_for = headers
+        .get(CLIENT_IP_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    node.rate_limiter().client_ip(addr.ip(), forwarded_for)
+}
+
+/// Checks a transaction's signature and contents, the sender's rate limits
+/// and any required proof-of-work stamp before handing it to `node`. Nodes
+/// that forward writes leave the stamp to their upstream.
+async fn queue_signed<N: Ingress>(
+    node: Arc<N>,
+    ip: IpAddr,
+    tx: Transaction,
+    pow_nonce: Option<u64>,
+) -> Result<(), (StatusCode, String)> {
+    if node.is_shutting_down() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "node is shutting down".to_string(),
+        ));
+    }
+    let metrics = node.metrics();
+    if !tx.verify_signature() {
+        return Err(reject_api(
+            metrics,
+            "signature",
+            StatusCode::BAD_REQUEST,
+            "signature verification failed".to_string(),
+        ));
+    }
+    validation::validate_tx(&tx)
+        .map_err(|e| reject_api(metrics, "invalid", StatusCode::BAD_REQUEST, e.to_string()))?;
+    node.rate_limiter()
+        .check(&tx.pubkey(), ip)
+        .await
+        .map_err(|e| reject_api(metrics, "rate_limited", StatusCode::TOO_MANY_REQUESTS, e))?;
+
+    let difficulty = if node.forwards_writes() {
+        None
+    } else {
+        node.rate_limiter().pow_difficulty(ip)
+    };
+    if let (Transaction::SendMessage(_), Some(difficulty)) = (&tx, difficulty) {
+        let tx_bytes =
+            bincode::serialize(&tx).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        if !pow_nonce.is_some_and(|nonce| verify_stamp(&tx_bytes, nonce, difficulty)) {
+            return Err(reject_api(
+                metrics,
+                "pow",
+                StatusCode::FORBIDDEN,
+                format!("proof-of-work stamp of difficulty {difficulty} required"),
+            ));
+        }
+    }
+
+    node.accept_transaction(tx, pow_nonce, ip).await
+}
 
 pub(crate) async fn list_channels(AxumState(node): AxumState<Arc<FullNode>>) -> Json<Vec<String>> {
     let state = node.state.lock().await;
@@ -32,31 +362,649 @@ pub(crate) async fn read_channel(
     Json(state.read_channel(channel).cloned())
 }
 
-pub(crate) async fn register_user(
+/// Channels moved out of the app namespace, and the namespace each is in.
+pub(crate) async fn directory(
+    AxumState(node): AxumState<Arc<FullNode>>,
+) -> Json<Vec<(String, ChannelAssignment)>> {
+    let state = node.state.lock().await;
+    Json(state.directory())
+}
+
+pub(crate) async fn snapshot(
+    AxumState(node): AxumState<Arc<FullNode>>,
+) -> Result<Vec<u8>, (StatusCode, String)> {
+    let state = node.state.lock().await;
+    state
+        .to_snapshot()
+        .and_then(|snapshot| Ok(bincode::serialize(&snapshot)?))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+pub(crate) async fn state_root(
+    AxumState(node): AxumState<Arc<FullNode>>,
+    axum::extract::Path(height): axum::extract::Path<u64>,
+) -> Json<Option<Hash>> {
+    let state = node.state.lock().await;
+    Json(state.root_at(height))
+}
+
+/// The root of the channels moved to a channel namespace, given as the hex
+/// of its v0 id.
+pub(crate) async fn channel_root(
+    AxumState(node): AxumState<Arc<FullNode>>,
+    axum::extract::Path((height, namespace)): axum::extract::Path<(u64, String)>,
+) -> Result<Json<Option<Hash>>, (StatusCode, String)> {
+    let namespace: NamespaceId = hex::decode(namespace)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or((
+            StatusCode::BAD_REQUEST,
+            "namespace must be 10 hex-encoded bytes".to_string(),
+        ))?;
+    check_downloaded(&node, Some(namespace)).await?;
+    let state = node.state.lock().await;
+    Ok(Json(state.channel_root_at(height, namespace)))
+}
+
+/// Refuses requests about a channel namespace the node doesn't download,
+/// whose messages it doesn't have. `None` is the app namespace.
+async fn check_downloaded(
+    node: &FullNode,
+    namespace: Option<NamespaceId>,
+) -> Result<(), (StatusCode, String)> {
+    match namespace {
+        Some(namespace)
+            if !node
+                .channel_namespaces()
+                .await
+                .contains(&Namespace::const_v0(namespace)) =>
+        {
+            Err((
+                StatusCode::NOT_FOUND,
+                format!(
+                    "this node doesn't follow namespace {}",
+                    hex::encode(namespace)
+                ),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) async fn prove_user(
+    AxumState(node): AxumState<Arc<FullNode>>,
+    axum::extract::Path(key): axum::extract::Path<String>,
+) -> Result<Json<UserProof>, (StatusCode, String)> {
+    let user = parse_public_key(&key)?;
+    let state = node.state.lock().await;
+    Ok(Json(state.prove_user(&user)))
+}
+
+pub(crate) async fn prove_channel(
     AxumState(node): AxumState<Arc<FullNode>>,
+    axum::extract::Path(channel): axum::extract::Path<String>,
+    Query(page): Query<ProofQuery>,
+) -> Result<Json<ChannelProof>, (StatusCode, String)> {
+    let namespace = node.state.lock().await.channel_namespace(&channel);
+    check_downloaded(&node, namespace).await?;
+    let limit = page.limit.unwrap_or(MAX_PROOF_PAGE).min(MAX_PROOF_PAGE);
+    // Built under one lock, so every proof is against the same root, and
+    // serialized after it is released.
+    let proof = node
+        .state
+        .lock()
+        .await
+        .prove_channel(&channel, page.from.unwrap_or(0), limit);
+    Ok(Json(proof))
+}
+
+/// Messages are identified as `<channel>:<index>`.
+pub(crate) async fn prove_message(
+    AxumState(node): AxumState<Arc<FullNode>>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<Json<MessageProof>, (StatusCode, String)> {
+    let (channel, index) = id
+        .rsplit_once(':')
+        .and_then(|(channel, index)| Some((channel, index.parse::<u64>().ok()?)))
+        .ok_or((
+            StatusCode::BAD_REQUEST,
+            "message id must be <channel>:<index>".to_string(),
+        ))?;
+    let namespace = node.state.lock().await.channel_namespace(channel);
+    check_downloaded(&node, namespace).await?;
+    let state = node.state.lock().await;
+    Ok(Json(state.prove_message(channel, index)))
+}
+
+fn parse_public_key(key: &str) -> Result<PublicKey, (StatusCode, String)> {
+    hex::decode(key)
+        .map(PublicKey::new)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+/// Users are identified by their hex-encoded public key, since user ids
+/// aren't unique.
+pub(crate) async fn get_user(
+    AxumState(node): AxumState<Arc<FullNode>>,
+    axum::extract::Path(key): axum::extract::Path<String>,
+) -> Result<Json<UserInfo>, (StatusCode, String)> {
+    let user = parse_public_key(&key)?;
+    let state = node.state.lock().await;
+    state
+        .user_info(&user)
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "user not registered".to_string()))
+}
+
+pub(crate) async fn get_user_messages(
+    AxumState(node): AxumState<Arc<FullNode>>,
+    axum::extract::Path(key): axum::extract::Path<String>,
+    Query(page): Query<HistoryQuery>,
+) -> Result<Json<Vec<IndexedMessage>>, (StatusCode, String)> {
+    let user = parse_public_key(&key)?;
+    let limit = page.limit.unwrap_or(MAX_HISTORY_PAGE).min(MAX_HISTORY_PAGE);
+    let state = node.state.lock().await;
+    Ok(Json(state.user_messages(
+        &user,
+        page.offset.unwrap_or(0),
+        limit,
+    )))
+}
+
+pub(crate) async fn search(
+    AxumState(node): AxumState<Arc<FullNode>>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<IndexedMessage>>, (StatusCode, String)> {
+    let state = node.state.lock().await;
+    state
+        .search(&query)
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+pub(crate) async fn register_user<N: Ingress>(
+    AxumState(node): AxumState<Arc<N>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(payload): Json<RegisterUserRequest>,
 ) -> Result<(), (StatusCode, String)> {
+    let ip = client_ip(node.as_ref(), addr, &headers);
     let tx = Transaction::Register(Register {
         user: PublicKey::new(payload.public_key),
         id: payload.id,
         signature: Signature::new(payload.signature),
     });
-    node.queue_transaction(tx)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+    queue_signed(node, ip, tx, None).await
 }
 
-pub(crate) async fn send_message(
-    AxumState(node): AxumState<Arc<FullNode>>,
+pub(crate) async fn send_message<N: Ingress>(
+    AxumState(node): AxumState<Arc<N>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(payload): Json<SendMessageRequest>,
 ) -> Result<(), (StatusCode, String)> {
+    let ip = client_ip(node.as_ref(), addr, &headers);
     let tx = Transaction::SendMessage(SendMessage {
         user: PublicKey::new(payload.user),
-        contents: payload.contents,
+        contents: Content::Text(payload.contents),
         channel: payload.channel,
+        nonce: payload.nonce,
         signature: Signature::new(payload.signature),
     });
-    node.queue_transaction(tx)
+    queue_signed(node, ip, tx, None).await
+}
+
+pub(crate) async fn submit_transaction<N: Ingress>(
+    AxumState(node): AxumState<Arc<N>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<SubmitTransactionRequest>,
+) -> Result<(), (StatusCode, String)> {
+    let ip = client_ip(node.as_ref(), addr, &headers);
+    let bytes = hex::decode(payload.tx).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let tx: Transaction =
+        bincode::deserialize(&bytes).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    queue_signed(node, ip, tx, payload.pow_nonce).await
+}
+
+/// Posts the body as an attachment blob, if the upload is signed by a
+/// registered user and carries any proof-of-work stamp the node asks for.
+pub(crate) async fn upload_attachment<N: Ingress>(
+    AxumState(node): AxumState<Arc<N>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<AttachmentUpload>, (StatusCode, String)> {
+    if body.is_empty() || body.len() as u64 > validation::MAX_ATTACHMENT_SIZE {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "attachments must be between 1 and {} bytes",
+                validation::MAX_ATTACHMENT_SIZE
+            ),
+        ));
+    }
+    let ip = client_ip(node.as_ref(), addr, &headers);
+    let uploader = AttachmentUploader::from_headers(&headers)?;
+    let payload = attachment_payload(&body);
+    if !uploader.signature.verify(&uploader.user, &payload) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "signature verification failed".to_string(),
+        ));
+    }
+    node.rate_limiter()
+        .check(&uploader.user, ip)
         .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        .map_err(|e| (StatusCode::TOO_MANY_REQUESTS, e))?;
+    let difficulty = if node.forwards_writes() {
+        None
+    } else {
+        node.rate_limiter().attachment_pow_difficulty(ip)
+    };
+    if let Some(difficulty) = difficulty {
+        if !uploader
+            .pow_nonce
+            .is_some_and(|nonce| verify_stamp(&payload, nonce, difficulty))
+        {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("proof-of-work stamp of difficulty {difficulty} required"),
+            ));
+        }
+    }
+
+    Ok(Json(
+        node.accept_attachment(&uploader, ip, body.to_vec()).await?,
+    ))
+}
+
+pub(crate) async fn download_attachment(
+    AxumState(node): AxumState<Arc<FullNode>>,
+    axum::extract::Path((height, commitment)): axum::extract::Path<(u64, String)>,
+) -> Result<Vec<u8>, (StatusCode, String)> {
+    let commitment: Hash = hex::decode(commitment)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or((
+            StatusCode::BAD_REQUEST,
+            "commitment must be 32 hex-encoded bytes".to_string(),
+        ))?;
+    node.fetch_attachment(height, Commitment(commitment))
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))
+}
+
+/// Liveness: the server is up and answering.
+pub(crate) async fn health() -> &'static str {
+    "ok"
+}
+
+/// Readiness: the node has finished its initial sync and sync is running,
+/// so its state reflects the DA layer.
+pub(crate) async fn ready(AxumState(node): AxumState<Arc<FullNode>>) -> (StatusCode, &'static str) {
+    if !node.is_synced() {
+        (StatusCode::SERVICE_UNAVAILABLE, "syncing")
+    } else if node.sync_error().is_some() {
+        (StatusCode::SERVICE_UNAVAILABLE, "degraded")
+    } else {
+        (StatusCode::OK, "ready")
+    }
+}
+
+pub(crate) async fn status(AxumState(node): AxumState<Arc<FullNode>>) -> Json<NodeStatus> {
+    Json(node.status().await)
+}
+
+pub(crate) async fn metrics(AxumState(node): AxumState<Arc<FullNode>>) -> String {
+    let state = node.state.lock().await;
+    node.metrics.render(Some(&state))
+}
+
+pub(crate) async fn gateway_status(
+    AxumState(gateway): AxumState<Arc<Gateway>>,
+) -> Json<GatewayStatus> {
+    Json(gateway.status().await)
+}
+
+pub(crate) async fn gateway_metrics(AxumState(gateway): AxumState<Arc<Gateway>>) -> String {
+    gateway.metrics().render(None)
+}
+
+/// Tags every request with an ID, taken from the client's `x-request-id`
+/// if it sent one, and runs it in a span carrying that ID and the route it
+/// matched. The route also labels the latency metric, so paths with
+/// parameters share one series.
+pub(crate) async fn observe_request<N: Ingress, B>(
+    AxumState(node): AxumState<Arc<N>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+    let route = request.extensions().get::<MatchedPath>().map_or_else(
+        || request.uri().path().to_string(),
+        |path| path.as_str().to_string(),
+    );
+    // Each namespace keeps its own metrics, so its prefix is left out.
+    let route = match route.strip_prefix("/ns/").and_then(|rest| rest.find('/')) {
+        Some(end) => route[end + "/ns/".len()..].to_string(),
+        None => route,
+    };
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)));
+    let span = info_span!(
+        "request",
+        id = request_id,
+        method = %request.method(),
+        route
+    );
+
+    let started = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+    let elapsed = started.elapsed();
+    node.metrics()
+        .http_request_seconds
+        .observe_duration(&[&route, response.status().as_str()], elapsed);
+    span.in_scope(|| {
+        debug!(
+            status = response.status().as_u16(),
+            millis = elapsed.as_millis() as u64,
+            "Request completed"
+        )
+    });
+    if let Ok(id) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, id);
+    }
+    response
+}
+
+/// Sends each request to the namespace it is for: the one in its
+/// `/ns/<namespace_hex>` prefix if it has one, otherwise the one in its
+/// `x-grugchat-namespace` header, otherwise the first of `namespaces`.
+/// Unprefixed requests are rewritten to carry the prefix.
+pub(crate) async fn route_namespace<B>(
+    AxumState(namespaces): AxumState<Arc<Vec<String>>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let prefixed = request
+        .uri()
+        .path()
+        .strip_prefix("/ns/")
+        .map(|rest| rest.split('/').next().unwrap_or_default().to_string());
+    let namespace = match &prefixed {
+        Some(namespace) => namespace.clone(),
+        None => request
+            .headers()
+            .get(NAMESPACE_HEADER)
+            .and_then(|namespace| namespace.to_str().ok())
+            .map(str::to_string)
+            .unwrap_or_else(|| namespaces[0].clone()),
+    };
+    if !namespaces.contains(&namespace) {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("namespace {namespace} is not served by this node"),
+        )
+            .into_response();
+    }
+
+    if prefixed.is_none() {
+        let path = request
+            .uri()
+            .path_and_query()
+            .map_or("/", |path| path.as_str());
+        match format!("/ns/{namespace}{path}").parse() {
+            Ok(uri) => *request.uri_mut() = uri,
+            Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ChainConfig, IndexFilter, NodeConfig};
+    use crate::da::MockDa;
+    use crate::tx::AssignChannel;
+    use celestia_types::nmt::Namespace;
+    use ed25519_dalek::SigningKey;
+    use tokio::time::{sleep, timeout, Duration};
+
+    fn start_node(da: &Arc<MockDa>, config: NodeConfig) -> Arc<FullNode> {
+        let node = Arc::new(FullNode::with_da(
+            da.clone(),
+            Namespace::const_v0(*b"grugchat-t"),
+            1,
+            config,
+        ));
+        tokio::spawn(node.clone().start_sync());
+        node
+    }
+
+    /// Serves `node`'s API on a free local port, returning its URL.
+    fn serve_on_any_port(node: Arc<FullNode>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = axum::Server::from_tcp(listener).unwrap().serve(
+            node.router()
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        );
+        tokio::spawn(server);
+        url
+    }
+
+    async fn wait_until(condition: impl Fn() -> bool) {
+        timeout(Duration::from_secs(10), async {
+            while !condition() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition not met in time");
+    }
+
+    #[tokio::test]
+    async fn ready_once_synced_until_sync_fails() {
+        let da = Arc::new(MockDa::new());
+        let node = Arc::new(FullNode::with_da(
+            da.clone(),
+            Namespace::const_v0(*b"grugchat-t"),
+            1,
+            NodeConfig {
+                subscription_timeout: Duration::from_millis(500),
+                ..NodeConfig::default()
+            },
+        ));
+        assert_eq!(
+            ready(AxumState(node.clone())).await,
+            (StatusCode::SERVICE_UNAVAILABLE, "syncing")
+        );
+
+        tokio::spawn(node.clone().start_sync());
+        wait_until(|| node.is_synced()).await;
+        assert_eq!(
+            ready(AxumState(node.clone())).await,
+            (StatusCode::OK, "ready")
+        );
+
+        // Without any heights arriving, the subscription times out.
+        wait_until(|| node.sync_error().is_some()).await;
+        assert_eq!(
+            ready(AxumState(node.clone())).await,
+            (StatusCode::SERVICE_UNAVAILABLE, "degraded")
+        );
+    }
+
+    #[tokio::test]
+    async fn status_reports_sync_and_posting() {
+        let da = Arc::new(MockDa::new());
+        da.produce_block().await;
+        da.produce_block().await;
+        let node = start_node(&da, NodeConfig::default());
+        wait_until(|| node.is_synced()).await;
+
+        let Json(status) = status(AxumState(node.clone())).await;
+        assert_eq!(status.namespace, hex::encode(b"grugchat-t"));
+        assert!(status.genesis_sync_complete);
+        assert_eq!(status.sync_error, None);
+        assert_eq!(status.last_height, Some(2));
+        assert_eq!(status.network_height, Some(2));
+        assert_eq!(status.pending_transactions, 0);
+        assert!(status.last_batch.is_none());
+
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let register = Transaction::Register(Register {
+            user: key.verifying_key().into(),
+            id: "alice".to_string(),
+            signature: Signature::new(Vec::new()),
+        })
+        .sign(&key)
+        .unwrap();
+        node.clone().queue_transaction(register).await.unwrap();
+        let Json(status) = super::status(AxumState(node.clone())).await;
+        assert_eq!(status.pending_transactions, 1);
+
+        node.clone().post_pending_batch().await.unwrap();
+        let height = da.produce_block().await;
+        let Json(status) = super::status(AxumState(node.clone())).await;
+        assert_eq!(status.pending_transactions, 0);
+        let last_batch = status.last_batch.unwrap();
+        assert_eq!((last_batch.height, last_batch.txs), (height, 1));
+    }
+
+    #[tokio::test]
+    async fn filtered_nodes_serve_no_roots_or_proofs() {
+        let da = Arc::new(MockDa::new());
+        da.produce_block().await;
+        let index_filter = IndexFilter {
+            channels: Some(vec!["general".to_string()]),
+            ..IndexFilter::default()
+        };
+        let filtered = start_node(
+            &da,
+            NodeConfig {
+                index_filter: index_filter.clone(),
+                ..NodeConfig::default()
+            },
+        );
+        let full = start_node(&da, NodeConfig::default());
+        wait_until(|| filtered.is_synced() && full.is_synced()).await;
+
+        let Json(status) = status(AxumState(filtered.clone())).await;
+        assert_eq!(status.index_filter, index_filter);
+
+        let client = reqwest::Client::new();
+        let key = hex::encode([1; 32]);
+        for (node, expected) in [
+            (filtered, reqwest::StatusCode::NOT_FOUND),
+            (full, reqwest::StatusCode::OK),
+        ] {
+            let url = serve_on_any_port(node);
+            for path in ["/root/1".to_string(), format!("/proof/user/{key}")] {
+                let response = client.get(format!("{url}{path}")).send().await.unwrap();
+                assert_eq!(response.status(), expected, "{path}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn gateways_post_messages_to_their_channels_namespace() {
+        let da = Arc::new(MockDa::new());
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let node = start_node(
+            &da,
+            NodeConfig {
+                chain: ChainConfig {
+                    directory_admins: vec![alice.verifying_key().into()],
+                    ..ChainConfig::default()
+                },
+                ..NodeConfig::default()
+            },
+        );
+        let register = Transaction::Register(Register {
+            user: alice.verifying_key().into(),
+            id: "alice".to_string(),
+            signature: Signature::new(Vec::new()),
+        })
+        .sign(&alice)
+        .unwrap();
+        let assign = Transaction::AssignChannel(AssignChannel {
+            user: alice.verifying_key().into(),
+            channel: "firehose".to_string(),
+            namespace: *b"grugchat-1",
+            nonce: 1,
+            signature: Signature::new(Vec::new()),
+        })
+        .sign(&alice)
+        .unwrap();
+        for tx in [register, assign] {
+            node.clone().queue_transaction(tx).await.unwrap();
+            node.clone().post_pending_batch().await.unwrap();
+            da.produce_block().await;
+        }
+        wait_until(|| {
+            node.state()
+                .try_lock()
+                .is_ok_and(|state| state.last_height() == Some(2))
+        })
+        .await;
+
+        let url = serve_on_any_port(node.clone());
+        let send = Transaction::SendMessage(SendMessage {
+            user: alice.verifying_key().into(),
+            contents: Content::Text("hi".to_string()),
+            channel: "firehose".to_string(),
+            nonce: 1,
+            signature: Signature::new(Vec::new()),
+        })
+        .sign(&alice)
+        .unwrap();
+        let client_ip = IpAddr::from([127, 0, 0, 1]);
+        let namespace = Namespace::const_v0(*b"grugchat-t");
+        // Without a full node to look the channel up on, the message goes to
+        // the app namespace, where it is skipped as the channel has moved.
+        let lone = Gateway::with_da(da.clone(), namespace, NodeConfig::default());
+        lone.accept_transaction(send.clone(), None, client_ip)
+            .await
+            .unwrap();
+        lone.post_pending_batch().await.unwrap();
+        da.produce_block().await;
+        wait_until(|| {
+            node.state().try_lock().is_ok_and(|state| {
+                state.last_height() == Some(3)
+                    && state.read_channel("firehose".to_string()).is_none()
+            })
+        })
+        .await;
+
+        let gateway = Gateway::with_da(
+            da.clone(),
+            namespace,
+            NodeConfig {
+                state_url: Some(url),
+                ..NodeConfig::default()
+            },
+        );
+        gateway
+            .accept_transaction(send, None, client_ip)
+            .await
+            .unwrap();
+        gateway.post_pending_batch().await.unwrap();
+        da.produce_block().await;
+        wait_until(|| {
+            node.state().try_lock().is_ok_and(|state| {
+                state
+                    .read_channel("firehose".to_string())
+                    .is_some_and(|messages| messages.len() == 1)
+            })
+        })
+        .await;
+    }
 }
@@ -0,0 +1,606 @@
+use celestia_types::{nmt::Namespace, Blob};
+use ed25519_dalek::SigningKey;
+use grugchat::config::{Activations, ChainConfig, FollowChannels, IndexFilter, NodeConfig};
+use grugchat::da::{DataAvailabilityLayer, MockDa};
+use grugchat::fullnode::{attachment_namespace, Batch, FullNode};
+use grugchat::legacy;
+use grugchat::multinode::MultiNode;
+use grugchat::tx::{AssignChannel, Content, Register, SendMessage, Signature, Transaction};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::time::{sleep, timeout, Duration};
+
+fn namespace() -> Namespace {
+    Namespace::new_v0(&[0x67, 0x72, 0x75, 0x67]).unwrap()
+}
+
+fn signing_key(seed: u8) -> SigningKey {
+    SigningKey::from_bytes(&[seed; 32])
+}
+
+fn register(key: &SigningKey, id: &str) -> Transaction {
+    Transaction::Register(Register {
+        user: key.verifying_key().into(),
+        id: id.to_string(),
+        signature: Signature::new(Vec::new()),
+    })
+    .sign(key)
+    .unwrap()
+}
+
+/// A message with a nonce greater than any sent before.
+fn send(key: &SigningKey, channel: &str, contents: &str) -> Transaction {
+    static NONCE: AtomicU64 = AtomicU64::new(1);
+    Transaction::SendMessage(SendMessage {
+        user: key.verifying_key().into(),
+        contents: Content::Text(contents.to_string()),
+        channel: channel.to_string(),
+        nonce: NONCE.fetch_add(1, Ordering::Relaxed),
+        signature: Signature::new(Vec::new()),
+    })
+    .sign(key)
+    .unwrap()
+}
+
+fn start_node(da: &Arc<MockDa>) -> Arc<FullNode> {
+    start_node_with(da, NodeConfig::default())
+}
+
+fn start_node_with(da: &Arc<MockDa>, config: NodeConfig) -> Arc<FullNode> {
+    let da: Arc<dyn DataAvailabilityLayer> = da.clone();
+    let node = Arc::new(FullNode::with_da(da, namespace(), 1, config));
+    tokio::spawn(node.clone().start_sync());
+    node
+}
+
+async fn wait_for_height(node: &FullNode, height: u64) {
+    timeout(Duration::from_secs(10), async {
+        while node.state().lock().await.last_height() < Some(height) {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("node did not reach height in time");
+}
+
+/// Has each node queue `txs` and post them as its own batch, then includes
+/// all of the batches at a single height.
+async fn post_in_one_block(da: &MockDa, batches: Vec<(&Arc<FullNode>, Vec<Transaction>)>) -> u64 {
+    for (node, txs) in batches {
+        for tx in txs {
+            node.clone().queue_transaction(tx).await.unwrap();
+        }
+        node.clone().post_pending_batch().await.unwrap();
+    }
+    da.produce_block().await
+}
+
+async fn assert_converged(nodes: &[Arc<FullNode>], height: u64) {
+    for node in nodes {
+        wait_for_height(node, height).await;
+    }
+    let root = nodes[0].state().lock().await.root();
+    for node in &nodes[1..] {
+        assert_eq!(node.state().lock().await.root(), root);
+    }
+}
+
+#[tokio::test]
+async fn nodes_converge_on_batches_posted_to_the_same_height() {
+    let da = Arc::new(MockDa::new());
+    let nodes: Vec<_> = (0..3).map(|_| start_node(&da)).collect();
+    let alice = signing_key(1);
+    let bob = signing_key(2);
+
+    let height = post_in_one_block(
+        &da,
+        vec![
+            (&nodes[0], vec![register(&alice, "alice")]),
+            (
+                &nodes[1],
+                vec![register(&bob, "bob"), register(&alice, "mallory")],
+            ),
+            (&nodes[2], vec![send(&alice, "general", "first")]),
+        ],
+    )
+    .await;
+    assert_converged(&nodes, height).await;
+
+    let height = post_in_one_block(
+        &da,
+        vec![
+            (&nodes[2], vec![send(&bob, "general", "second")]),
+            (&nodes[0], vec![send(&alice, "general", "third")]),
+        ],
+    )
+    .await;
+    assert_converged(&nodes, height).await;
+
+    for node in &nodes {
+        let state = node.state().lock().await;
+        let messages = state.read_channel("general".to_string()).unwrap();
+        let log: Vec<_> = messages
+            .iter()
+            .map(|msg| (msg.user_id.as_str(), msg.contents.to_string()))
+            .collect();
+        // The first registration of a key wins, and messages follow the
+        // order their batches were included in.
+        assert_eq!(
+            log,
+            vec![
+                ("alice", "first".to_string()),
+                ("bob", "second".to_string()),
+                ("alice", "third".to_string())
+            ]
+        );
+    }
+}
+
+#[tokio::test]
+async fn late_node_converges_with_running_nodes() {
+    let da = Arc::new(MockDa::new());
+    let mut nodes = vec![start_node(&da), start_node(&da)];
+    let alice = signing_key(1);
+
+    post_in_one_block(&da, vec![(&nodes[0], vec![register(&alice, "alice")])]).await;
+    da.produce_block().await;
+    let height =
+        post_in_one_block(&da, vec![(&nodes[1], vec![send(&alice, "general", "hi")])]).await;
+
+    nodes.push(start_node(&da));
+    assert_converged(&nodes, height).await;
+
+    let height = post_in_one_block(
+        &da,
+        vec![(&nodes[2], vec![send(&alice, "general", "again")])],
+    )
+    .await;
+    assert_converged(&nodes, height).await;
+}
+
+#[tokio::test]
+async fn namespaces_served_together_keep_separate_state() {
+    let da = Arc::new(MockDa::new());
+    let other = Namespace::new_v0(&[0x6f, 0x74, 0x68, 0x72]).unwrap();
+    let multinode =
+        MultiNode::with_da(da.clone(), &[namespace(), other], 1, NodeConfig::default()).unwrap();
+    let nodes = multinode.nodes().to_vec();
+    for node in &nodes {
+        tokio::spawn(node.clone().start_sync());
+    }
+    // A node on its own sees only the namespace it syncs.
+    let single = start_node(&da);
+    let alice = signing_key(1);
+    let bob = signing_key(2);
+
+    post_in_one_block(
+        &da,
+        vec![
+            (&nodes[0], vec![register(&alice, "alice")]),
+            (&nodes[1], vec![register(&bob, "bob")]),
+        ],
+    )
+    .await;
+    let height = post_in_one_block(
+        &da,
+        vec![
+            (&nodes[0], vec![send(&alice, "general", "hi")]),
+            (&nodes[1], vec![send(&bob, "general", "hello")]),
+        ],
+    )
+    .await;
+    assert_converged(&[nodes[0].clone(), single], height).await;
+    wait_for_height(&nodes[1], height).await;
+
+    let messages = |node: &Arc<FullNode>| {
+        let node = node.clone();
+        async move {
+            let state = node.state().lock().await;
+            state
+                .read_channel("general".to_string())
+                .unwrap()
+                .iter()
+                .map(|msg| (msg.user_id.clone(), msg.contents.to_string()))
+                .collect::<Vec<_>>()
+        }
+    };
+    assert_eq!(
+        messages(&nodes[0]).await,
+        vec![("alice".to_string(), "hi".to_string())]
+    );
+    assert_eq!(
+        messages(&nodes[1]).await,
+        vec![("bob".to_string(), "hello".to_string())]
+    );
+    assert!(multinode.node(other).is_some());
+    assert!(MultiNode::with_da(da, &[other, other], 1, NodeConfig::default()).is_err());
+}
+
+#[tokio::test]
+async fn every_namespace_is_finished_despite_failures() {
+    let da = Arc::new(MockDa::new());
+    let other = Namespace::new_v0(&[0x6f, 0x74, 0x68, 0x72]).unwrap();
+    let config = NodeConfig {
+        snapshot_path: Some("/nonexistent/grugchat.snapshot".into()),
+        ..NodeConfig::default()
+    };
+    let multinode = MultiNode::with_da(da.clone(), &[namespace(), other], 1, config).unwrap();
+    let nodes = multinode.nodes().to_vec();
+    for node in &nodes {
+        tokio::spawn(node.clone().start_sync());
+    }
+    let alice = signing_key(1);
+    let bob = signing_key(2);
+
+    let height = da.produce_block().await;
+    for node in &nodes {
+        wait_for_height(node, height).await;
+    }
+    nodes[0]
+        .clone()
+        .queue_transaction(register(&alice, "alice"))
+        .await
+        .unwrap();
+    nodes[1]
+        .clone()
+        .queue_transaction(register(&bob, "bob"))
+        .await
+        .unwrap();
+    multinode.shutdown();
+
+    // Neither snapshot can be saved, but both final batches are posted and
+    // both failures are reported.
+    let error = multinode.finish().await.unwrap_err().to_string();
+    for namespace in [namespace(), other] {
+        assert!(error.contains(&hex::encode(namespace.id_v0().unwrap())));
+    }
+    let height = da.produce_block().await;
+    for node in &nodes {
+        wait_for_height(node, height).await;
+    }
+    assert!(nodes[0].is_registered(&alice.verifying_key().into()).await);
+    assert!(nodes[1].is_registered(&bob.verifying_key().into()).await);
+}
+
+#[tokio::test]
+async fn channels_moved_to_their_own_namespace_are_only_synced_by_followers() {
+    let da = Arc::new(MockDa::new());
+    let alice = signing_key(1);
+    let chain = ChainConfig {
+        directory_admins: vec![alice.verifying_key().into()],
+        ..ChainConfig::default()
+    };
+    let follower = start_node_with(
+        &da,
+        NodeConfig {
+            chain: chain.clone(),
+            ..NodeConfig::default()
+        },
+    );
+    let other = start_node_with(
+        &da,
+        NodeConfig {
+            chain,
+            follow_channels: FollowChannels::Only(Vec::new()),
+            ..NodeConfig::default()
+        },
+    );
+    let assign = Transaction::AssignChannel(AssignChannel {
+        user: alice.verifying_key().into(),
+        channel: "firehose".to_string(),
+        namespace: *b"grugchat-1",
+        nonce: 1,
+        signature: Signature::new(Vec::new()),
+    })
+    .sign(&alice)
+    .unwrap();
+
+    post_in_one_block(&da, vec![(&follower, vec![register(&alice, "alice")])]).await;
+    let height = post_in_one_block(&da, vec![(&follower, vec![assign])]).await;
+    assert_converged(&[follower.clone(), other.clone()], height).await;
+    assert_eq!(
+        follower.channel_namespaces().await,
+        vec![Namespace::const_v0(*b"grugchat-1")]
+    );
+    assert!(other.channel_namespaces().await.is_empty());
+
+    // Queued messages go to the channel's namespace, and a message to it
+    // posted in the app namespace is skipped.
+    let stray = Batch::new(vec![send(&alice, "firehose", "stray")])
+        .to_blob(namespace())
+        .unwrap();
+    da.submit(&[stray]).await.unwrap();
+    let height = post_in_one_block(
+        &da,
+        vec![
+            (&other, vec![send(&alice, "firehose", "hi")]),
+            (&follower, vec![send(&alice, "general", "hello")]),
+        ],
+    )
+    .await;
+    // The channel is committed to its namespace's own root, so the app
+    // roots still agree.
+    assert_converged(&[follower.clone(), other.clone()], height).await;
+    assert!(follower
+        .state()
+        .lock()
+        .await
+        .channel_root_at(height, *b"grugchat-1")
+        .is_some());
+
+    let firehose = follower
+        .state()
+        .lock()
+        .await
+        .read_channel("firehose".to_string())
+        .cloned()
+        .unwrap();
+    assert_eq!(firehose.len(), 1);
+    assert_eq!(firehose[0].contents.to_string(), "hi");
+    let state = other.state().lock().await;
+    assert!(state.read_channel("firehose".to_string()).is_none());
+    assert_eq!(state.read_channel("general".to_string()).unwrap().len(), 1);
+    assert_eq!(state.user_count(), 1);
+}
+
+#[tokio::test]
+async fn selective_nodes_skip_messages_but_validate_like_the_rest() {
+    let da = Arc::new(MockDa::new());
+    let full = start_node(&da);
+    let alice = signing_key(1);
+    let bob = signing_key(2);
+    let selective = start_node_with(
+        &da,
+        NodeConfig {
+            index_filter: IndexFilter {
+                channels: Some(vec!["general".to_string()]),
+                skip_users: vec![bob.verifying_key().into()],
+                ..IndexFilter::default()
+            },
+            ..NodeConfig::default()
+        },
+    );
+
+    post_in_one_block(
+        &da,
+        vec![(
+            &full,
+            vec![register(&alice, "alice"), register(&bob, "bob")],
+        )],
+    )
+    .await;
+    post_in_one_block(
+        &da,
+        vec![(
+            &full,
+            vec![
+                send(&alice, "general", "hi"),
+                send(&alice, "random", "psst"),
+                send(&bob, "general", "hello"),
+            ],
+        )],
+    )
+    .await;
+    // Both refuse to move a channel that has messages, whether or not they
+    // stored them.
+    let assign = Transaction::AssignChannel(AssignChannel {
+        user: alice.verifying_key().into(),
+        channel: "random".to_string(),
+        namespace: *b"grugchat-2",
+        nonce: 1,
+        signature: Signature::new(Vec::new()),
+    })
+    .sign(&alice)
+    .unwrap();
+    let height = post_in_one_block(&da, vec![(&full, vec![assign])]).await;
+    wait_for_height(&full, height).await;
+    wait_for_height(&selective, height).await;
+
+    let state = selective.state().lock().await;
+    assert_eq!(state.user_count(), 2);
+    assert_eq!(state.message_count(), 1);
+    assert!(state.read_channel("random".to_string()).is_none());
+    assert!(state.directory().is_empty());
+    assert!(state.to_snapshot().is_err());
+    assert_eq!(full.state().lock().await.message_count(), 3);
+    assert!(full.state().lock().await.directory().is_empty());
+}
+
+fn legacy_blob(key: &SigningKey, txs: Vec<legacy::Transaction>) -> Blob {
+    use ed25519_dalek::Signer;
+    let signed: Vec<legacy::Transaction> = txs
+        .into_iter()
+        .map(|tx| {
+            let signature: Signature = key.sign(&bincode::serialize(&tx).unwrap()).into();
+            match tx {
+                legacy::Transaction::SendMessage(msg) => {
+                    legacy::Transaction::SendMessage(legacy::SendMessage { signature, ..msg })
+                }
+                legacy::Transaction::Register(register) => {
+                    legacy::Transaction::Register(legacy::Register {
+                        signature,
+                        ..register
+                    })
+                }
+            }
+        })
+        .collect();
+    Blob::new(namespace(), bincode::serialize(&signed).unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn unversioned_blobs_apply_until_versioned_batches_activate() {
+    let da = Arc::new(MockDa::new());
+    let node = start_node_with(
+        &da,
+        NodeConfig {
+            chain: ChainConfig {
+                activations: Activations {
+                    versioned_batches: 3,
+                    ..Activations::default()
+                },
+                ..ChainConfig::default()
+            },
+            ..NodeConfig::default()
+        },
+    );
+    let alice = signing_key(1);
+    let user = alice.verifying_key().into();
+    let legacy_send = |contents: &str| {
+        legacy::Transaction::SendMessage(legacy::SendMessage {
+            user: alice.verifying_key().into(),
+            contents: contents.to_string(),
+            channel: "general".to_string(),
+            signature: Signature::new(Vec::new()),
+        })
+    };
+
+    let register = legacy::Transaction::Register(legacy::Register {
+        user,
+        id: "alice".to_string(),
+        signature: Signature::new(Vec::new()),
+    });
+    da.submit(&[legacy_blob(&alice, vec![register, legacy_send("old")])])
+        .await
+        .unwrap();
+    da.produce_block().await;
+    // Versioned batches are accepted before the activation too.
+    let height = post_in_one_block(&da, vec![(&node, vec![send(&alice, "general", "new")])]).await;
+    assert_eq!(height, 2);
+    da.submit(&[legacy_blob(&alice, vec![legacy_send("too late")])])
+        .await
+        .unwrap();
+    let height = da.produce_block().await;
+    wait_for_height(&node, height).await;
+
+    let state = node.state().lock().await;
+    let general = state.read_channel("general".to_string()).unwrap();
+    let contents: Vec<String> = general.iter().map(|msg| msg.contents.to_string()).collect();
+    assert_eq!(contents, vec!["old", "new"]);
+    assert!(general.iter().all(|msg| msg.verify_signature("general")));
+}
+
+#[tokio::test]
+async fn attachments_are_posted_outside_the_app_namespace() {
+    let da = Arc::new(MockDa::new());
+    let node = start_node(&da);
+
+    let data = b"not a batch".to_vec();
+    let (height, commitment) = node.post_attachment(data.clone()).await.unwrap();
+    assert_eq!(da.produce_block().await, height);
+
+    assert!(da.get_blob(height, namespace(), commitment).await.is_err());
+    let blob = da
+        .get_blob(height, attachment_namespace(namespace()), commitment)
+        .await
+        .unwrap();
+    assert_eq!(blob.data, data);
+    assert_eq!(
+        node.clone()
+            .fetch_attachment(height, commitment)
+            .await
+            .unwrap(),
+        data
+    );
+}
+
+#[tokio::test]
+async fn failed_submissions_keep_the_batch_pending() {
+    let da = Arc::new(MockDa::new());
+    let node = start_node(&da);
+    let alice = signing_key(1);
+
+    node.clone()
+        .queue_transaction(register(&alice, "alice"))
+        .await
+        .unwrap();
+    da.fail_submissions(true).await;
+    assert!(node.clone().post_pending_batch().await.is_err());
+    assert_eq!(node.status().await.pending_transactions, 1);
+
+    da.fail_submissions(false).await;
+    node.clone().post_pending_batch().await.unwrap();
+    assert_eq!(node.status().await.pending_transactions, 0);
+    let height = da.produce_block().await;
+    wait_for_height(&node, height).await;
+    assert!(node.is_registered(&alice.verifying_key().into()).await);
+}
+
+#[tokio::test]
+async fn shutdown_posts_a_final_batch_and_restarts_from_its_snapshot() {
+    let da = Arc::new(MockDa::new());
+    let snapshot_path = std::env::temp_dir().join(format!(
+        "grugchat-shutdown-test-{}.snapshot",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&snapshot_path);
+    let config = NodeConfig {
+        snapshot_path: Some(snapshot_path.clone()),
+        ..NodeConfig::default()
+    };
+    let node = start_node_with(&da, config.clone());
+    let alice = signing_key(1);
+    let bob = signing_key(2);
+
+    let height = post_in_one_block(&da, vec![(&node, vec![register(&alice, "alice")])]).await;
+    wait_for_height(&node, height).await;
+
+    node.clone()
+        .queue_transaction(register(&bob, "bob"))
+        .await
+        .unwrap();
+    node.shutdown();
+    assert!(node
+        .clone()
+        .queue_transaction(send(&alice, "general", "too late"))
+        .await
+        .is_err());
+    node.clone().finish().await.unwrap();
+    let height = da.produce_block().await;
+
+    let da_layer: Arc<dyn DataAvailabilityLayer> = da.clone();
+    let resumed = Arc::new(FullNode::resume(da_layer, namespace(), 1, config).unwrap());
+    std::fs::remove_file(&snapshot_path).unwrap();
+    assert_eq!(resumed.status().await.start_height, height);
+    assert!(resumed.is_registered(&alice.verifying_key().into()).await);
+
+    tokio::spawn(resumed.clone().start_sync());
+    assert_converged(&[resumed.clone(), start_node(&da)], height).await;
+    assert!(resumed.is_registered(&bob.verifying_key().into()).await);
+}
+
+#[tokio::test]
+async fn nodes_resubscribe_after_the_subscription_closes() {
+    let da = Arc::new(MockDa::new());
+    let node = start_node(&da);
+    let alice = signing_key(1);
+
+    let height = post_in_one_block(&da, vec![(&node, vec![register(&alice, "alice")])]).await;
+    wait_for_height(&node, height).await;
+
+    da.close_subscriptions().await;
+    timeout(Duration::from_secs(10), async {
+        while node.sync_error().is_none() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("node did not notice the closed subscription");
+    assert_eq!(
+        node.sync_error().as_deref(),
+        Some("blob subscription closed")
+    );
+
+    // Heights produced while it was unsubscribed are caught up on.
+    let height = post_in_one_block(
+        &da,
+        vec![(&node, vec![send(&alice, "general", "still here")])],
+    )
+    .await;
+    assert_converged(&[node.clone(), start_node(&da)], height).await;
+    assert_eq!(node.sync_error(), None);
+
+    let height =
+        post_in_one_block(&da, vec![(&node, vec![send(&alice, "general", "again")])]).await;
+    wait_for_height(&node, height).await;
+}